@@ -0,0 +1,101 @@
+import statistics
+import sys
+import time
+
+from cffi import FFI
+
+
+# Compares image upload strategies on the first connected device:
+#   sync    - SetImage, waiting for every frame
+#   chunked - BeginFrame, SetImageRegion per strip, CommitFrame
+# Usage: bench-upload.py [path to liblibfip.so] [frame count]
+#
+# There is no async upload to compare: every export sends its requests on the calling
+# thread, so SetImage from another thread is the same upload. Neither is there a mock
+# device to simulate USB latencies with, so this needs a real device.
+
+
+f = FFI()
+f.cdef(
+'''
+typedef void (__stdcall *Pfn_DirectOutput_EnumerateCallback)(void* hDevice, void* pCtxt);
+
+HRESULT __stdcall DirectOutput_Initialize(const wchar_t* wszPluginName);
+HRESULT __stdcall DirectOutput_Deinitialize();
+HRESULT __stdcall DirectOutput_Enumerate(Pfn_DirectOutput_EnumerateCallback pfnCb, void* pCtxt);
+HRESULT __stdcall DirectOutput_SetImage(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD cbValue, const void* pvValue);
+HRESULT __stdcall DirectOutput_SetImageRegion(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD x, DWORD y, DWORD width, DWORD height, DWORD cbValue, const void* pvValue);
+HRESULT __stdcall DirectOutput_BeginFrame(void* hDevice, DWORD dwPage);
+HRESULT __stdcall DirectOutput_CommitFrame(void* hDevice, DWORD dwPage);
+HRESULT __stdcall DirectOutput_Flush();
+'''.replace('HRESULT', 'uint64_t').replace('DWORD', 'int32_t')
+)
+m = f.dlopen(sys.argv[1] if len(sys.argv) > 1 else './target/release/liblibfip.so')
+frame_count = int(sys.argv[2]) if len(sys.argv) > 2 else 100
+
+WIDTH, HEIGHT = 320, 240
+STRIP_HEIGHT = 40
+
+
+device_ptrs = []
+
+
+@f.callback("void(void*, void *)")
+def enumerate_callback(device_ptr, ctx):
+    device_ptrs.append(device_ptr)
+
+
+def frames():
+    # every frame differs, so none of them can be skipped as a duplicate
+    for i in range(frame_count):
+        yield bytes([i % 256]) * (WIDTH * HEIGHT * 3)
+
+
+def upload_sync(device_ptr, image):
+    m.DirectOutput_SetImage(device_ptr, 0, 0, len(image), image)
+
+
+def upload_chunked(device_ptr, image):
+    m.DirectOutput_BeginFrame(device_ptr, 0)
+    strip_size = WIDTH * STRIP_HEIGHT * 3
+    for y in range(0, HEIGHT, STRIP_HEIGHT):
+        # the image rows are bottom-up, as are the region ones
+        start = (HEIGHT - y - STRIP_HEIGHT) * WIDTH * 3
+        strip = image[start:start + strip_size]
+        m.DirectOutput_SetImageRegion(device_ptr, 0, 0, 0, y, WIDTH, STRIP_HEIGHT, len(strip), strip)
+    m.DirectOutput_CommitFrame(device_ptr, 0)
+
+
+def bench(name, upload):
+    latencies = []
+    started = time.perf_counter()
+    for image in frames():
+        frame_started = time.perf_counter()
+        upload(image)
+        latencies.append(time.perf_counter() - frame_started)
+    m.DirectOutput_Flush()
+    report(name, time.perf_counter() - started, latencies)
+
+
+def report(name, elapsed, latencies):
+    latencies_ms = sorted(latency * 1000 for latency in latencies)
+    p95 = latencies_ms[int(len(latencies_ms) * 0.95) - 1]
+    print(
+        f'{name:8} {len(latencies) / elapsed:6.1f} fps, '
+        f'latency median {statistics.median(latencies_ms):6.1f} ms, p95 {p95:6.1f} ms'
+    )
+
+
+m.DirectOutput_Initialize('bench-upload')
+try:
+    time.sleep(0.5)
+    m.DirectOutput_Enumerate(enumerate_callback, f.NULL)
+    if not device_ptrs:
+        print('No devices found!')
+        exit(1)
+    device_ptr = device_ptrs[0]
+
+    bench('sync', lambda image: upload_sync(device_ptr, image))
+    bench('chunked', lambda image: upload_chunked(device_ptr, image))
+finally:
+    m.DirectOutput_Deinitialize()
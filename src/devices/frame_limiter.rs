@@ -0,0 +1,130 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Condvar, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use super::{DroppedFrames, FrameOverflowPolicy};
+
+/// Enforces a minimum interval between image frames sent to a device.
+///
+/// A frame that comes too early is queued until the interval elapses.
+/// Every image slot has its own bounded queue; what happens to a frame
+/// that comes when the queue is full is decided by the overflow policy.
+pub struct FrameLimiter {
+    min_interval: Option<Duration>,
+    queue_depth: usize,
+    overflow_policy: FrameOverflowPolicy,
+    state: Mutex<FrameLimiterState>,
+    // notified when a frame leaves a queue
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct FrameLimiterState {
+    last_frame_started: Option<Instant>,
+    // keyed by page and image index
+    pending: BTreeMap<(u8, u8), VecDeque<Arc<[u8]>>>,
+    dropped: DroppedFrames,
+}
+
+impl FrameLimiter {
+    pub fn new(
+        min_interval: Option<Duration>,
+        queue_depth: usize,
+        overflow_policy: FrameOverflowPolicy,
+    ) -> FrameLimiter {
+        FrameLimiter {
+            min_interval,
+            queue_depth: queue_depth.max(1),
+            overflow_policy,
+            state: Mutex::default(),
+            condvar: Condvar::default(),
+        }
+    }
+
+    /// Blocks until a frame of the image slot may be sent and returns the oldest queued one,
+    /// or returns `None` if the frame has been dropped or will be sent by another caller.
+    pub fn throttle(&self, page: u8, index: u8, data: Arc<[u8]>) -> Option<Arc<[u8]>> {
+        let Some(min_interval) = self.min_interval else {
+            return Some(data);
+        };
+
+        {
+            let mut state = self.state.lock().expect("Frame limiter is poisoned");
+            let queue_len = |state: &FrameLimiterState| {
+                state.pending.get(&(page, index)).map_or(0, VecDeque::len)
+            };
+            if queue_len(&state) >= self.queue_depth {
+                match self.overflow_policy {
+                    FrameOverflowPolicy::DropOldest => {
+                        state.dropped.oldest += 1;
+                        if let Some(queue) = state.pending.get_mut(&(page, index)) {
+                            queue.pop_front();
+                        }
+                    }
+                    FrameOverflowPolicy::DropNewest => {
+                        state.dropped.newest += 1;
+                        return None;
+                    }
+                    FrameOverflowPolicy::Block => {
+                        state = self
+                            .condvar
+                            .wait_while(state, |state| queue_len(state) >= self.queue_depth)
+                            .expect("Frame limiter is poisoned");
+                    }
+                }
+            }
+            state
+                .pending
+                .entry((page, index))
+                .or_default()
+                .push_back(data);
+        }
+
+        loop {
+            let wait = {
+                let mut state = self.state.lock().expect("Frame limiter is poisoned");
+                let elapsed = state.last_frame_started.map(|instant| instant.elapsed());
+                match elapsed {
+                    Some(elapsed) if elapsed < min_interval => min_interval - elapsed,
+                    _ => {
+                        state.last_frame_started = Some(Instant::now());
+                        let data = state
+                            .pending
+                            .get_mut(&(page, index))
+                            .and_then(VecDeque::pop_front);
+                        self.condvar.notify_all();
+                        return data;
+                    }
+                }
+            };
+            sleep(wait);
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        let state = self.state.lock().expect("Frame limiter is poisoned");
+        state.pending.values().any(|queue| !queue.is_empty())
+    }
+
+    pub fn dropped_frames(&self) -> DroppedFrames {
+        self.state
+            .lock()
+            .expect("Frame limiter is poisoned")
+            .dropped
+    }
+
+    /// Drops the frames queued for the page, returns whether there were any
+    pub fn cancel(&self, page: u8) -> bool {
+        let mut state = self.state.lock().expect("Frame limiter is poisoned");
+        let pending_count: usize = state.pending.values().map(VecDeque::len).sum();
+        state
+            .pending
+            .retain(|(pending_page, _), _| *pending_page != page);
+        let cancelled = state.pending.values().map(VecDeque::len).sum::<usize>() != pending_count;
+        self.condvar.notify_all();
+        cancelled
+    }
+}
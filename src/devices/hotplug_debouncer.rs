@@ -0,0 +1,326 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{UsbDeviceAddress, UsbPortPath};
+
+/// Change of the displays, as told to the hotplug handlers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DebouncedChange {
+    Arrived {
+        addr: UsbDeviceAddress,
+        reconnected: bool,
+    },
+    Left(UsbDeviceAddress),
+    Count(usize),
+}
+
+/// Smooths out bursts of hotplug events, e.g. a powered hub resetting, with all of its
+/// devices disconnecting and reconnecting at once:
+///
+/// - a display that has left its port is told about right before another one arrives
+///   at the same port, or once `delay` passes without one, so a reconnected display
+///   is told about as a pair of left and arrived changes;
+/// - the count is told once it has stopped changing for `delay`, and only if it differs
+///   from the last one told, so a burst ends in a single notification at most.
+///
+/// The changes are told in order, from a thread of its own.
+pub struct HotplugDebouncer {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<HotplugDebouncerState>,
+    // notified when there is a change or the debouncer is dropped
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct HotplugDebouncerState {
+    // changes to tell right away
+    ready: VecDeque<DebouncedChange>,
+    // displays that have left and when, by their port, until they are told about
+    left: BTreeMap<UsbPortPath, (UsbDeviceAddress, Instant)>,
+    // last count and when it has changed, until it is told
+    pending_count: Option<(usize, Instant)>,
+    told_count: Option<usize>,
+    stopped: bool,
+}
+
+impl HotplugDebouncer {
+    /// Starts the thread calling `notify` with the changes
+    pub fn new(
+        delay: Duration,
+        notify: impl Fn(DebouncedChange) + Send + 'static,
+    ) -> HotplugDebouncer {
+        let shared = Arc::new(Shared {
+            state: Mutex::default(),
+            condvar: Condvar::default(),
+        });
+        let thread_shared = shared.clone();
+        std::thread::Builder::new()
+            .name("Hotplug debouncing".to_owned())
+            .spawn(move || thread_shared.run(delay, notify))
+            .expect("Could not start hotplug debouncing thread");
+        HotplugDebouncer { shared }
+    }
+
+    /// `count` is the number of displays left. A display with an unknown port
+    /// is told about right away.
+    pub fn display_left(&self, addr: UsbDeviceAddress, port: Option<UsbPortPath>, count: usize) {
+        let mut state = self.shared.state.lock().expect("Debouncer is poisoned");
+        match port {
+            Some(port) => {
+                // the port may have been left before with no display arriving meanwhile
+                if let Some((earlier, _)) = state.left.insert(port, (addr, Instant::now())) {
+                    state.ready.push_back(DebouncedChange::Left(earlier));
+                }
+            }
+            None => state.ready.push_back(DebouncedChange::Left(addr)),
+        }
+        state.pending_count = Some((count, Instant::now()));
+        self.shared.condvar.notify_all();
+    }
+
+    /// `count` is the number of displays with the one arrived
+    pub fn display_arrived(
+        &self,
+        addr: UsbDeviceAddress,
+        port: Option<&UsbPortPath>,
+        reconnected: bool,
+        count: usize,
+    ) {
+        let mut state = self.shared.state.lock().expect("Debouncer is poisoned");
+        if let Some((left, _)) = port.and_then(|port| state.left.remove(port)) {
+            state.ready.push_back(DebouncedChange::Left(left));
+        }
+        state
+            .ready
+            .push_back(DebouncedChange::Arrived { addr, reconnected });
+        state.pending_count = Some((count, Instant::now()));
+        self.shared.condvar.notify_all();
+    }
+}
+
+impl HotplugDebouncerState {
+    /// Returns the next change to tell, or how long to wait for one
+    /// (`None` for until there is a new change)
+    fn next_change(&mut self, delay: Duration) -> Result<DebouncedChange, Option<Duration>> {
+        if let Some(change) = self.ready.pop_front() {
+            return Ok(change);
+        }
+        let now = Instant::now();
+        let due_in = |since: Instant| delay.saturating_sub(now.duration_since(since));
+
+        // no display has arrived at the port in time
+        let expired_port = self
+            .left
+            .iter()
+            .filter(|(_, (_, left_at))| due_in(*left_at).is_zero())
+            .min_by_key(|(_, (_, left_at))| *left_at)
+            .map(|(port, _)| port.clone());
+        if let Some((addr, _)) = expired_port.and_then(|port| self.left.remove(&port)) {
+            return Ok(DebouncedChange::Left(addr));
+        }
+
+        if let Some((count, changed_at)) = self.pending_count {
+            if due_in(changed_at).is_zero() {
+                self.pending_count = None;
+                if self.told_count.replace(count) != Some(count) {
+                    return Ok(DebouncedChange::Count(count));
+                }
+                log::debug!(
+                    "Display count is back to {} after a burst, not telling",
+                    count
+                );
+            }
+        }
+
+        Err(self
+            .left
+            .values()
+            .map(|(_, left_at)| due_in(*left_at))
+            .chain(self.pending_count.map(|(_, changed_at)| due_in(changed_at)))
+            .min())
+    }
+}
+
+impl Shared {
+    fn run(&self, delay: Duration, notify: impl Fn(DebouncedChange)) {
+        let mut state = self.state.lock().expect("Debouncer is poisoned");
+        loop {
+            if state.stopped {
+                return;
+            }
+            state = match state.next_change(delay) {
+                Ok(change) => {
+                    // the handlers may cause other changes, so they are called unlocked
+                    drop(state);
+                    notify(change);
+                    self.state.lock().expect("Debouncer is poisoned")
+                }
+                Err(Some(wait)) => {
+                    self.condvar
+                        .wait_timeout(state, wait)
+                        .expect("Debouncer is poisoned")
+                        .0
+                }
+                Err(None) => self.condvar.wait(state).expect("Debouncer is poisoned"),
+            };
+        }
+    }
+}
+
+impl Drop for HotplugDebouncer {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.stopped = true;
+        }
+        self.shared.condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    const DELAY: Duration = Duration::from_millis(50);
+
+    fn debouncer() -> (HotplugDebouncer, mpsc::Receiver<DebouncedChange>) {
+        let (sender, receiver) = mpsc::channel();
+        let debouncer = HotplugDebouncer::new(DELAY, move |change| {
+            _ = sender.send(change);
+        });
+        (debouncer, receiver)
+    }
+
+    /// Returns the changes told until none has come for a while
+    fn told(receiver: &mpsc::Receiver<DebouncedChange>) -> Vec<DebouncedChange> {
+        std::iter::from_fn(|| receiver.recv_timeout(DELAY * 4).ok()).collect()
+    }
+
+    fn port(number: u8) -> UsbPortPath {
+        (1, vec![2, number])
+    }
+
+    fn arrived(addr: UsbDeviceAddress, reconnected: bool) -> DebouncedChange {
+        DebouncedChange::Arrived { addr, reconnected }
+    }
+
+    #[test]
+    fn pairs_reconnections_of_a_burst() {
+        let (debouncer, receiver) = debouncer();
+        for number in 1..=3 {
+            debouncer.display_arrived((1, number), Some(&port(number)), false, number.into());
+        }
+        assert_eq!(
+            told(&receiver),
+            [
+                arrived((1, 1), false),
+                arrived((1, 2), false),
+                arrived((1, 3), false),
+                DebouncedChange::Count(3),
+            ]
+        );
+
+        // a hub reset: every display leaves, then comes back with a new address
+        for number in 1..=3 {
+            debouncer.display_left((1, number), Some(port(number)), usize::from(3 - number));
+        }
+        for number in 1..=3 {
+            debouncer.display_arrived((1, number + 10), Some(&port(number)), true, number.into());
+        }
+        // the count is the same as before the burst, so it is not told
+        assert_eq!(
+            told(&receiver),
+            [
+                DebouncedChange::Left((1, 1)),
+                arrived((1, 11), true),
+                DebouncedChange::Left((1, 2)),
+                arrived((1, 12), true),
+                DebouncedChange::Left((1, 3)),
+                arrived((1, 13), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn tells_about_displays_not_coming_back() {
+        let (debouncer, receiver) = debouncer();
+        debouncer.display_arrived((1, 1), Some(&port(1)), false, 1);
+        debouncer.display_arrived((1, 2), Some(&port(2)), false, 2);
+        debouncer.display_left((1, 1), Some(port(1)), 1);
+        assert_eq!(
+            told(&receiver),
+            [
+                arrived((1, 1), false),
+                arrived((1, 2), false),
+                DebouncedChange::Left((1, 1)),
+                DebouncedChange::Count(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn tells_about_displays_with_unknown_ports_right_away() {
+        let (debouncer, receiver) = debouncer();
+        debouncer.display_arrived((1, 1), None, false, 1);
+        debouncer.display_left((1, 1), None, 0);
+        debouncer.display_arrived((1, 2), None, false, 1);
+        assert_eq!(
+            receiver.recv_timeout(DELAY / 2).ok(),
+            Some(arrived((1, 1), false))
+        );
+        assert_eq!(
+            receiver.recv_timeout(DELAY / 2).ok(),
+            Some(DebouncedChange::Left((1, 1)))
+        );
+        assert_eq!(
+            told(&receiver),
+            [arrived((1, 2), false), DebouncedChange::Count(1)]
+        );
+    }
+
+    #[test]
+    fn converges_after_churn() {
+        let (debouncer, receiver) = debouncer();
+        let addr = |number: u8, round: u8| (1, round * 10 + number);
+        for number in 1..=4 {
+            debouncer.display_arrived(addr(number, 0), Some(&port(number)), false, number.into());
+        }
+        // every display reconnects a few times in a row, then the last one leaves for good
+        for round in 1..=5 {
+            for number in 1..=4 {
+                debouncer.display_left(addr(number, round - 1), Some(port(number)), 3);
+                debouncer.display_arrived(addr(number, round), Some(&port(number)), true, 4);
+            }
+        }
+        debouncer.display_left(addr(4, 5), Some(port(4)), 3);
+
+        let mut displays = std::collections::BTreeSet::new();
+        let mut counts = Vec::new();
+        for change in told(&receiver) {
+            match change {
+                DebouncedChange::Arrived { addr, .. } => {
+                    assert!(displays.insert(addr), "{:?} arrived twice", addr)
+                }
+                DebouncedChange::Left(addr) => {
+                    assert!(displays.remove(&addr), "{:?} left unknown", addr)
+                }
+                DebouncedChange::Count(count) => counts.push(count),
+            }
+        }
+        assert_eq!(displays, [addr(1, 5), addr(2, 5), addr(3, 5)].into());
+        assert_eq!(counts.last(), Some(&3));
+
+        // the thread stops with the debouncer, dropping the notifying closure
+        drop(debouncer);
+        assert_eq!(
+            receiver.recv_timeout(DELAY * 4),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+    }
+}
@@ -1,63 +1,792 @@
+mod frame_limiter;
+mod hotplug_debouncer;
+mod pages;
+mod pending_frames;
 mod saitek_fip_lcd;
+mod snapshots;
+mod thread_tuning;
+mod uinput;
+mod upload_limiter;
 mod usb_ids;
 
+use bitmask_enum::bitmask;
 use rusb::UsbContext;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     io::Read,
-    sync::{Arc, RwLock, Weak},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
+use crate::images::{self, ImageAdjustment};
+use hotplug_debouncer::{DebouncedChange, HotplugDebouncer};
+pub use pages::PageFlags;
+pub use usb_ids::{parse_device_ids, DeviceIds, DeviceType, DEVICE_TYPE_SAITEK_FIP};
+
+#[bitmask(u32)]
+pub enum Capabilities {
+    IMAGE = 0b00001,
+    TEXT = 0b00010,
+    LED = 0b00100,
+    BUTTONS = 0b01000,
+    FILES = 0b10000,
+}
+
+/// Soft buttons state, as it is reported to the host (`SoftButton_*` constants)
+#[bitmask(u32)]
+pub enum SoftButtons {
+    SELECT = 0x00000001,
+    UP = 0x00000002,
+    DOWN = 0x00000004,
+    LEFT = 0x00000008,
+    RIGHT = 0x00000010,
+    B1 = 0x00000020,
+    B2 = 0x00000040,
+    B3 = 0x00000080,
+    B4 = 0x00000100,
+    B5 = 0x00000200,
+    B6 = 0x00000400,
+}
+
+/// Number of requests waiting for the device, by their priority
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueDepth {
+    pub control: usize,
+    pub bulk: usize,
+}
+
+/// What to do with a new frame when the frame queue of its image slot is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameOverflowPolicy {
+    /// Drops the oldest queued frame, for the lowest latency of live content
+    #[default]
+    DropOldest,
+    /// Drops the new frame
+    DropNewest,
+    /// Blocks the caller until there is room in the queue, so no frame is lost
+    Block,
+}
+
+/// Number of frames dropped from the frame queues, by the overflow policy that dropped them
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DroppedFrames {
+    pub oldest: u64,
+    pub newest: u64,
+}
+
+/// Statistics of the transfers to and from a device
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransferStats {
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    pub transfers: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+    pub average_latency: Duration,
+    /// Sequence number of the last image sent, it is sent along with the image
+    /// to tell frames dropped by the host and by the device apart
+    /// (0 unless `Options::image_sequence_numbers` is set)
+    pub last_image_sequence: u32,
+    /// Number of image uploads being sent or waiting to be sent, see `Options::max_image_uploads`
+    pub image_uploads_in_flight: usize,
+    /// Number of HID reports the buttons could not be read from, which are skipped
+    pub malformed_hid_reports: usize,
+}
+
+/// Status of a request as reported by the device (`SRequestStatus`)
+///
+/// Only the error fields tell whether the request has failed. The info fields
+/// may be set on success too; no meaning of their values is known yet,
+/// so they are passed to the host as they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestStatus {
+    pub header_error: u32,
+    pub header_info: u32,
+    pub request_error: u32,
+    pub request_info: u32,
+}
+
+impl RequestStatus {
+    pub fn is_error(&self) -> bool {
+        self.header_error > 0 || self.request_error > 0
+    }
+
+    pub fn has_info(&self) -> bool {
+        self.header_info > 0 || self.request_info > 0
+    }
+}
+
+/// Why a request has failed, for the requests telling timeouts apart
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    /// The transfers have not been done in time
+    Timeout,
+    /// The transfers have failed otherwise, or the device has reported an error
+    Failed,
+}
+
+pub trait SoftButtonsHandler: Send + Sync {
+    fn soft_buttons_changed(&mut self, buttons: SoftButtons);
+}
+
+impl<F: FnMut(SoftButtons) + Send + Sync> SoftButtonsHandler for F {
+    fn soft_buttons_changed(&mut self, buttons: SoftButtons) {
+        self(buttons)
+    }
+}
+
+/// Called once when a device stops being usable (e.g. it has been disconnected),
+/// from a thread of its own
+pub type InvalidationHandler = Box<dyn FnOnce() + Send>;
+
+pub trait PageChangeHandler: Send + Sync {
+    fn page_changed(&mut self, page: u8, is_active: bool);
+}
+
 pub trait ManagedDisplay: Send + Sync {
     fn ready(&self) -> bool;
+    /// Tells whether the device is still being initialized, so it may become ready yet
+    fn initializing(&self) -> bool;
+    /// Blocks until the device is initialized (or has failed to), or the timeout elapses,
+    /// returning `false` in the latter case
+    fn wait_for_init(&self, timeout: Duration) -> bool;
+    /// Tells whether the device has not become ready because another application
+    /// (e.g. the official driver) holds it
+    fn in_use_elsewhere(&self) -> bool;
+    /// Tells whether the device has not become ready because it is in "factory mode".
+    ///
+    /// A device is taken to be in it when it accepts a request the regular firmware
+    /// rejects. What the mode is meant for and how to leave it is not known;
+    /// presumably it is used for testing or flashing the devices.
+    fn is_factory_mode(&self) -> bool;
+    /// Tells whether the device has not become ready because its initialization
+    /// has not finished in time (see `Options::init_timeout`); it is not retried
+    fn init_timed_out(&self) -> bool;
+    fn capabilities(&self) -> Capabilities;
+    fn queue_depth(&self) -> QueueDepth;
+    fn transfer_stats(&self) -> TransferStats;
+    /// Returns how many uploads have been dropped because their page was removed
+    fn cancelled_transfers(&self) -> usize;
+    fn dropped_frames(&self) -> DroppedFrames;
+    /// Blocks until all requests to the device (including frames held back
+    /// by the frame limiter) are done, or fails if the timeout elapses first.
+    fn flush(&self, timeout: Duration) -> Result<(), ()>;
+    /// Returns the status of the last response received from the device
+    fn last_request_status(&self) -> Option<RequestStatus>;
     fn serial_number(&self) -> String;
+    /// Returns the manufacturer string of the device, if it has one
+    fn manufacturer(&self) -> Option<String>;
+    /// Returns the product string of the device, if it has one
+    fn product(&self) -> Option<String>;
     fn device_type_uuid(&self) -> Uuid;
-    fn set_image_data(&self, page: u8, data: &[u8; 0x38400]) -> Result<(), ()>;
-    fn set_led(&self, page: u8, index: u8, value: bool) -> Result<(), ()>;
+    /// Sets the handler of soft buttons changes, replacing the previous one.
+    ///
+    /// The handler is called once right away with the last known buttons
+    /// state, unless the device hasn't reported any state yet. A closure may be
+    /// given as the handler; it's dropped along with the device or the next handler.
+    fn set_soft_buttons_handler(&self, handler: Box<dyn SoftButtonsHandler>);
+    /// Reads the buttons state from the device right away, instead of
+    /// returning the last reported one. May block for up to 5 seconds.
+    fn read_soft_buttons(&self) -> Result<SoftButtons, ()>;
+    /// Waits for the next change of the buttons state, for the polling hosts.
+    ///
+    /// Changes are queued from the first call on (the oldest ones are dropped
+    /// if they are not taken in time), the handler is still called for them.
+    fn next_button_event(&self, timeout: Duration) -> Option<SoftButtons>;
+    /// Sets the handler of active page changes, replacing the previous one
+    fn set_page_change_handler(&self, handler: Box<dyn PageChangeHandler>);
+    fn add_page(&self, page: u8, debug_name: Option<String>, flags: PageFlags) -> Result<(), ()>;
+    /// Same as `add_page`, but uploads the image of the page first,
+    /// so the page never shows up blank when it becomes active
+    fn add_page_with_image(
+        &self,
+        page: u8,
+        debug_name: Option<String>,
+        flags: PageFlags,
+        data: &[u8],
+    ) -> Result<(), ()> {
+        if self.page_numbers().contains(&page) {
+            return Err(());
+        }
+        self.set_image_data(page, 0, data)?;
+        self.add_page(page, debug_name, flags)
+    }
+    /// Removes the page.
+    ///
+    /// If the page is active, the next page becomes active and its images
+    /// are sent again, or the display is cleared if there are no pages left.
+    fn remove_page(&self, page: u8) -> Result<(), ()>;
+    fn active_page(&self) -> Option<u8>;
+    fn page_numbers(&self) -> Vec<u8>;
+    /// Returns the adjustment to apply to images converted for this device
+    fn image_adjustment(&self) -> ImageAdjustment;
+    /// Returns indices of the image slots available on the page.
+    ///
+    /// Every page has its own set of image slots, each holding a full-screen image;
+    /// slot 0 is always present. The page shows its slots composited in index order,
+    /// see `get_page_image`. Black is the transparent color of the slots, so black
+    /// set to a slot above others (e.g. by `fill_color`) shows the slots below it;
+    /// `clear_image_slot` is what removes the image of a slot.
+    fn image_indices(&self, page: u8) -> Vec<u8>;
+    /// Returns the size of a full-screen image of the device, in the device format
+    fn image_data_size(&self) -> usize;
+    /// Returns the image currently in the slot of the page
+    fn get_image_data(&self, page: u8, index: u8) -> Result<Vec<u8>, ()>;
+    /// Returns the image shown on the page: its non-empty slots drawn over each other
+    /// in index order, the black pixels of a slot letting the slots below show through
+    /// (a black image for a page without any)
+    fn get_page_image(&self, page: u8) -> Result<Vec<u8>, ()>;
+    /// Devices without the `IMAGE` capability (e.g. text-only ones) return an error
+    /// without sending anything to the device.
+    fn set_image_data(&self, page: u8, index: u8, data: &[u8]) -> Result<(), ()>;
+    fn fill_color(&self, page: u8, index: u8, r: u8, g: u8, b: u8) -> Result<(), ()> {
+        self.set_image_data(page, index, &images::solid_color(r, g, b))
+    }
+    /// Writes the region over the current image of the slot. Inside of a frame
+    /// (see `begin_frame`) nothing is sent to the device until it is committed.
+    fn set_image_region(
+        &self,
+        page: u8,
+        index: u8,
+        region: images::Region,
+        data: &[u8],
+    ) -> Result<(), ()>;
+    /// Begins a frame of the page, so image regions written to it are shown all at once
+    /// by `commit_frame`. A frame is committed automatically when its page becomes active
+    /// or if it's not committed in time.
+    fn begin_frame(&self, page: u8);
+    fn commit_frame(&self, page: u8) -> Result<(), ()>;
+    /// Begins buffering images and LED levels set to the device (of any page),
+    /// until `end_batch` sends the last image of every slot and the LEDs that
+    /// have changed. Does nothing if a batch has already begun. A batch is ended
+    /// automatically if it's not ended in time.
+    fn begin_batch(&self);
+    fn end_batch(&self) -> Result<(), ()>;
+    /// Returns indices of the text lines available on the page,
+    /// none on devices without the `TEXT` capability
+    fn string_indices(&self, _page: u8) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Returns how many characters fit in a text line
+    fn string_width(&self) -> usize {
+        0
+    }
+    /// Sets the text of the line, which fits in `string_width`; an empty text clears the line.
+    /// Devices without the `TEXT` capability return an error.
+    fn set_string(&self, _page: u8, _index: u8, _text: &str) -> Result<(), ()> {
+        Err(())
+    }
+    /// Returns indices of the LEDs available on the page
+    fn led_indices(&self, page: u8) -> Vec<u8>;
+    /// Returns the levels the LEDs have been set to, by page and LED index
+    fn led_levels(&self) -> BTreeMap<(u8, u8), u8>;
+    /// Turns off every LED that is lit, on every page. Everything is tried even if
+    /// something fails (e.g. the device is being disconnected).
+    fn all_leds_off(&self) -> Result<(), ()> {
+        // only the lit LEDs are sent
+        self.led_levels()
+            .into_iter()
+            .filter(|(_, level)| *level > 0)
+            .fold(Ok(()), |result, ((page, index), _)| {
+                result.and(self.set_led_level(page, index, 0))
+            })
+    }
+    /// Turns off the lit LEDs as the host goes away. A batch that has begun is dropped
+    /// rather than ended, and the levels are not saved, so the state file keeps them
+    /// to be restored for the next host.
+    fn release_leds(&self) -> Result<(), ()>;
+    fn set_led(&self, page: u8, index: u8, value: bool) -> Result<(), ()> {
+        self.set_led_level(page, index, value.into())
+    }
+    /// Sets the LED to the level, clamped to the range the device supports
+    fn set_led_level(&self, page: u8, index: u8, level: u8) -> Result<(), ()>;
     fn clear_image(&self, page: u8) -> Result<(), ()>;
-    fn save_file(&self, page: u8, file: u8, data: &mut dyn Read) -> Result<(), ()>;
+    /// Clears a single image slot, leaving the other slots of the page as they are
+    fn clear_image_slot(&self, page: u8, index: u8) -> Result<(), ()>;
+    /// Brings the device back to the last state set through this library.
+    ///
+    /// There is no known protocol request to reset a FIP, so this clears
+    /// every image and LED that has been set and sends them again.
+    fn reset(&self) -> Result<(), ()>;
+    fn save_file(&self, page: u8, file: u8, data: &mut dyn Read) -> Result<(), ()> {
+        let mut buffer = Vec::new();
+        if let Err(err) = data.read_to_end(&mut buffer) {
+            log::error!("Cannot read data: {:?}", err);
+            return Err(());
+        }
+        let mut status = RequestStatus::default();
+        self.save_file_bytes(page, file, &buffer, &mut status)
+            .map_err(drop)
+    }
+    /// `status` is set to the status the device has responded with, if it has
+    fn save_file_bytes(
+        &self,
+        page: u8,
+        file: u8,
+        data: &[u8],
+        status: &mut RequestStatus,
+    ) -> Result<(), RequestError>;
+    /// Same as `save_file_bytes`, but with the timeout of the transfers instead of the default one
+    fn save_file_bytes_with_timeout(
+        &self,
+        page: u8,
+        file: u8,
+        data: &[u8],
+        timeout: Duration,
+        status: &mut RequestStatus,
+    ) -> Result<(), RequestError>;
     fn display_file(&self, page: u8, index: u8, file: u8) -> Result<(), ()>;
     fn delete_file(&self, page: u8, file: u8) -> Result<(), ()>;
+    /// Uploads and starts a server application, returning its id.
+    ///
+    /// `status` is filled from the device response, if there was one.
+    fn start_server(&self, data: &mut dyn Read, status: &mut RequestStatus) -> Result<u32, ()>;
 }
 
 pub type UsbDeviceAddress = (u8, u8);
 
+const DEFAULT_FRAME_QUEUE_DEPTH: usize = 2;
+const DEFAULT_IMAGE_BUFFER_POOL_SIZE: usize = 2;
+const DEFAULT_MAX_IMAGE_UPLOADS: usize = 2;
+const DEFAULT_INIT_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_HOTPLUG_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// How long after a display is invalidated its device is probed again the first time,
+/// doubled for every next time, see `reprobe`
+const REPROBE_DELAY: Duration = Duration::from_secs(1);
+/// How many times the device at a port is probed again before it is given up on
+/// until it is reconnected
+const MAX_REPROBES: u32 = 5;
+
+/// Library-wide settings applied to every device
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    min_frame_interval: Option<Duration>,
+    frame_queue_depth: Option<usize>,
+    frame_overflow_policy: FrameOverflowPolicy,
+    image_buffer_pool_size: Option<usize>,
+    max_image_uploads: Option<usize>,
+    wait_for_ready: Option<Duration>,
+    init_timeout: Option<Duration>,
+    hotplug_debounce: Option<Duration>,
+    image_adjustment: ImageAdjustment,
+    image_dither_bits: Option<u8>,
+    device_ids: DeviceIds,
+    app_name: Option<String>,
+    reset_on_init: bool,
+    keep_hid_driver: bool,
+    skip_factory_mode_check: bool,
+    image_sequence_numbers: bool,
+    file_transfer_endpoint: bool,
+    state_file: Option<PathBuf>,
+    uinput_serial_numbers: Option<Vec<String>>,
+    device_thread_tuning: thread_tuning::ThreadTuning,
+}
+
+impl Options {
+    /// Limits how often images are sent to a device (unlimited by default)
+    pub fn min_frame_interval(mut self, interval: Duration) -> Options {
+        self.min_frame_interval = Some(interval);
+        self
+    }
+
+    /// Sets how many frames of an image slot may wait for the frame rate limit,
+    /// and what happens to frames coming when that many are already waiting
+    /// (2 frames, dropping the oldest one by default)
+    pub fn frame_queue(mut self, depth: usize, overflow_policy: FrameOverflowPolicy) -> Options {
+        self.frame_queue_depth = Some(depth.max(1));
+        self.frame_overflow_policy = overflow_policy;
+        self
+    }
+
+    fn frame_queue_depth(&self) -> usize {
+        self.frame_queue_depth.unwrap_or(DEFAULT_FRAME_QUEUE_DEPTH)
+    }
+
+    /// Sets how many image buffers every device keeps for composing regions and fills,
+    /// instead of allocating them for every frame (2 by default, 0 disables reusing them)
+    pub fn image_buffer_pool(mut self, size: usize) -> Options {
+        self.image_buffer_pool_size = Some(size);
+        self
+    }
+
+    fn image_buffer_pool_size(&self) -> usize {
+        self.image_buffer_pool_size
+            .unwrap_or(DEFAULT_IMAGE_BUFFER_POOL_SIZE)
+    }
+
+    /// Caps the number of image uploads of a device in flight at once, so images set faster
+    /// than they are sent don't pile up in memory (2 by default). Images coming when the cap
+    /// is reached are handled by the frame overflow policy: only the latest one of each image
+    /// slot is sent once an upload finishes with `DropOldest`, they are dropped with
+    /// `DropNewest`, and wait with `Block`.
+    pub fn max_image_uploads(mut self, max: usize) -> Options {
+        self.max_image_uploads = Some(max.max(1));
+        self
+    }
+
+    fn image_upload_cap(&self) -> usize {
+        self.max_image_uploads.unwrap_or(DEFAULT_MAX_IMAGE_UPLOADS)
+    }
+
+    /// Sets the adjustment applied to images loaded from files (identity by default).
+    /// Files taken as raw image data are not adjusted.
+    pub fn image_adjustment(mut self, image_adjustment: ImageAdjustment) -> Options {
+        self.image_adjustment = image_adjustment;
+        self
+    }
+
+    /// Dithers images loaded from files to `bits` per color channel, after the adjustment
+    /// (not dithered by default, as it costs a pass over every image)
+    pub fn image_dithering(mut self, bits: u8) -> Options {
+        self.image_dither_bits = Some(bits);
+        self
+    }
+
+    /// Adds USB IDs to handle as the device types, on top of the built-in ones
+    /// (which they take precedence over)
+    pub fn device_ids(mut self, device_ids: DeviceIds) -> Options {
+        self.device_ids.extend(device_ids);
+        self
+    }
+
+    fn device_type(&self, vendor_id: u16, product_id: u16) -> Option<DeviceType> {
+        self.device_ids
+            .iter()
+            .find(|(ids, _)| *ids == (vendor_id, product_id))
+            .map(|(_, device_type)| *device_type)
+            .or_else(|| usb_ids::builtin_device_type(vendor_id, product_id))
+    }
+
+    /// Sets the name of the app using the library, which is included in device log messages
+    pub fn app_name(mut self, app_name: String) -> Options {
+        self.app_name = Some(app_name);
+        self
+    }
+
+    /// Makes devices blank the image and turn off the LEDs once initialized,
+    /// instead of keeping what a previous process has left on them (kept by default)
+    pub fn reset_on_init(mut self) -> Options {
+        self.reset_on_init = true;
+        self
+    }
+
+    /// Leaves the HID interface of devices to the kernel driver (e.g. for joystick
+    /// mapping software), so buttons are not available through this library
+    pub fn keep_hid_driver(mut self) -> Options {
+        self.keep_hid_driver = true;
+        self
+    }
+
+    /// Treats every device as usable, without probing whether it is in factory mode
+    /// (see `ManagedDisplay::is_factory_mode`), for testing
+    pub fn skip_factory_mode_check(mut self) -> Options {
+        self.skip_factory_mode_check = true;
+        self
+    }
+
+    /// Sends a sequence number in the parameter of every image SetImage doesn't use,
+    /// see `TransferStats::last_image_sequence`. Not sent by default, as it is not known
+    /// whether every firmware ignores the parameter.
+    pub fn image_sequence_numbers(mut self) -> Options {
+        self.image_sequence_numbers = true;
+        self
+    }
+
+    /// Sends the data of file transfers to the second OUT endpoint of the vendor interface,
+    /// on firmwares that have one. Not used by default, as what the endpoint is for
+    /// is only guessed.
+    pub fn file_transfer_endpoint(mut self) -> Options {
+        self.file_transfer_endpoint = true;
+        self
+    }
+
+    /// Saves the pages and LED levels of every device to the file shortly after they change,
+    /// and restores them when the device is connected (or the library is initialized) again.
+    ///
+    /// A restored page is taken over by the host when it adds the page again.
+    pub fn state_file(mut self, path: PathBuf) -> Options {
+        self.state_file = Some(path);
+        self
+    }
+
+    /// Mirrors the soft buttons of the devices with the serial numbers (or of every device
+    /// if there are none) to virtual joysticks, for sims that only read joysticks.
+    ///
+    /// Only works on Linux, with the `uinput` feature, and needs write access
+    /// to `/dev/uinput` (see the `uinput` module). Devices left to the HID driver
+    /// (see `keep_hid_driver`) are not mirrored.
+    pub fn uinput(mut self, serial_numbers: Vec<String>) -> Options {
+        self.uinput_serial_numbers = Some(serial_numbers);
+        self
+    }
+
+    /// Sets the nice value of the device threads (which initialize the devices and read
+    /// their buttons), lower for a higher priority. Only applied on Linux, where going
+    /// below 0 needs the `CAP_SYS_NICE` capability; not applied (with a warning) otherwise.
+    pub fn device_thread_priority(mut self, nice: i32) -> Options {
+        self.device_thread_tuning.nice = Some(nice);
+        self
+    }
+
+    /// Pins the device threads to the CPU. Only applied on Linux,
+    /// not applied (with a warning) otherwise.
+    pub fn device_thread_cpu(mut self, cpu: usize) -> Options {
+        self.device_thread_tuning.cpu = Some(cpu);
+        self
+    }
+
+    /// Makes the library initialization, and the calls with a device that is still
+    /// initializing, block until the device is ready to be used, for at most `timeout`
+    /// (see `State::wait_for_ready`; by default devices are initialized in the background).
+    /// Device discovery itself never blocks, as it runs on the libusb events thread.
+    pub fn wait_for_ready(mut self, timeout: Duration) -> Options {
+        self.wait_for_ready = Some(timeout);
+        self
+    }
+
+    /// Bounds the whole initialization of a device (15 seconds by default).
+    /// A device not initialized in time is given up on until it is connected again,
+    /// see `ManagedDisplay::init_timed_out`.
+    pub fn init_timeout(mut self, timeout: Duration) -> Options {
+        self.init_timeout = Some(timeout);
+        self
+    }
+
+    fn init_timeout_duration(&self) -> Duration {
+        self.init_timeout.unwrap_or(DEFAULT_INIT_TIMEOUT)
+    }
+
+    /// Sets how long a display that has left waits for another one to arrive at the same
+    /// USB port, and how long the display count has to stay the same, before the hotplug
+    /// handlers are told about them (2 seconds by default). A hub reset disconnecting and
+    /// reconnecting all of its devices so ends in pairs of `Hotplug::display_left` and
+    /// `Hotplug::display_arrived`, and a single `Hotplug::display_count_changed` at most.
+    pub fn hotplug_debounce(mut self, delay: Duration) -> Options {
+        self.hotplug_debounce = Some(delay);
+        self
+    }
+
+    fn hotplug_debounce_delay(&self) -> Duration {
+        self.hotplug_debounce.unwrap_or(DEFAULT_HOTPLUG_DEBOUNCE)
+    }
+}
+
 pub struct State {
-    #[allow(dead_code)] // prevent dropping
     libusb_context: rusb::Context,
     #[allow(dead_code)] // prevent dropping
     libusb_hotplug_reg: rusb::Registration<rusb::Context>,
+    // shared by the libusb hotplug callbacks and rescans
+    usb_hotplug_handler: Arc<Mutex<UsbHotplugHandler>>,
     displays: Arc<RwLock<BTreeMap<UsbDeviceAddress, Arc<dyn ManagedDisplay>>>>,
-    display_hotplug_handlers: Arc<RwLock<Vec<Box<dyn Hotplug>>>>,
+    display_hotplug_handlers: Arc<RwLock<HotplugHandlers>>,
+    app_name: Option<String>,
+    wait_for_ready: Option<Duration>,
 }
 
+/// Handler of display changes. Its functions are called from a thread of its own,
+/// see `Options::hotplug_debounce`.
 pub trait Hotplug: Send + Sync {
     fn display_arrived(&mut self, device_addr: UsbDeviceAddress);
     fn display_left(&mut self, device_addr: UsbDeviceAddress);
+    /// Called after `display_arrived` if a display has been connected to the same
+    /// USB port before, so the host may send the content of its pages again.
+    /// Ports stay the same when a hub resets, so its displays are told as reconnected
+    /// even though they get new addresses.
+    fn display_reconnected(&mut self, _device_addr: UsbDeviceAddress) {}
+    /// Called with the number of connected displays once it has stopped changing
+    fn display_count_changed(&mut self, _count: usize) {}
 }
 
+// every handler has its own lock, so the list is not locked while the handlers are called
+type HotplugHandlers = Vec<Arc<Mutex<Box<dyn Hotplug>>>>;
+
+/// Bus number and port numbers of a USB device, which (unlike the address)
+/// stay the same when the device is reconnected to the same port
+type UsbPortPath = (u8, Vec<u8>);
+
 struct UsbHotplugHandler {
+    options: Options,
     displays: Weak<RwLock<BTreeMap<UsbDeviceAddress, Arc<dyn ManagedDisplay>>>>,
-    display_hotplug_handlers: Weak<RwLock<Vec<Box<dyn Hotplug>>>>,
+    display_hotplug_handlers: Weak<RwLock<HotplugHandlers>>,
+    // ports displays have ever been connected to
+    display_ports: BTreeSet<UsbPortPath>,
+    // how many times the devices at the ports have been probed again since they were connected
+    reprobes: BTreeMap<UsbPortPath, u32>,
+    hotplug_debouncer: Arc<HotplugDebouncer>,
+    // the handler itself, for the invalidated displays to be probed again
+    this: Weak<Mutex<UsbHotplugHandler>>,
+}
+
+/// Change of the displays to tell the hotplug handlers about, through the hotplug debouncer.
+/// They are told once the USB hotplug handler is unlocked.
+enum DisplayChange {
+    Arrived {
+        addr: UsbDeviceAddress,
+        port: Option<UsbPortPath>,
+        reconnected: bool,
+        count: usize,
+    },
+    Left {
+        addr: UsbDeviceAddress,
+        port: Option<UsbPortPath>,
+        count: usize,
+    },
+}
+
+/// Passes display changes to the hotplug debouncer
+#[derive(Clone)]
+struct DisplayChangeNotifier {
+    hotplug_debouncer: Weak<HotplugDebouncer>,
+}
+
+impl DisplayChangeNotifier {
+    fn notify(&self, change: DisplayChange) {
+        let Some(hotplug_debouncer) = self.hotplug_debouncer.upgrade() else {
+            return;
+        };
+        match change {
+            DisplayChange::Arrived {
+                addr,
+                port,
+                reconnected,
+                count,
+            } => hotplug_debouncer.display_arrived(addr, port.as_ref(), reconnected, count),
+            DisplayChange::Left { addr, port, count } => {
+                hotplug_debouncer.display_left(addr, port, count)
+            }
+        }
+    }
+}
+
+/// Forwards the libusb hotplug callbacks to the handler shared with rescans
+struct SharedUsbHotplugHandler(Arc<Mutex<UsbHotplugHandler>>);
+
+impl rusb::Hotplug<rusb::Context> for SharedUsbHotplugHandler {
+    fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+        let (change, notifier) = {
+            let mut handler = self.0.lock().expect("State is poisoned");
+            (handler.device_arrived(device), handler.notifier())
+        };
+        if let Some(change) = change {
+            notifier.notify(change);
+        }
+    }
+
+    fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
+        let (change, notifier) = {
+            let mut handler = self.0.lock().expect("State is poisoned");
+            (handler.device_left(device), handler.notifier())
+        };
+        if let Some(change) = change {
+            notifier.notify(change);
+        }
+    }
+}
+
+/// Walks the USB device list on demand, for when hotplug events don't come
+pub struct UsbScanner {
+    libusb_context: rusb::Context,
+    usb_hotplug_handler: Arc<Mutex<UsbHotplugHandler>>,
 }
 
-pub fn init() -> Result<State, ()> {
+impl UsbScanner {
+    /// Handles the devices that have been connected as arrived, and the displays
+    /// that are not connected anymore as left, the same way as their hotplug events.
+    ///
+    /// The hotplug handlers are told about the changes from a thread of their own.
+    pub fn rescan(&self) -> Result<(), ()> {
+        let devices = self.libusb_context.devices().map_err(|err| {
+            log::error!("Cannot list USB devices: {}", err);
+        })?;
+        let (changes, notifier) = {
+            // hotplug events wait for the devices to be handled, so each one is handled once
+            let mut handler = self.usb_hotplug_handler.lock().expect("State is poisoned");
+            let connected: BTreeSet<UsbDeviceAddress> = devices
+                .iter()
+                .map(|device| (device.bus_number(), device.address()))
+                .collect();
+            let left: Vec<UsbDeviceAddress> = match handler.displays.upgrade() {
+                Some(ref rc) => rc
+                    .read()
+                    .expect("State is poisoned")
+                    .keys()
+                    .filter(|addr| !connected.contains(addr))
+                    .copied()
+                    .collect(),
+                None => return Ok(()),
+            };
+            let mut changes: Vec<DisplayChange> = left
+                .into_iter()
+                // the ports of the displays are not known anymore
+                .filter_map(|addr| remove_display(&handler.displays, addr, None))
+                .collect();
+            // already known devices are skipped by the handler
+            changes.extend(
+                devices
+                    .iter()
+                    .filter_map(|device| handler.device_arrived(device)),
+            );
+            (changes, handler.notifier())
+        };
+        changes
+            .into_iter()
+            .for_each(|change| notifier.notify(change));
+        Ok(())
+    }
+}
+
+pub fn init(options: Options) -> Result<State, ()> {
     let displays: Arc<RwLock<BTreeMap<UsbDeviceAddress, Arc<dyn ManagedDisplay>>>> =
         Arc::new(RwLock::new(BTreeMap::new()));
-    let display_hotplug_handlers: Arc<RwLock<Vec<Box<dyn Hotplug>>>> =
+    let display_hotplug_handlers: Arc<RwLock<HotplugHandlers>> =
         Arc::new(RwLock::new(Vec::with_capacity(1)));
 
+    let app_name = options.app_name.clone();
+    let wait_for_ready = options.wait_for_ready;
     let libusb_context: rusb::Context = rusb::Context::new().expect("Cannot create libusb context");
-    let libusb_hotplug_reg = rusb::HotplugBuilder::new()
-        .enumerate(true)
-        .vendor_id(usb_ids::VID_SAITEK)
+    let mut hotplug_builder = rusb::HotplugBuilder::new();
+    hotplug_builder.enumerate(true);
+    // devices of other vendors may be mapped by the user
+    if options
+        .device_ids
+        .iter()
+        .all(|((vendor_id, _), _)| *vendor_id == usb_ids::VID_SAITEK)
+    {
+        hotplug_builder.vendor_id(usb_ids::VID_SAITEK);
+    }
+    let hotplug_debouncer = {
+        let display_hotplug_handlers = Arc::downgrade(&display_hotplug_handlers);
+        HotplugDebouncer::new(options.hotplug_debounce_delay(), move |change| {
+            notify_hotplug_handlers(&display_hotplug_handlers, |handler| match change {
+                DebouncedChange::Arrived { addr, reconnected } => {
+                    handler.display_arrived(addr);
+                    if reconnected {
+                        handler.display_reconnected(addr);
+                    }
+                }
+                DebouncedChange::Left(addr) => handler.display_left(addr),
+                DebouncedChange::Count(count) => handler.display_count_changed(count),
+            })
+        })
+    };
+    let usb_hotplug_handler = Arc::new_cyclic(|this| {
+        Mutex::new(UsbHotplugHandler {
+            options,
+            displays: Arc::downgrade(&displays),
+            display_hotplug_handlers: Arc::downgrade(&display_hotplug_handlers),
+            display_ports: BTreeSet::new(),
+            reprobes: BTreeMap::new(),
+            hotplug_debouncer: Arc::new(hotplug_debouncer),
+            this: this.clone(),
+        })
+    });
+    let libusb_hotplug_reg = hotplug_builder
         .register(
             &libusb_context,
-            Box::new(UsbHotplugHandler {
-                displays: Arc::downgrade(&displays),
-                display_hotplug_handlers: Arc::downgrade(&display_hotplug_handlers),
-            }),
+            Box::new(SharedUsbHotplugHandler(usb_hotplug_handler.clone())),
         )
         .expect("Cannot register libusb hotplug handler");
 
@@ -74,84 +803,291 @@ pub fn init() -> Result<State, ()> {
     Ok(State {
         libusb_context,
         libusb_hotplug_reg,
+        usb_hotplug_handler,
         displays,
         display_hotplug_handlers,
+        app_name,
+        wait_for_ready,
     })
 }
 
-impl<T: UsbContext + 'static> rusb::Hotplug<T> for UsbHotplugHandler {
-    fn device_arrived(&mut self, device: rusb::Device<T>) {
+impl UsbHotplugHandler {
+    fn notifier(&self) -> DisplayChangeNotifier {
+        DisplayChangeNotifier {
+            hotplug_debouncer: Arc::downgrade(&self.hotplug_debouncer),
+        }
+    }
+
+    /// Adds the display if the device is one, returning the change to tell the hotplug
+    /// handlers about
+    fn device_arrived<T: UsbContext + 'static>(
+        &mut self,
+        device: rusb::Device<T>,
+    ) -> Option<DisplayChange> {
         let addr = (device.bus_number(), device.address());
 
+        // libusb may report an already known device again (e.g. on re-enumeration).
+        // Devices are told apart by their addresses rather than serial numbers: the serial
+        // number is only read once the device is opened by its thread, and is not known
+        // to be unique (see `State::display_addrs`). A device reconnected under a new address
+        // is paired with the one that has left its port by the hotplug debouncer instead.
+        let is_known = match self.displays.upgrade() {
+            Some(ref rc) => rc.read().expect("State is poisoned").contains_key(&addr),
+            None => false,
+        };
+        if is_known {
+            log::debug!(
+                "USB device {bus_number}-{address} is already known, ignoring it",
+                bus_number = device.bus_number(),
+                address = device.address()
+            );
+            return None;
+        }
+
         let Ok(desc) = device.device_descriptor() else {
             log::warn!(
                 "Could not read USB device {bus_number}-{address} descriptor",
                 bus_number = device.bus_number(),
                 address = device.address()
             );
-            return;
+            return None;
         };
 
-        let display = match (desc.vendor_id(), desc.product_id()) {
-            (usb_ids::VID_SAITEK, usb_ids::PID_SAITEK_FIP) => {
+        let port = device
+            .port_numbers()
+            .ok()
+            .map(|port_numbers| (addr.0, port_numbers));
+        let display = match self
+            .options
+            .device_type(desc.vendor_id(), desc.product_id())
+        {
+            Some(DeviceType::SaitekFip) => {
                 log::info!(
                     "Saitek FIP device detected via USB ({bus_number}-{address})",
                     bus_number = device.bus_number(),
                     address = device.address()
                 );
-                crate::devices::saitek_fip_lcd::new_from_libusb(device)
+                // the host is told the device has left once it fails,
+                // and about the arrival again when it is reconnected or probed again
+                let on_invalidated: InvalidationHandler = {
+                    let displays = self.displays.clone();
+                    let port = port.clone();
+                    let notifier = self.notifier();
+                    let usb_hotplug_handler = self.this.clone();
+                    let device = device.clone();
+                    Box::new(move || {
+                        if let Some(change) = remove_display(&displays, addr, port.clone()) {
+                            notifier.notify(change);
+                        }
+                        reprobe(&usb_hotplug_handler, device, port);
+                    })
+                };
+                crate::devices::saitek_fip_lcd::new_from_libusb(
+                    device,
+                    &self.options,
+                    on_invalidated,
+                )
             }
-            _ => return,
+            None => return None,
         };
 
-        {
-            let Some(ref rc) = self.displays.upgrade() else { return; };
+        let count = {
+            let Some(ref rc) = self.displays.upgrade() else {
+                return None;
+            };
             let mut displays = rc.write().expect("State is poisoned");
             displays.insert(addr, display);
+            displays.len()
+        };
+        let reconnected = port
+            .as_ref()
+            .is_some_and(|port| !self.display_ports.insert(port.clone()));
+        Some(DisplayChange::Arrived {
+            addr,
+            port,
+            reconnected,
+            count,
+        })
+    }
+
+    /// Removes the display, returning the change to tell the hotplug handlers about
+    fn device_left<T: UsbContext>(&mut self, device: rusb::Device<T>) -> Option<DisplayChange> {
+        let port = device
+            .port_numbers()
+            .ok()
+            .map(|port_numbers| (device.bus_number(), port_numbers));
+        // a reconnected device is probed again as many times as a new one
+        if let Some(ref port) = port {
+            self.reprobes.remove(port);
         }
-        {
-            let Some(ref rc) = self.display_hotplug_handlers.upgrade() else { return; };
-            let mut handlers = rc.write().expect("State is poisoned");
-            handlers
-                .iter_mut()
-                .for_each(|handler| handler.display_arrived(addr))
+        // the display may have been removed already, if it has been invalidated
+        remove_display(
+            &self.displays,
+            (device.bus_number(), device.address()),
+            port,
+        )
+    }
+}
+
+/// Probes the device of an invalidated display again, in case it has failed while still
+/// connected (e.g. on garbage reads or a protocol violation), so it's not lost until replugged.
+///
+/// A device that keeps failing is probed with a growing delay, and given up on after
+/// `MAX_REPROBES` until it is reconnected. Devices with an unknown port are not probed again.
+fn reprobe<T: UsbContext + 'static>(
+    usb_hotplug_handler: &Weak<Mutex<UsbHotplugHandler>>,
+    device: rusb::Device<T>,
+    port: Option<UsbPortPath>,
+) {
+    let addr = (device.bus_number(), device.address());
+    let Some(port) = port else {
+        return;
+    };
+    let reprobes = {
+        let Some(usb_hotplug_handler) = usb_hotplug_handler.upgrade() else {
+            return; // the library is deinitialized
+        };
+        let mut handler = usb_hotplug_handler.lock().expect("State is poisoned");
+        let reprobes = handler.reprobes.entry(port).or_default();
+        *reprobes += 1;
+        *reprobes
+    };
+    if reprobes > MAX_REPROBES {
+        log::warn!(
+            "USB device {bus_number}-{address} has failed {count} times, not probing it again until it is reconnected",
+            bus_number = addr.0,
+            address = addr.1,
+            count = reprobes
+        );
+        return;
+    }
+    // a disconnected device is gone from the device list by then
+    std::thread::sleep(REPROBE_DELAY * 2_u32.pow(reprobes - 1));
+    let is_connected = device.context().devices().is_ok_and(|devices| {
+        devices
+            .iter()
+            .any(|connected| (connected.bus_number(), connected.address()) == addr)
+    });
+    if !is_connected {
+        return;
+    }
+    let Some(usb_hotplug_handler) = usb_hotplug_handler.upgrade() else {
+        return; // the library is deinitialized
+    };
+    log::info!(
+        "USB device {bus_number}-{address} is still connected, probing it again",
+        bus_number = addr.0,
+        address = addr.1
+    );
+    let (change, notifier) = {
+        let mut handler = usb_hotplug_handler.lock().expect("State is poisoned");
+        (handler.device_arrived(device), handler.notifier())
+    };
+    if let Some(change) = change {
+        notifier.notify(change);
+    }
+}
+
+/// Removes the display unless it is already removed, returning the change
+/// to tell the hotplug handlers about
+fn remove_display(
+    displays: &Weak<RwLock<BTreeMap<UsbDeviceAddress, Arc<dyn ManagedDisplay>>>>,
+    addr: UsbDeviceAddress,
+    port: Option<UsbPortPath>,
+) -> Option<DisplayChange> {
+    let rc = displays.upgrade()?;
+    let mut displays = rc.write().expect("State is poisoned");
+    displays.remove(&addr)?;
+    log::info!(
+        "USB device disconnected ({bus_number}-{address})",
+        bus_number = addr.0,
+        address = addr.1
+    );
+    Some(DisplayChange::Left {
+        addr,
+        port,
+        count: displays.len(),
+    })
+}
+
+/// Calls every hotplug handler without locking the list of them,
+/// so a handler may add another one
+fn notify_hotplug_handlers(
+    display_hotplug_handlers: &Weak<RwLock<HotplugHandlers>>,
+    notify: impl Fn(&mut dyn Hotplug),
+) {
+    let Some(ref rc) = display_hotplug_handlers.upgrade() else {
+        return;
+    };
+    let handlers = rc.read().expect("State is poisoned").clone();
+    handlers.iter().for_each(|handler| {
+        notify(handler.lock().expect("State is poisoned").as_mut());
+    });
+}
+
+impl State {
+    /// Name of the app that has initialized the library, if it has passed one
+    pub fn app_name(&self) -> Option<&str> {
+        self.app_name.as_deref()
+    }
+
+    /// Blocks until the display is initialized (or has failed to), for at most
+    /// the `Options::wait_for_ready` timeout; doesn't block if it is not set
+    pub fn wait_for_ready(&self, display: &dyn ManagedDisplay) {
+        if let Some(timeout) = self.wait_for_ready {
+            display.wait_for_init(timeout);
         }
     }
 
-    fn device_left(&mut self, device: rusb::Device<T>) {
-        let addr = (device.bus_number(), device.address());
-        {
-            let Some(ref rc) = self.displays.upgrade() else { return; };
-            let mut displays = rc.write().expect("State is poisoned");
-            if displays.remove(&addr).is_none() {
-                return;
-            }
-            log::info!(
-                "USB device disconnected ({bus_number}-{address})",
-                bus_number = device.bus_number(),
-                address = device.address()
-            );
+    /// Same as `wait_for_ready`, for all the displays at once
+    pub fn wait_for_all_ready(&self) {
+        let Some(timeout) = self.wait_for_ready else {
+            return;
+        };
+        let deadline = Instant::now() + timeout;
+        for (_, display) in self.displays() {
+            display.wait_for_init(deadline.saturating_duration_since(Instant::now()));
         }
-        {
-            let Some(ref rc) = self.display_hotplug_handlers.upgrade() else { return; };
-            let mut handlers = rc.write().expect("State is poisoned");
-            handlers
-                .iter_mut()
-                .for_each(|handler| handler.display_left(addr))
+    }
+
+    /// Returns a scanner of the USB devices, to be used without the state locked
+    /// (as it calls the hotplug handlers)
+    pub fn usb_scanner(&self) -> UsbScanner {
+        UsbScanner {
+            libusb_context: self.libusb_context.clone(),
+            usb_hotplug_handler: self.usb_hotplug_handler.clone(),
         }
     }
-}
 
-impl State {
     pub fn add_hotplug_handler(&mut self, hotplug: Box<dyn Hotplug>) {
-        self.display_hotplug_handlers.write().unwrap().push(hotplug);
+        self.display_hotplug_handlers
+            .write()
+            .unwrap()
+            .push(Arc::new(Mutex::new(hotplug)));
     }
 
+    /// Returns addresses of ready displays, ordered by their serial numbers
+    /// (and by the addresses, for displays with the same serial number).
+    ///
+    /// The order (and so the index of a display in it) is stable as long as
+    /// the set of connected displays doesn't change; hotplug invalidates it.
     pub fn display_addrs(&self) -> Vec<UsbDeviceAddress> {
+        let displays = self.displays.read().unwrap();
+        let mut addrs: Vec<(String, UsbDeviceAddress)> = displays
+            .iter()
+            .filter(|kv| kv.1.ready())
+            .map(|kv| (kv.1.serial_number(), *kv.0))
+            .collect();
+        addrs.sort();
+        addrs.into_iter().map(|(_, addr)| addr).collect()
+    }
+
+    /// Returns every display, including the ones that are not ready
+    pub fn displays(&self) -> Vec<(UsbDeviceAddress, Arc<dyn ManagedDisplay>)> {
         let displays = self.displays.read().unwrap();
         displays
             .iter()
-            .filter_map(|kv| if kv.1.ready() { Some(*kv.0) } else { None })
+            .map(|(addr, display)| (*addr, display.clone()))
             .collect()
     }
 
@@ -163,3 +1099,10 @@ impl State {
         }
     }
 }
+
+impl Drop for State {
+    fn drop(&mut self) {
+        // the host may exit right after deinitializing the library
+        snapshots::flush();
+    }
+}
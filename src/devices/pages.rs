@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+
+use bitmask_enum::bitmask;
+
+/// Flags of a page added by the host (`FLAG_*` constants)
+#[bitmask(u32)]
+pub enum PageFlags {
+    SET_AS_ACTIVE = 0x00000001,
+}
+
+impl PageFlags {
+    /// Keeps only the known flags, warning about the others
+    pub fn from_bits_lossy(bits: u32) -> PageFlags {
+        let known_bits = PageFlags::SET_AS_ACTIVE.bits();
+        if bits & !known_bits != 0 {
+            log::warn!("Ignoring unknown page flags ({:#x})", bits & !known_bits);
+        }
+        PageFlags::from(bits & known_bits)
+    }
+}
+
+pub struct Page {
+    pub debug_name: Option<String>,
+    // restored from the state file and not added by the host yet
+    restored: bool,
+}
+
+/// Pages added by the host to a device
+#[derive(Default)]
+pub struct PageRegistry {
+    pages: BTreeMap<u8, Page>,
+    // incremented each time the page is removed, to detect outdated transfers
+    epochs: BTreeMap<u8, u64>,
+    active: Option<u8>,
+}
+
+impl PageRegistry {
+    /// Adds the page, making it active if asked to or if there is no active page yet.
+    ///
+    /// A restored page is adopted rather than taken for a duplicate, as the host
+    /// adds it again once it is restarted.
+    pub fn add(
+        &mut self,
+        page: u8,
+        debug_name: Option<String>,
+        flags: PageFlags,
+    ) -> Result<(), ()> {
+        match self.pages.get_mut(&page) {
+            Some(existing) if existing.restored => {
+                existing.debug_name = debug_name;
+                existing.restored = false;
+            }
+            Some(_) => return Err(()),
+            None => {
+                let page_state = Page {
+                    debug_name,
+                    restored: false,
+                };
+                self.pages.insert(page, page_state);
+            }
+        }
+        if flags.contains(PageFlags::SET_AS_ACTIVE) || self.active.is_none() {
+            self.active = Some(page);
+        }
+        Ok(())
+    }
+
+    /// Adds the page restored from the state file, unless the host has added it already
+    pub fn restore(&mut self, page: u8, active: bool) {
+        if self.pages.contains_key(&page) {
+            return;
+        }
+        self.pages.insert(
+            page,
+            Page {
+                debug_name: None,
+                restored: true,
+            },
+        );
+        if active || self.active.is_none() {
+            self.active = Some(page);
+        }
+    }
+
+    /// Removes the page. If it was active, the next page (wrapping around
+    /// to the first one) becomes active, if there are any pages left.
+    pub fn remove(&mut self, page: u8) -> Result<Page, ()> {
+        let removed = self.pages.remove(&page).ok_or(())?;
+        *self.epochs.entry(page).or_default() += 1;
+        if self.active == Some(page) {
+            self.active = self
+                .pages
+                .range(page..)
+                .chain(self.pages.range(..page))
+                .map(|(page, _)| *page)
+                .next();
+        }
+        Ok(removed)
+    }
+
+    pub fn active(&self) -> Option<u8> {
+        self.active
+    }
+
+    pub fn epoch(&self, page: u8) -> u64 {
+        self.epochs.get(&page).copied().unwrap_or_default()
+    }
+
+    pub fn page_numbers(&self) -> Vec<u8> {
+        self.pages.keys().copied().collect()
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+/// Images of pages being composed between `begin_frame` and `commit_frame`
+#[derive(Default)]
+pub struct PendingFrames {
+    frames: BTreeMap<u8, PendingFrame>,
+}
+
+struct PendingFrame {
+    begun: Instant,
+    // keyed by image index
+    images: BTreeMap<u8, Vec<u8>>,
+}
+
+impl PendingFrames {
+    /// Begins a frame of the page, unless one is begun already
+    pub fn begin(&mut self, page: u8) {
+        self.frames.entry(page).or_insert_with(|| PendingFrame {
+            begun: Instant::now(),
+            images: BTreeMap::new(),
+        });
+    }
+
+    pub fn is_begun(&self, page: u8) -> bool {
+        self.frames.contains_key(&page)
+    }
+
+    /// Returns the image of the slot being composed, starting it from `base`
+    /// if it hasn't been written yet, or `None` if no frame of the page is begun.
+    pub fn image_mut(
+        &mut self,
+        page: u8,
+        index: u8,
+        base: impl FnOnce() -> Vec<u8>,
+    ) -> Option<&mut Vec<u8>> {
+        let frame = self.frames.get_mut(&page)?;
+        Some(frame.images.entry(index).or_insert_with(base))
+    }
+
+    /// Ends the frame of the page, returning its images by image index
+    pub fn take(&mut self, page: u8) -> Option<BTreeMap<u8, Vec<u8>>> {
+        self.frames.remove(&page).map(|frame| frame.images)
+    }
+
+    /// Returns pages of the frames begun more than `timeout` ago
+    pub fn expired(&self, timeout: Duration) -> Vec<u8> {
+        self.frames
+            .iter()
+            .filter(|(_, frame)| frame.begun.elapsed() > timeout)
+            .map(|(page, _)| *page)
+            .collect()
+    }
+
+    /// Returns how long until the first of the frames expires, or `None` if no frame is begun
+    pub fn next_expiry(&self, timeout: Duration) -> Option<Duration> {
+        self.frames
+            .values()
+            .map(|frame| timeout.saturating_sub(frame.begun.elapsed()))
+            .min()
+    }
+}
@@ -1,27 +1,95 @@
 use std::{
-    cell::OnceCell,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     io::Read,
     mem,
-    sync::{Arc, Mutex, RwLock, Weak},
-    time::Duration, thread::sleep,
+    num::TryFromIntError,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, Weak,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
 };
 
 use bitmask_enum::bitmask;
-use num_enum::{IntoPrimitive, TryFromPrimitive, TryFromPrimitiveError};
+use num_enum::{FromPrimitive, IntoPrimitive};
 use uuid::{self, Uuid};
 use zerocopy::{AsBytes, FromBytes, Unaligned};
 
-use crate::devices::ManagedDisplay;
+use crate::{
+    devices::{
+        frame_limiter::FrameLimiter,
+        pages::PageRegistry,
+        pending_frames::PendingFrames,
+        snapshots::{self, Snapshot},
+        uinput::VirtualJoystick,
+        upload_limiter::UploadLimiter,
+        Capabilities, DroppedFrames, InvalidationHandler, ManagedDisplay, Options,
+        PageChangeHandler, PageFlags, QueueDepth, RequestError, RequestStatus, SoftButtons,
+        SoftButtonsHandler, TransferStats, DEVICE_TYPE_SAITEK_FIP,
+    },
+    images::{self, ImageAdjustment, ImageBufferPool},
+};
 
 struct DeviceHandlerWrapper<T: rusb::UsbContext> {
     libusb_handle: rusb::DeviceHandle<T>,
-    hid_endpoint_address: u8,
-    read_endpoint_address: u8,
+    hid_interface_number: Option<u8>,
+    hid_endpoint_address: Option<u8>,
+    hid_max_packet_size: usize,
+    // responses cannot be read without it, see `transcieve_cancellable`
+    read_endpoint_address: Option<u8>,
     write_endpoint_address: u8,
+    // second OUT endpoint some firmwares have, used for the data of file transfers
+    // if `Options::file_transfer_endpoint` is set
+    file_write_endpoint_address: Option<u8>,
+    stats: TransferCounters,
+}
+
+/// Counters of the vendor interface transfers
+#[derive(Default)]
+struct TransferCounters {
+    bytes_written: AtomicU64,
+    bytes_read: AtomicU64,
+    transfers: AtomicU64,
+    errors: AtomicU64,
+    timeouts: AtomicU64,
+    total_latency_us: AtomicU64,
+}
+
+impl TransferCounters {
+    fn record(&self, result: &Result<usize, rusb::Error>, started: Instant, counter: &AtomicU64) {
+        self.transfers.fetch_add(1, Ordering::Relaxed);
+        self.total_latency_us
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        match result {
+            Ok(len) => _ = counter.fetch_add(*len as u64, Ordering::Relaxed),
+            Err(rusb::Error::Timeout) => _ = self.timeouts.fetch_add(1, Ordering::Relaxed),
+            Err(_) => _ = self.errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn snapshot(&self) -> TransferStats {
+        let transfers = self.transfers.load(Ordering::Relaxed);
+        let total_latency_us = self.total_latency_us.load(Ordering::Relaxed);
+        TransferStats {
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            transfers,
+            errors: self.errors.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            average_latency: Duration::from_micros(
+                total_latency_us.checked_div(transfers).unwrap_or(0),
+            ),
+            last_image_sequence: 0,
+            image_uploads_in_flight: 0,
+            malformed_hid_reports: 0,
+        }
+    }
 }
 
 #[allow(clippy::enum_variant_names)]
-#[derive(IntoPrimitive, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, FromPrimitive, IntoPrimitive, PartialEq, Eq)]
 #[repr(u32)]
 enum Request {
     FolderRemoved = 0x02, // ??WHAT??
@@ -33,138 +101,542 @@ enum Request {
     SomeFactoryModeRequest = 0x0a, // ? i'm not sure
     ClearImage = 0x13,
     SetLed = 0x18,
+    /// Opcode not known (yet), kept for logging
+    #[num_enum(catch_all)]
+    Unknown(u32),
 }
 
 impl<T: rusb::UsbContext> DeviceHandlerWrapper<T> {
     fn read_hid(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, rusb::Error> {
         log::trace!("reading hid");
+        let Some(hid_endpoint_address) = self.hid_endpoint_address else {
+            return Err(rusb::Error::NotSupported);
+        };
         self.libusb_handle
-            .read_bulk(self.hid_endpoint_address, buf, timeout)
+            .read_bulk(hid_endpoint_address, buf, timeout)
+    }
+
+    /// Requests the input report through the control endpoint (HID GET_REPORT),
+    /// so it doesn't interfere with the reads of the HID endpoint
+    fn get_hid_input_report(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, rusb::Error> {
+        log::trace!("getting hid input report");
+        let Some(hid_interface_number) = self.hid_interface_number else {
+            return Err(rusb::Error::NotSupported);
+        };
+        self.libusb_handle.read_control(
+            rusb::request_type(
+                rusb::Direction::In,
+                rusb::RequestType::Class,
+                rusb::Recipient::Interface,
+            ),
+            HID_REQUEST_GET_REPORT,
+            HID_REPORT_TYPE_INPUT << 8,
+            hid_interface_number.into(),
+            buf,
+            timeout,
+        )
     }
 
     fn read_bulk(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, rusb::Error> {
         log::trace!("reading bulk");
-        self.libusb_handle
-            .read_bulk(self.read_endpoint_address, buf, timeout)
+        let Some(read_endpoint_address) = self.read_endpoint_address else {
+            return Err(rusb::Error::NotSupported);
+        };
+        let started = Instant::now();
+        let result = self.with_stall_recovery(read_endpoint_address, || {
+            self.libusb_handle
+                .read_bulk(read_endpoint_address, buf, timeout)
+        });
+        self.stats.record(&result, started, &self.stats.bytes_read);
+        result
     }
 
     fn write_bulk(&self, buf: &[u8], timeout: Duration) -> Result<usize, rusb::Error> {
-        log::trace!("writing bulk");
-        self.libusb_handle
-            .write_bulk(self.write_endpoint_address, buf, timeout)
+        self.write_bulk_to(self.write_endpoint_address, buf, timeout)
+    }
+
+    /// Writes the file data to the file transfer endpoint, if the device has one in use
+    fn write_file_bulk(&self, buf: &[u8], timeout: Duration) -> Result<usize, rusb::Error> {
+        let endpoint_address = self
+            .file_write_endpoint_address
+            .unwrap_or(self.write_endpoint_address);
+        self.write_bulk_to(endpoint_address, buf, timeout)
+    }
+
+    fn write_bulk_to(
+        &self,
+        endpoint_address: u8,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, rusb::Error> {
+        log::trace!("writing bulk to {:#04x}", endpoint_address);
+        let started = Instant::now();
+        let result = self.with_stall_recovery(endpoint_address, || {
+            self.libusb_handle
+                .write_bulk(endpoint_address, buf, timeout)
+        });
+        self.stats
+            .record(&result, started, &self.stats.bytes_written);
+        result
+    }
+
+    /// Runs the transfer, and if the endpoint has stalled,
+    /// clears the halt and retries the transfer once
+    fn with_stall_recovery(
+        &self,
+        endpoint_address: u8,
+        mut transfer: impl FnMut() -> Result<usize, rusb::Error>,
+    ) -> Result<usize, rusb::Error> {
+        match transfer() {
+            Err(rusb::Error::Pipe) => {
+                log::warn!(
+                    "Endpoint {:#04x} has stalled, clearing the halt and retrying",
+                    endpoint_address
+                );
+                self.libusb_handle.clear_halt(endpoint_address)?;
+                transfer()
+            }
+            result => result,
+        }
     }
 }
 
 struct UsbSaitekFipLcdInt<T: rusb::UsbContext> {
     handle: DeviceHandlerWrapper<T>,
     serial_number: String,
+    manufacturer: Option<String>,
+    product: Option<String>,
     device_type_uuid: Uuid,
-    vendor_if_mutex: Mutex<()>,
+    vendor_if_lock: VendorInterfaceLock,
+    button_decoder: &'static dyn ButtonDecoder,
+    factory_mode_probe: &'static dyn FactoryModeProbe,
+    // `None` until the first HID report if the report descriptor cannot be read
+    hid_report_layout: Mutex<Option<HidReportLayout>>,
 }
+/// Locking discipline:
+///
+/// - `int` is read-locked for the duration of every transfer and write-locked
+///   only to set it up or to invalidate the device. Invalidation waits for the
+///   transfers in flight, so an operation never loses the device midway, but the
+///   device may be gone by the next one: callers that have checked `ready()`
+///   must still handle `int` being `None` (which is reported as `NoDevice`).
+/// - `int` must not be write-locked by a thread that holds its read lock,
+///   so `invalidate()` is only called after the read guard is dropped.
+/// - Transfers are serialized by `VendorInterfaceLock` (taken inside the `int`
+///   read lock), which also orders them by priority.
+/// - The other mutexes guard plain state and are never held across a transfer
+///   or while calling a host handler.
 struct UsbSaitekFipLcd<T: rusb::UsbContext> {
     libusb_device: rusb::Device<T>,
     int: Arc<RwLock<Option<UsbSaitekFipLcdInt<T>>>>,
+    cache: Mutex<DisplayStateCache>,
+    pages: Mutex<PageRegistry>,
+    soft_buttons: Mutex<SoftButtonsState>,
+    // notified when a button event is queued
+    soft_buttons_condvar: Condvar,
+    // handlers are called without holding the device locks, so they may call the device
+    page_change_handler: Mutex<Option<Arc<Mutex<Box<dyn PageChangeHandler>>>>>,
+    frame_limiter: FrameLimiter,
+    image_uploads: UploadLimiter,
+    pending_frames: Mutex<PendingFrames>,
+    // buffers images are composed in before being set, see `current_image`
+    image_buffers: ImageBufferPool,
+    batch: Mutex<Option<Batch>>,
+    last_status: Mutex<Option<RequestStatus>>,
+    cancelled_transfers: AtomicUsize,
+    malformed_hid_reports: AtomicUsize,
+    // sequence number of the last image sent, see `send_image_data`
+    image_sequence: AtomicU32,
+    // set once the device thread has finished initializing the device (successfully or not)
+    init_finished: Mutex<bool>,
+    init_finished_condvar: Condvar,
+    // set if the device could not be opened because another application holds it
+    in_use_elsewhere: AtomicBool,
+    // set if the device has turned out to be in factory mode, see `ManagedDisplay::is_factory_mode`
+    factory_mode: AtomicBool,
+    init_timeout: Duration,
+    // set if the device has not been initialized in `init_timeout`
+    init_timed_out: AtomicBool,
+    image_adjustment: ImageAdjustment,
+    reset_on_init: bool,
+    keep_hid_driver: bool,
+    skip_factory_mode_check: bool,
+    // see `Options::image_sequence_numbers`
+    image_sequence_numbers: bool,
+    // see `Options::file_transfer_endpoint`
+    file_transfer_endpoint: bool,
+    // pages and LED levels are saved to and restored from it, see `Options::state_file`
+    state_file: Option<PathBuf>,
+    // soft buttons are mirrored to a virtual joystick, see `Options::uinput`
+    uinput_serial_numbers: Option<Vec<String>>,
+    // prepended to the device lifecycle log messages, tells apps sharing a log apart
+    log_prefix: String,
+    // called once the device thread has invalidated the device
+    on_invalidated: Mutex<Option<InvalidationHandler>>,
+}
+
+/// Images and LED levels set since `begin_batch`, only the last ones are sent
+struct Batch {
+    begun: Instant,
+    // keyed by page and image (or LED) index
+    images: BTreeMap<(u8, u8), Arc<[u8]>>,
+    leds: BTreeMap<(u8, u8), u8>,
+}
+
+#[derive(Default)]
+struct SoftButtonsState {
+    last: Option<SoftButtons>,
+    handler: Option<Arc<Mutex<Box<dyn SoftButtonsHandler>>>>,
+    // `None` until the events are polled for the first time
+    events: Option<VecDeque<SoftButtons>>,
+}
+
+/// How many button events may wait for `next_button_event`
+const BUTTON_EVENTS_CAPACITY: usize = 32;
+
+/// LED indices: soft buttons S1-S6 backlights, then the page up and down buttons ones
+const LED_INDICES: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+/// Maximum LED level: 0 is off, 1 is on, 2 is brighter on the revisions
+/// that support it (others treat it as just on)
+const LED_LEVEL_MAX: u8 = 2;
+
+/// Image slots of a page. The FIP shows a single image per page (its slot 0),
+/// so the slots are composited by the library and sent as that image;
+/// their number bounds the images kept for a page.
+const IMAGE_INDICES: [u8; 4] = [0, 1, 2, 3];
+
+/// How long a frame may stay uncommitted before it is committed automatically
+const FRAME_COMMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a batch may stay open before it is ended automatically, so a host
+/// that forgets to end it doesn't have everything it sets buffered forever
+const BATCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Timeout of every transfer of the vendor interface, unless a request is given another one
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often `flush` checks whether the device is idle
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Reading the descriptors right after opening the device fails now and then on some hubs,
+/// so it is tried this many times, with the delay in between
+const DESCRIPTOR_READ_ATTEMPTS: u32 = 3;
+const DESCRIPTOR_READ_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Last state successfully sent to the device, used to restore it on reset
+#[derive(Clone, Default)]
+struct DisplayStateCache {
+    // keyed by page and image index
+    images: BTreeMap<(u8, u8), Arc<[u8]>>,
+    leds: BTreeMap<(u8, u8), u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    Control,
+    Bulk,
+}
+
+/// Lock of the vendor interface which lets control requests (LEDs, clearing, ...)
+/// go ahead of the waiting bulk uploads (images, files).
+///
+/// A transfer that has already started is never interrupted.
+#[derive(Default)]
+struct VendorInterfaceLock {
+    state: Mutex<VendorInterfaceLockState>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct VendorInterfaceLockState {
+    busy: bool,
+    waiting: QueueDepth,
+}
+
+struct VendorInterfaceGuard<'a> {
+    lock: &'a VendorInterfaceLock,
+}
+
+impl VendorInterfaceLock {
+    fn lock(&self, priority: Priority) -> VendorInterfaceGuard<'_> {
+        let mut state = self.state.lock().expect("Device is poisoned");
+        *Self::waiting_mut(&mut state, priority) += 1;
+        let mut state = self
+            .condvar
+            .wait_while(state, |state| {
+                state.busy || (priority == Priority::Bulk && state.waiting.control > 0)
+            })
+            .expect("Device is poisoned");
+        *Self::waiting_mut(&mut state, priority) -= 1;
+        state.busy = true;
+        VendorInterfaceGuard { lock: self }
+    }
+
+    fn waiting_mut<'a>(
+        state: &'a mut MutexGuard<VendorInterfaceLockState>,
+        priority: Priority,
+    ) -> &'a mut usize {
+        match priority {
+            Priority::Control => &mut state.waiting.control,
+            Priority::Bulk => &mut state.waiting.bulk,
+        }
+    }
+
+    fn queue_depth(&self) -> QueueDepth {
+        self.state.lock().expect("Device is poisoned").waiting
+    }
+
+    fn is_idle(&self) -> bool {
+        let state = self.state.lock().expect("Device is poisoned");
+        !state.busy && state.waiting.control == 0 && state.waiting.bulk == 0
+    }
+}
+
+impl Drop for VendorInterfaceGuard<'_> {
+    fn drop(&mut self) {
+        // the interface is released even if a thread has panicked with the state locked,
+        // so the others are not left waiting for it forever
+        let mut state = self
+            .lock
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.busy = false;
+        drop(state);
+        self.lock.condvar.notify_all();
+    }
+}
+
+/// Returns the time left until the init deadline, at most `max`,
+/// or `Timeout` if the deadline has passed
+fn init_time_left(deadline: Instant, max: Duration) -> Result<Duration, rusb::Error> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(left) if !left.is_zero() => Ok(left.min(max)),
+        _ => Err(rusb::Error::Timeout),
+    }
 }
 
 impl<T: rusb::UsbContext> UsbSaitekFipLcdInt<T> {
-    fn new(dev: &UsbSaitekFipLcd<T>) -> Result<UsbSaitekFipLcdInt<T>, rusb::Error> {
+    /// Opens the device; every blocking step is bounded by the deadline
+    fn new(
+        dev: &UsbSaitekFipLcd<T>,
+        deadline: Instant,
+    ) -> Result<UsbSaitekFipLcdInt<T>, rusb::Error> {
+        init_time_left(deadline, Duration::ZERO)?;
         let mut libusb_handle = dev.libusb_device.open()?;
-        let device_descriptor = dev.libusb_device.device_descriptor()?;
-        let config_descriptor = dev.libusb_device.active_config_descriptor()?;
+        init_time_left(deadline, Duration::ZERO)?;
+        let (device_descriptor, config_descriptor) = dev.read_descriptors()?;
 
-        let mut interfaces = config_descriptor.interfaces();
-
-        let hid_interface = interfaces
+        // HID interface may be absent on headless firmwares,
+        // the device is still usable for images and LEDs without it
+        let hid_interface = config_descriptor
+            .interfaces()
             .find(|interface| match interface.descriptors().next() {
                 Some(desc) => desc.class_code() == rusb::constants::LIBUSB_CLASS_HID,
                 None => false,
-            })
-            .expect("Cannot find HID interface of the device");
-        let vendor_interface = interfaces
+            });
+        let hid_interface = match hid_interface {
+            Some(_) if dev.keep_hid_driver => {
+                log::info!("Leaving HID interface of the device to the kernel driver, buttons will not be available");
+                None
+            }
+            hid_interface => hid_interface,
+        };
+        let vendor_interface = config_descriptor
+            .interfaces()
             .find(|interface| match interface.descriptors().next() {
                 Some(desc) => desc.class_code() == rusb::constants::LIBUSB_CLASS_VENDOR_SPEC,
                 None => false,
-            })
-            .expect("Cannot find vendor's interface of the device");
+            });
+        let Some(vendor_interface) = vendor_interface else {
+            log::error!("Cannot find vendor's interface of the device");
+            return Err(rusb::Error::NotFound);
+        };
+        // nothing is claimed on devices that are mapped to a FIP by mistake
+        if !looks_like_fip(&vendor_interface, hid_interface.as_ref()) {
+            log::error!("Device matches the USB IDs, but doesn't look like a FIP");
+            return Err(rusb::Error::NotSupported);
+        }
 
-        _ = libusb_handle.detach_kernel_driver(hid_interface.number());
-        libusb_handle.claim_interface(hid_interface.number())?;
+        if let Some(ref hid_interface) = hid_interface {
+            _ = libusb_handle.detach_kernel_driver(hid_interface.number());
+            libusb_handle.claim_interface(hid_interface.number())?;
+        } else {
+            log::warn!("Cannot find HID interface of the device, buttons will not be available");
+        }
 
         _ = libusb_handle.detach_kernel_driver(vendor_interface.number());
         libusb_handle.claim_interface(vendor_interface.number())?;
 
-        let serial_number = {
-            let langs = libusb_handle.read_languages(std::time::Duration::from_secs(5))?;
-            libusb_handle
-                .read_serial_number_string(
-                    langs[0],
-                    &device_descriptor,
-                    std::time::Duration::from_secs(1),
-                )?
-        };
-
-        // seems like that is just a harcoded uuid
-        // with no way of retreiving it from device itself, but I may be wrong
-        let device_type_uuid = uuid::uuid!("3E083CD8-6A37-4A58-80A8-3D6A2C07513E");
-
-        let hid_endpoint_address: OnceCell<u8> = OnceCell::new();
-        hid_interface
-            .descriptors()
-            .next()
-            .expect("Cannot read device HID interface descriptors")
-            .endpoint_descriptors()
-            .for_each(|endpoint| match endpoint.direction() {
-                rusb::Direction::In => hid_endpoint_address
-                    .set(endpoint.address())
-                    .expect("Found multiple IN endpoints"),
-                rusb::Direction::Out => (),
+        let langs =
+            libusb_handle.read_languages(init_time_left(deadline, Duration::from_secs(5))?)?;
+        let Some(lang) = langs.first().copied() else {
+            log::error!("Device has no string descriptor languages");
+            return Err(rusb::Error::NotFound);
+        };
+        let serial_number = libusb_handle.read_serial_number_string(
+            lang,
+            &device_descriptor,
+            init_time_left(deadline, Duration::from_secs(1))?,
+        )?;
+        // units made after Logitech has acquired Saitek keep the Saitek IDs
+        // and the protocol, only the manufacturer string tells them apart
+        let manufacturer = libusb_handle
+            .read_manufacturer_string(
+                lang,
+                &device_descriptor,
+                init_time_left(deadline, Duration::from_secs(1))?,
+            )
+            .ok();
+        let product = libusb_handle
+            .read_product_string(
+                lang,
+                &device_descriptor,
+                init_time_left(deadline, Duration::from_secs(1))?,
+            )
+            .ok();
+
+        let device_type_uuid = DEVICE_TYPE_SAITEK_FIP;
+
+        // the first endpoint of each direction is used, if there are more of them
+        let hid_endpoint = hid_interface
+            .as_ref()
+            .and_then(|hid_interface| hid_interface.descriptors().next())
+            .and_then(|descriptor| {
+                descriptor
+                    .endpoint_descriptors()
+                    .find(|endpoint| endpoint.direction() == rusb::Direction::In)
             });
+        let (hid_endpoint_address, hid_max_packet_size) = match hid_endpoint {
+            Some(endpoint) => (Some(endpoint.address()), endpoint.max_packet_size().into()),
+            None => {
+                if hid_interface.is_some() {
+                    log::warn!("Cannot find IN endpoint of the HID interface, buttons will not be available");
+                }
+                (None, 0)
+            }
+        };
+        let hid_report_layout = match (hid_endpoint_address, hid_interface.as_ref()) {
+            (Some(_), Some(hid_interface)) => read_hid_report_layout(
+                &libusb_handle,
+                hid_interface.number(),
+                init_time_left(deadline, Duration::from_secs(1))?,
+            ),
+            _ => None,
+        };
 
-        let read_endpoint_address: OnceCell<u8> = OnceCell::new();
-        let write_endpoint_address: OnceCell<u8> = OnceCell::new();
-        vendor_interface
-            .descriptors()
-            .next()
-            .expect("Cannot read device vendor's interface descriptors")
+        let Some(vendor_interface_descriptor) = vendor_interface.descriptors().next() else {
+            log::error!("Cannot read device vendor's interface descriptors");
+            return Err(rusb::Error::NotFound);
+        };
+        let find_vendor_endpoint = |direction| {
+            vendor_interface_descriptor
+                .endpoint_descriptors()
+                .find(|endpoint| endpoint.direction() == direction)
+                .map(|endpoint| endpoint.address())
+        };
+        let read_endpoint_address = find_vendor_endpoint(rusb::Direction::In);
+        // some firmwares have a second OUT endpoint, seemingly for file transfers
+        let write_endpoint_addresses: Vec<u8> = vendor_interface_descriptor
             .endpoint_descriptors()
-            .for_each(|endpoint| match endpoint.direction() {
-                rusb::Direction::In => read_endpoint_address
-                    .set(endpoint.address())
-                    .expect("Found multiple IN endpoints"),
-                rusb::Direction::Out => write_endpoint_address
-                    .set(endpoint.address())
-                    .expect("Found multiple OUT endpoints"),
-            });
+            .filter(|endpoint| endpoint.direction() == rusb::Direction::Out)
+            .map(|endpoint| endpoint.address())
+            .collect();
+        let (write_endpoint_address, file_write_endpoint_address) =
+            match write_endpoint_addresses[..] {
+                [] => {
+                    log::error!("Cannot find OUT endpoint of the vendor's interface");
+                    return Err(rusb::Error::NotFound);
+                }
+                [write_endpoint_address] => (write_endpoint_address, None),
+                [write_endpoint_address, file_write_endpoint_address, ..] => {
+                    log::info!(
+                        "Found second OUT endpoint {:#04x} of the vendor's interface, using it for file transfers: {}",
+                        file_write_endpoint_address,
+                        dev.file_transfer_endpoint
+                    );
+                    (write_endpoint_address, Some(file_write_endpoint_address))
+                }
+            };
+        let file_write_endpoint_address =
+            file_write_endpoint_address.filter(|_| dev.file_transfer_endpoint);
+        if read_endpoint_address.is_none() {
+            log::warn!("Cannot find IN endpoint of the vendor's interface, responses of the device will not be checked");
+        }
 
+        let firmware_version = device_descriptor.device_version();
         log::info!(
-            "Saitek FIP device initialized (serial number: {:?}, type uuid: {:?})",
+            "Saitek FIP device initialized ({:03}-{:03}, serial number: {:?}, type uuid: {:?}, firmware: {}, manufacturer: {:?}, product: {:?})",
+            dev.libusb_device.bus_number(),
+            dev.libusb_device.address(),
             serial_number,
-            device_type_uuid
+            device_type_uuid,
+            firmware_version,
+            manufacturer,
+            product
         );
 
         Ok(UsbSaitekFipLcdInt {
             handle: DeviceHandlerWrapper {
                 libusb_handle,
-                hid_endpoint_address: *hid_endpoint_address
-                    .get()
-                    .expect("Could not find HID endpoint"),
-                read_endpoint_address: *read_endpoint_address
-                    .get()
-                    .expect("Could not find IN endpoint"),
-                write_endpoint_address: *write_endpoint_address
-                    .get()
-                    .expect("Could not find OUT endpoint"),
+                hid_interface_number: hid_endpoint_address
+                    .and(hid_interface.as_ref())
+                    .map(|hid_interface| hid_interface.number()),
+                hid_endpoint_address,
+                hid_max_packet_size,
+                read_endpoint_address,
+                write_endpoint_address,
+                file_write_endpoint_address,
+                stats: TransferCounters::default(),
             },
             serial_number,
+            manufacturer,
+            product,
             device_type_uuid,
-            vendor_if_mutex: Mutex::default(),
+            vendor_if_lock: VendorInterfaceLock::default(),
+            button_decoder: button_decoder(firmware_version),
+            factory_mode_probe: factory_mode_probe(firmware_version),
+            hid_report_layout: Mutex::new(hid_report_layout),
         })
     }
 }
 
+/// Checks the endpoints of the interfaces match the ones of a FIP: bulk endpoints
+/// (at least an OUT one) on the vendor's interface, interrupt endpoints on the HID one
+fn looks_like_fip(
+    vendor_interface: &rusb::Interface,
+    hid_interface: Option<&rusb::Interface>,
+) -> bool {
+    let endpoints = |interface: &rusb::Interface| -> Vec<(rusb::Direction, rusb::TransferType)> {
+        match interface.descriptors().next() {
+            Some(descriptor) => descriptor
+                .endpoint_descriptors()
+                .map(|endpoint| (endpoint.direction(), endpoint.transfer_type()))
+                .collect(),
+            None => Vec::new(),
+        }
+    };
+    let vendor_endpoints = endpoints(vendor_interface);
+    let hid_endpoints = hid_interface.map(endpoints).unwrap_or_default();
+    vendor_endpoints
+        .iter()
+        .any(|(direction, _)| *direction == rusb::Direction::Out)
+        && vendor_endpoints
+            .iter()
+            .all(|(_, transfer_type)| *transfer_type == rusb::TransferType::Bulk)
+        && hid_endpoints
+            .iter()
+            .all(|(_, transfer_type)| *transfer_type == rusb::TransferType::Interrupt)
+}
+
 type BEU32 = zerocopy::byteorder::U32<zerocopy::byteorder::BigEndian>;
 
-#[derive(AsBytes, Debug, FromBytes, Unaligned)]
+#[derive(AsBytes, Clone, Debug, FromBytes, Unaligned)]
 #[repr(C)]
 struct ControlPacket {
     server_id: BEU32,
@@ -190,8 +662,8 @@ impl ControlPacket {
     }
 
     #[inline(always)]
-    fn page(&self) -> u8 {
-        self.page.get().try_into().expect("Got invalid `page`")
+    fn page(&self) -> Result<u8, TryFromIntError> {
+        self.page.get().try_into()
     }
     #[inline(always)]
     fn set_page(&mut self, value: u8) {
@@ -226,8 +698,8 @@ impl ControlPacket {
     }
 
     #[inline(always)]
-    fn request(&self) -> Result<Request, TryFromPrimitiveError<Request>> {
-        Request::try_from(self.request.get())
+    fn request(&self) -> Request {
+        Request::from(self.request.get())
     }
     #[inline(always)]
     fn set_request(&mut self, value: Request) {
@@ -279,8 +751,18 @@ impl ControlPacket {
         self.request_info = value.into()
     }
 
+    /// Nonzero info fields alone are not an error, see `RequestStatus`
     fn has_error(&self) -> bool {
-        self.header_error() > 0 || self.request_error() > 0
+        self.status().is_error()
+    }
+
+    fn status(&self) -> RequestStatus {
+        RequestStatus {
+            header_error: self.header_error(),
+            header_info: self.header_info(),
+            request_error: self.request_error(),
+            request_info: self.request_info(),
+        }
     }
 
     fn new(request: Request) -> ControlPacket {
@@ -298,16 +780,92 @@ impl ControlPacket {
             request_info: 0.into(),
         }
     }
+
+    /// Creates a packet carrying the data, with `data_size` set from it
+    fn with_data(request: Request, data: &[u8]) -> (ControlPacket, Option<&[u8]>) {
+        let mut packet = ControlPacket::new(request);
+        packet.set_data_size(data.len());
+        (packet, Some(data))
+    }
+}
+
+struct Response {
+    packet: ControlPacket,
+    data: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+enum ResponseError {
+    Usb(rusb::Error),
+    DeviceReported(RequestStatus),
+}
+
+impl From<rusb::Error> for ResponseError {
+    fn from(err: rusb::Error) -> ResponseError {
+        ResponseError::Usb(err)
+    }
+}
+
+impl Response {
+    /// Turns the response into an error if the device has reported one
+    fn ok(self) -> Result<Response, ResponseError> {
+        match self.packet.has_error() {
+            false => Ok(self),
+            true => Err(ResponseError::DeviceReported(self.status())),
+        }
+    }
+
+    fn status(&self) -> RequestStatus {
+        self.packet.status()
+    }
+
+    #[allow(dead_code)]
+    fn take_data(&mut self) -> Option<Vec<u8>> {
+        self.data.take()
+    }
+}
+
+/// Maps the transfer result and the error reported by the device to a single error
+fn checked(result: Result<Response, rusb::Error>) -> Result<Response, ()> {
+    match result.map_err(ResponseError::from).and_then(Response::ok) {
+        Ok(response) => {
+            if response.status().has_info() {
+                log::debug!("Request has succeeded with info: {:?}", response.status());
+            }
+            Ok(response)
+        }
+        Err(ResponseError::Usb(rusb::Error::Interrupted)) => Err(()), // cancelled
+        Err(err) => {
+            log::error!("Request has failed: {:?}", err);
+            Err(()) // TODO: error
+        }
+    }
 }
 
 impl<T: rusb::UsbContext> UsbSaitekFipLcdInt<T> {
-    fn _read(&self) -> Result<(ControlPacket, Option<Vec<u8>>), rusb::Error> {
+    /// Returns the HID report layout, taking it from the report if it is not known yet
+    fn hid_report_layout(&self, report: &[u8]) -> HidReportLayout {
+        *self
+            .hid_report_layout
+            .lock()
+            .expect("Device is poisoned")
+            .get_or_insert_with(|| {
+                let layout = HidReportLayout::from_first_report(report.len());
+                log::debug!(
+                    "HID report layout taken from the first report: {:?}",
+                    layout
+                );
+                layout
+            })
+    }
+
+    fn _read(&self, timeout: Duration) -> Result<Response, rusb::Error> {
         let control_packet_bytes = {
             // FIXME(leenr): get rid of initializing a slice somehow
             let mut buffer = [0_u8; mem::size_of::<ControlPacket>()];
             if self
                 .handle
-                .read_bulk(buffer.as_mut_slice(), Duration::from_secs(5))?
+                .read_bulk(buffer.as_mut_slice(), timeout)?
                 == mem::size_of::<ControlPacket>()
             {
                 Ok(buffer)
@@ -317,22 +875,35 @@ impl<T: rusb::UsbContext> UsbSaitekFipLcdInt<T> {
         }?;
         let control_packet =
             ControlPacket::read_from(&control_packet_bytes as &[u8]).expect("Something strange");
-        log::debug!("Read control packet from device: {:?}", control_packet);
+        log::debug!(
+            "Read control packet from device ({:?}): {:?}",
+            control_packet.request(),
+            control_packet
+        );
 
         if control_packet.data_size() == 0 {
-            Ok((control_packet, None))
+            Ok(Response {
+                packet: control_packet,
+                data: None,
+            })
         } else {
             if control_packet.data_size() >= 512 * 1024 {
-                panic!("Too big data size");
+                log::error!("Got too big data size ({:#x})", control_packet.data_size());
+                return Err(rusb::Error::Other);
             }
-            let mut vec = Vec::with_capacity(control_packet.data_size());
-            if self.handle.read_bulk(&mut vec, Duration::from_secs(5))?
-                == control_packet.data_size()
-            {
-                Ok((control_packet, Some(vec)))
-            } else {
-                Err(rusb::Error::Other)
+            let mut vec = vec![0_u8; control_packet.data_size()];
+            // payload may be split into multiple transfers
+            let mut read = 0;
+            while read < vec.len() {
+                match self.handle.read_bulk(&mut vec[read..], timeout)? {
+                    0 => return Err(rusb::Error::Other),
+                    len => read += len,
+                }
             }
+            Ok(Response {
+                packet: control_packet,
+                data: Some(vec),
+            })
         }
     }
 
@@ -340,34 +911,73 @@ impl<T: rusb::UsbContext> UsbSaitekFipLcdInt<T> {
         &self,
         control_packet: ControlPacket,
         data: Option<&[u8]>,
+        timeout: Duration,
     ) -> Result<(), rusb::Error> {
-        if data.unwrap_or(&[]).len() != control_packet.data_size() {
-            panic!("Data size is not the same as the data size in the packet");
-        }
+        debug_assert_eq!(
+            data.unwrap_or(&[]).len(),
+            control_packet.data_size(),
+            "Data size is not the same as the data size in the packet"
+        );
 
         let buffer = control_packet.as_bytes();
-        log::debug!("Write control packet to device: {:?}", control_packet);
-        if self.handle.write_bulk(buffer, Duration::from_secs(5))? != buffer.len() {
+        log::debug!(
+            "Write control packet to device ({:?}): {:?}",
+            control_packet.request(),
+            control_packet
+        );
+        if self.handle.write_bulk(buffer, timeout)? != buffer.len() {
             return Err(rusb::Error::Other);
         }
 
-        if let Some(data) = data && !data.is_empty() {
+        if let Some(data) = data.filter(|data| !data.is_empty()) {
             log::debug!("Write data of len {:?} to device", data.len());
-            if self.handle.write_bulk(data, Duration::from_secs(5))? != data.len() {
+            let written = match control_packet.request() {
+                Request::SaveFile => self.handle.write_file_bulk(data, timeout)?,
+                _ => self.handle.write_bulk(data, timeout)?,
+            };
+            if written != data.len() {
                 return Err(rusb::Error::Other);
             }
         };
         Ok(())
     }
 
-    fn transcieve(
+    /// Sends the packet and reads the response, giving up with `Interrupted` if `is_cancelled`
+    /// returns true while waiting for the interface.
+    /// A transfer that has already started is completed, but its result is discarded.
+    fn transcieve_cancellable(
         &self,
         control_packet: ControlPacket,
         data: Option<&[u8]>,
-    ) -> Result<(ControlPacket, Option<Vec<u8>>), rusb::Error> {
-        let mutex = self.vendor_if_mutex.lock();
-        self._write(control_packet, data)?;
-        self._read()
+        is_cancelled: &dyn Fn() -> bool,
+        timeout: Duration,
+    ) -> Result<Response, rusb::Error> {
+        let priority = match control_packet.request() {
+            Request::SetImage | Request::SaveFile => Priority::Bulk,
+            _ => Priority::Control,
+        };
+        let _guard = self.vendor_if_lock.lock(priority);
+        if is_cancelled() {
+            return Err(rusb::Error::Interrupted);
+        }
+        let response = match self.handle.read_endpoint_address {
+            Some(_) => {
+                self._write(control_packet, data, timeout)?;
+                self._read(timeout)?
+            }
+            // write-only device: the request is assumed to have succeeded
+            None => {
+                self._write(control_packet.clone(), data, timeout)?;
+                Response {
+                    packet: control_packet,
+                    data: None,
+                }
+            }
+        };
+        if is_cancelled() {
+            return Err(rusb::Error::Interrupted);
+        }
+        Ok(response)
     }
 }
 
@@ -387,204 +997,1772 @@ enum Buttons {
     RIGHT_CLOCKWISE = 0b_00000000_00001000,
 }
 
-impl<T: rusb::UsbContext> UsbSaitekFipLcd<T> {
-    fn transmit(
-        &self,
-        control_packet: ControlPacket,
-        data: Option<&[u8]>,
-    ) -> Result<(ControlPacket, Option<Vec<u8>>), rusb::Error> {
-        let int_guard = self.int.read().expect("Device is poisoned");
-        let int = int_guard
-            .as_ref()
-            .expect("Device is gone or not initialized yet");
-        int.transcieve(control_packet, data)
+impl From<Buttons> for SoftButtons {
+    fn from(buttons: Buttons) -> SoftButtons {
+        [
+            (Buttons::S1, SoftButtons::B1),
+            (Buttons::S2, SoftButtons::B2),
+            (Buttons::S3, SoftButtons::B3),
+            (Buttons::S4, SoftButtons::B4),
+            (Buttons::S5, SoftButtons::B5),
+            (Buttons::S6, SoftButtons::B6),
+            (Buttons::LEFT_ANTICLOCKWISE, SoftButtons::LEFT),
+            (Buttons::LEFT_CLOCKWISE, SoftButtons::RIGHT),
+            (Buttons::RIGHT_CLOCKWISE, SoftButtons::UP),
+            (Buttons::RIGHT_ANTICLOCKWISE, SoftButtons::DOWN),
+        ]
+        .into_iter()
+        .filter(|(button, _)| buttons.contains(*button))
+        .fold(SoftButtons::none(), |acc, (_, soft_button)| {
+            acc | soft_button
+        })
     }
+}
 
-    fn _thread_target(device_weak: Weak<UsbSaitekFipLcd<T>>) {
-        let Some(device) = device_weak.upgrade() else { return };
-        let device_int = match UsbSaitekFipLcdInt::new(&device) {
-            Ok(device_int) => device_int,
-            Err(rusb::Error::Access) => {
-                sleep(Duration::from_secs(1));
-                UsbSaitekFipLcdInt::new(&device).expect("Cannot open device")
-            }
-            Err(_) => panic!("Cannot open device")
-        };
+/// Size of the buttons state in the HID report, without the report ID
+const HID_BUTTONS_REPORT_SIZE: usize = 2;
 
-        let (response, _) = device_int
-            .transcieve(ControlPacket::new(Request::SomeFactoryModeRequest), None)
-            .expect("Could not transcieve with the device");
-        if !response.has_error() {
-            log::warn!("Device is set to 'Factory Mode', whatever that means - skipping it");
-            return;
-        }
+/// How many malformed HID reports in a row make the device considered failing
+/// (they come as garbage on flaky connections), so it is reconnected cleanly
+const MALFORMED_HID_REPORTS_LIMIT: usize = 16;
 
-        _ = device
-            .int
-            .write()
-            .expect("Device is poisoned")
-            .replace(device_int);
+const HID_REQUEST_GET_REPORT: u8 = 0x01;
+const HID_REPORT_TYPE_INPUT: u16 = 0x01;
+const HID_DESCRIPTOR_TYPE_REPORT: u16 = 0x22;
 
-        let mut hid_buffer: [u8; 2] = [0, 0];
+/// Where the buttons state is in the HID input reports of a device.
+///
+/// Older firmwares send bare 2-byte reports, while others prefix them with a report ID byte
+/// (and may pad them). This is told by the HID report descriptor, or by the first report
+/// if the descriptor cannot be read, and stays the same for the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct HidReportLayout {
+    report_id: bool,
+    /// Size of a report, including the report ID and the padding
+    report_size: usize,
+}
 
-        loop {
-            let device = match device_weak.upgrade() {
-                Some(device) => device,
-                None => return, // device is dropped
-            };
-            match device
-                .int
-                .read()
-                .expect("Device is poisoned")
-                .as_ref()
-                .unwrap()
-                .handle
-                .read_hid(&mut hid_buffer, Duration::from_secs(5))
-            {
-                Ok(_) => {
-                    let buttons = Buttons::from(
-                        <zerocopy::U16<zerocopy::BigEndian>>::from_bytes(hid_buffer).get(),
-                    );
-                    log::debug!("Got HID buttons: {:#?}", buttons);
-                    // TODO
-                }
-                Err(rusb::Error::Timeout) => {
-                    continue;
-                }
-                Err(rusb::Error::NoDevice) => {
-                    log::info!("Device is disconnected, invalidating it");
-                    if let Ok(mut guard) = device.int.write() {
-                        drop(guard.take()); // invalidate the device
-                    }
-                }
-                Err(err) => {
-                    log::error!("Could not read from device ({}), invalidating it", err);
-                    if let Ok(mut guard) = device.int.write() {
-                        drop(guard.take()); // invalidate the device
-                    }
+impl HidReportLayout {
+    /// Reads the layout from a HID report descriptor, or returns `None` if the descriptor
+    /// is malformed or its input reports are too short to hold the buttons state
+    fn from_report_descriptor(descriptor: &[u8]) -> Option<HidReportLayout> {
+        let mut report_id = false;
+        let (mut report_size, mut report_count, mut input_bits) = (0_usize, 0_usize, 0_usize);
+        let mut rest = descriptor;
+        while let Some((&prefix, tail)) = rest.split_first() {
+            if prefix == 0xfe {
+                // long item: data size, tag, then the data
+                let size = *tail.first()? as usize;
+                rest = tail.get(2 + size..)?;
+                continue;
+            }
+            let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+            let data = tail.get(..size)?;
+            let value = data
+                .iter()
+                .rev()
+                .fold(0_usize, |value, byte| value << 8 | *byte as usize);
+            // item tag and type, without the size
+            match prefix & 0xfc {
+                0x84 => report_id = true,
+                0x74 => report_size = value,
+                0x94 => report_count = value,
+                // constant (padding) fields are counted too, as they are sent
+                0x80 => {
+                    input_bits = input_bits.saturating_add(report_size.saturating_mul(report_count))
                 }
-            };
-            drop(device);
+                _ => (),
+            }
+            rest = &tail[size..];
         }
+        let layout = HidReportLayout {
+            report_id,
+            report_size: input_bits.div_ceil(8).saturating_add(report_id.into()),
+        };
+        (layout.report_size >= layout.buttons_offset() + HID_BUTTONS_REPORT_SIZE).then_some(layout)
     }
-}
-
-pub fn new_from_libusb<T: rusb::UsbContext + 'static>(
-    libusb_device: rusb::Device<T>,
-) -> Arc<dyn ManagedDisplay> {
-    let device = Arc::new(UsbSaitekFipLcd {
-        libusb_device: libusb_device.clone(),
-        int: Arc::default(),
-    });
-
-    let device_ref = Arc::downgrade(&device);
-    std::thread::Builder::new()
-        .name(format!(
-            "Saitek FIP @ {:03}-{:03}",
-            libusb_device.bus_number(),
-            libusb_device.address()
-        ))
-        .spawn(|| UsbSaitekFipLcd::_thread_target(device_ref))
-        .expect("Could not start device thread");
 
-    device
-}
-
-impl<T: rusb::UsbContext> ManagedDisplay for UsbSaitekFipLcd<T> {
-    fn ready(&self) -> bool {
-        self.int.read().is_ok_and(|int| int.is_some())
+    /// Takes the first report for a single report with the buttons state,
+    /// after a report ID if it is longer than the buttons state
+    fn from_first_report(len: usize) -> HidReportLayout {
+        HidReportLayout {
+            report_id: len > HID_BUTTONS_REPORT_SIZE,
+            report_size: len.max(HID_BUTTONS_REPORT_SIZE),
+        }
     }
 
-    fn serial_number(&self) -> String {
-        let int_guard = self.int.read().expect("Device is poisoned");
-        let int = int_guard
-            .as_ref()
-            .expect("Device is gone or not initialized yet");
-        int.serial_number.clone()
+    fn buttons_offset(&self) -> usize {
+        self.report_id.into()
     }
 
-    fn device_type_uuid(&self) -> Uuid {
-        let int_guard = self.int.read().expect("Device is poisoned");
-        let int = int_guard
-            .as_ref()
-            .expect("Device is gone or not initialized yet");
-        int.device_type_uuid
+    /// Returns the buttons state of the report, or `None` if it is too short
+    fn buttons_bytes<'a>(&self, report: &'a [u8]) -> Option<&'a [u8]> {
+        report.get(self.buttons_offset()..self.buttons_offset() + HID_BUTTONS_REPORT_SIZE)
     }
+}
 
-    fn set_image_data(&self, page: u8, data: &[u8; 0x38400]) -> Result<(), ()> {
-        let mut packet = ControlPacket::new(Request::SetImage);
-        packet.set_page(page);
-        packet.set_data_size(data.len());
-        let (packet, _) = self.transmit(packet, Some(data)).map_err(|_| ())?; // TODO: error
-        match packet.has_error() {
-            false => Ok(()),
-            true => Err(()), // TODO
+/// Reads the HID report descriptor of the interface to tell the report layout,
+/// or returns `None` (with a warning) if it cannot be
+fn read_hid_report_layout<T: rusb::UsbContext>(
+    libusb_handle: &rusb::DeviceHandle<T>,
+    interface_number: u8,
+    timeout: Duration,
+) -> Option<HidReportLayout> {
+    let mut descriptor = [0_u8; 1024];
+    let result = libusb_handle.read_control(
+        rusb::request_type(
+            rusb::Direction::In,
+            rusb::RequestType::Standard,
+            rusb::Recipient::Interface,
+        ),
+        rusb::constants::LIBUSB_REQUEST_GET_DESCRIPTOR,
+        HID_DESCRIPTOR_TYPE_REPORT << 8,
+        interface_number.into(),
+        &mut descriptor,
+        timeout,
+    );
+    let layout = match result {
+        Ok(len) => HidReportLayout::from_report_descriptor(&descriptor[..len]),
+        Err(err) => {
+            log::warn!("Cannot read HID report descriptor: {}", err);
+            None
         }
+    };
+    match layout {
+        Some(layout) => log::debug!("HID report layout: {:?}", layout),
+        None => log::warn!("HID report layout is unknown, taking it from the first report"),
     }
+    layout
+}
 
-    fn set_led(&self, page: u8, index: u8, value: bool) -> Result<(), ()> {
-        let mut packet = ControlPacket::new(Request::SetLed);
-        packet.set_param_1(page.into());
-        packet.set_param_2(index.into());
-        packet.set_param_3(value.into());
-        let (packet, _) = self.transmit(packet, None).map_err(|_| ())?; // TODO: error
-        match packet.has_error() {
-            false => Ok(()),
-            true => Err(()), // TODO
-        }
+/// Decodes buttons state from its 2 bytes of a HID input report.
+/// States no device can report are taken for garbage.
+fn parse_hid_report(buttons_bytes: &[u8]) -> Option<Buttons> {
+    let bits = <zerocopy::U16<zerocopy::BigEndian>>::read_from(buttons_bytes)?.get();
+    if bits & !Buttons::all().bits() != 0 {
+        return None; // reserved bits
     }
-
-    fn clear_image(&self, page: u8) -> Result<(), ()> {
-        let mut packet = ControlPacket::new(Request::ClearImage);
-        packet.set_page(page);
-        let (packet, _) = self.transmit(packet, None).map_err(|_| ())?; // TODO: error
-        match packet.has_error() {
-            false => Ok(()),
-            true => Err(()), // TODO
-        }
+    let buttons = Buttons::from(bits);
+    // a knob cannot be turned both ways at once
+    let both_ways = |ways: Buttons| buttons.contains(ways);
+    if both_ways(Buttons::LEFT_ANTICLOCKWISE | Buttons::LEFT_CLOCKWISE)
+        || both_ways(Buttons::RIGHT_ANTICLOCKWISE | Buttons::RIGHT_CLOCKWISE)
+    {
+        return None;
     }
+    Some(buttons)
+}
 
-    fn save_file(&self, page: u8, file: u8, data: &mut dyn Read) -> Result<(), ()> {
-        let mut packet = ControlPacket::new(Request::SaveFile);
-        packet.set_param_1(page.into());
-        packet.set_param_3(file.into());
+/// Decodes buttons state from HID input reports (their buttons state bytes,
+/// see `HidReportLayout`), which may be laid out differently by different firmwares
+trait ButtonDecoder: Send + Sync {
+    fn decode(&self, buttons_bytes: &[u8]) -> Option<SoftButtons>;
+}
 
-        let mut buffer = Vec::new();
-        if let Err(err) = data.read_to_end(&mut buffer) {
-            log::error!("Cannot read data: {:?}", err);
-            return Err(());
-        }
-        packet.set_data_size(buffer.len());
+/// Layout of the `Buttons` bitmask, used by every firmware seen so far
+struct DefaultButtonDecoder;
 
-        let (packet, _) = self
-            .transmit(packet, Some(buffer.as_slice()))
-            .map_err(|_| ())?; // TODO: error
-        match packet.has_error() {
-            false => Ok(()),
-            true => Err(()), // TODO
-        }
+impl ButtonDecoder for DefaultButtonDecoder {
+    fn decode(&self, buttons_bytes: &[u8]) -> Option<SoftButtons> {
+        parse_hid_report(buttons_bytes).map(SoftButtons::from)
     }
+}
 
-    fn display_file(&self, page: u8, index: u8, file: u8) -> Result<(), ()> {
-        let mut packet = ControlPacket::new(Request::SaveFile);
+/// Probes whether the device is in factory mode, see `ManagedDisplay::is_factory_mode`.
+/// Both the request and the interpretation of its response are guesses so far,
+/// so they are kept out of the init code to be corrected as the protocol is understood.
+trait FactoryModeProbe: Send + Sync {
+    fn request(&self) -> Request;
+    fn is_factory_mode(&self, response: &Response) -> bool;
+}
+
+/// The regular firmware rejects `SomeFactoryModeRequest`,
+/// so a response without an error means factory mode
+struct DefaultFactoryModeProbe;
+
+impl FactoryModeProbe for DefaultFactoryModeProbe {
+    fn request(&self) -> Request {
+        Request::SomeFactoryModeRequest
+    }
+
+    fn is_factory_mode(&self, response: &Response) -> bool {
+        !response.packet.has_error()
+    }
+}
+
+/// Picks the factory mode probe for the firmware version (`bcdDevice` of the device)
+fn factory_mode_probe(firmware_version: rusb::Version) -> &'static dyn FactoryModeProbe {
+    log::debug!(
+        "Using the default factory mode probe for firmware {}",
+        firmware_version
+    );
+    &DefaultFactoryModeProbe
+}
+
+/// Picks the buttons decoder for the firmware version (`bcdDevice` of the device)
+fn button_decoder(firmware_version: rusb::Version) -> &'static dyn ButtonDecoder {
+    log::debug!(
+        "Using the default buttons decoder for firmware {}",
+        firmware_version
+    );
+    &DefaultButtonDecoder
+}
+
+impl<T: rusb::UsbContext> UsbSaitekFipLcd<T> {
+    fn transmit(
+        &self,
+        control_packet: ControlPacket,
+        data: Option<&[u8]>,
+    ) -> Result<Response, rusb::Error> {
+        self.transmit_cancellable(control_packet, data, &|| false, TRANSFER_TIMEOUT)
+    }
+
+    /// Transmits a page-bound request, which is cancelled if the page gets removed meanwhile
+    fn transmit_for_page(
+        &self,
+        page: u8,
+        control_packet: ControlPacket,
+        data: Option<&[u8]>,
+        timeout: Duration,
+    ) -> Result<Response, rusb::Error> {
+        let epoch = self.pages.lock().expect("Device is poisoned").epoch(page);
+        let is_cancelled = || self.pages.lock().expect("Device is poisoned").epoch(page) != epoch;
+        let result = self.transmit_cancellable(control_packet, data, &is_cancelled, timeout);
+        if let Err(rusb::Error::Interrupted) = result {
+            log::debug!(
+                "Transfer for page {} is cancelled, the page has been removed",
+                page
+            );
+            self.cancelled_transfers.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Every transfer of the device goes through here, on the calling thread.
+    ///
+    /// The transfers are not handed to a single thread owning the device through
+    /// a command queue: `VendorInterfaceLock` serializes them already (and orders them
+    /// by priority), and the `int` read lock keeps the device from being invalidated
+    /// midway, so a queue would add a thread hop per transfer without making them any safer.
+    fn transmit_cancellable(
+        &self,
+        control_packet: ControlPacket,
+        data: Option<&[u8]>,
+        is_cancelled: &dyn Fn() -> bool,
+        timeout: Duration,
+    ) -> Result<Response, rusb::Error> {
+        let result = {
+            let int_guard = self.int.read().expect("Device is poisoned");
+            // the device may be invalidated by its thread at any time
+            let Some(int) = int_guard.as_ref() else {
+                return Err(rusb::Error::NoDevice);
+            };
+            int.transcieve_cancellable(control_packet, data, is_cancelled, timeout)
+        };
+        match result {
+            Ok(response) => {
+                *self.last_status.lock().expect("Device is poisoned") = Some(response.status());
+                Ok(response)
+            }
+            // `Other` is what malformed or unexpected responses are reported as
+            Err(rusb::Error::Other) => {
+                log::error!(
+                    "{}Device has violated the protocol, invalidating it",
+                    self.log_prefix
+                );
+                self.invalidate();
+                Err(rusb::Error::Other)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn send_image_data(&self, page: u8, index: u8, data: &[u8]) -> Result<(), ()> {
+        let (mut packet, data) = ControlPacket::with_data(Request::SetImage, data);
+        packet.set_page(page);
+        // same slot encoding as in `display_file`
+        packet.set_param_2(index.into());
+        if !self.image_sequence_numbers {
+            return checked(self.transmit_for_page(page, packet, data, TRANSFER_TIMEOUT)).map(drop);
+        }
+        // param_3 is not used by SetImage, so the device is expected to ignore it; a sequence number
+        // there tells whether a frame has been dropped by the host or by the device
+        let sequence = self
+            .image_sequence
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
+        packet.set_param_3(sequence);
+        let response = checked(self.transmit_for_page(page, packet, data, TRANSFER_TIMEOUT))?;
+        log::trace!(
+            "Image {} is sent to slot {} of page {}",
+            sequence,
+            index,
+            page
+        );
+        match response.packet.param_3() {
+            0 => (),
+            echoed if echoed == sequence => {
+                log::trace!("Device has echoed image sequence number {}", sequence)
+            }
+            echoed => log::debug!(
+                "Device has responded to image {} with sequence number {}",
+                sequence,
+                echoed
+            ),
+        }
+        Ok(())
+    }
+
+    fn send_led(&self, page: u8, index: u8, level: u8) -> Result<(), ()> {
+        self.send_led_with_timeout(page, index, level, TRANSFER_TIMEOUT)
+    }
+
+    fn send_led_with_timeout(
+        &self,
+        page: u8,
+        index: u8,
+        level: u8,
+        timeout: Duration,
+    ) -> Result<(), ()> {
+        let mut packet = ControlPacket::new(Request::SetLed);
         packet.set_param_1(page.into());
         packet.set_param_2(index.into());
-        packet.set_param_3(file.into());
-        let (packet, _) = self.transmit(packet, None).map_err(|_| ())?; // TODO: error
-        match packet.has_error() {
-            false => Ok(()),
-            true => Err(()), // TODO
+        packet.set_param_3(level.into());
+        checked(self.transmit_cancellable(packet, None, &|| false, timeout)).map(drop)
+    }
+
+    fn send_clear_image(&self, page: u8) -> Result<(), ()> {
+        self.send_clear_image_with_timeout(page, TRANSFER_TIMEOUT)
+    }
+
+    fn send_clear_image_with_timeout(&self, page: u8, timeout: Duration) -> Result<(), ()> {
+        let mut packet = ControlPacket::new(Request::ClearImage);
+        packet.set_page(page);
+        checked(self.transmit_cancellable(packet, None, &|| false, timeout)).map(drop)
+    }
+
+    /// Blanks the images and turns off the LEDs left over by a previous server,
+    /// on every page it is known to have touched: page 0, which is shown until
+    /// the host adds pages, and the pages saved to the state file, if there is one.
+    /// Requests are not made once the init `deadline` has passed.
+    fn reset_display(&self, deadline: Instant) {
+        let snapshot = self
+            .state_file
+            .as_ref()
+            .and_then(|path| snapshots::load(path, &self.serial_number()));
+        let mut pages = BTreeSet::from([0]);
+        if let Some(snapshot) = snapshot {
+            pages.extend(snapshot.pages);
+            pages.extend(snapshot.leds.keys().map(|(page, _)| *page));
+        }
+        log::debug!(
+            "{}Resetting pages {:?} of the display",
+            self.log_prefix,
+            pages
+        );
+        let time_left = || init_time_left(deadline, TRANSFER_TIMEOUT).map_err(drop);
+        let results = pages.into_iter().flat_map(|page| {
+            let cleared =
+                time_left().and_then(|timeout| self.send_clear_image_with_timeout(page, timeout));
+            std::iter::once(cleared).chain(LED_INDICES.iter().map(move |index| {
+                time_left().and_then(|timeout| self.send_led_with_timeout(page, *index, 0, timeout))
+            }))
+        });
+        if results.fold(Ok(()), Result::and).is_err() {
+            log::warn!("{}Could not reset the display", self.log_prefix);
+        }
+    }
+
+    fn read_descriptors(
+        &self,
+    ) -> Result<(rusb::DeviceDescriptor, rusb::ConfigDescriptor), rusb::Error> {
+        let mut attempt = 1;
+        loop {
+            let result = self.libusb_device.device_descriptor().and_then(|device| {
+                let config = self.libusb_device.active_config_descriptor()?;
+                Ok((device, config))
+            });
+            match result {
+                // there is nothing to retry with a device that is gone
+                Err(err) if err == rusb::Error::NoDevice => return Err(err),
+                Err(err) if attempt < DESCRIPTOR_READ_ATTEMPTS => {
+                    log::warn!(
+                        "{}Could not read the device descriptors ({}), retrying",
+                        self.log_prefix,
+                        err
+                    );
+                    attempt += 1;
+                    sleep(DESCRIPTOR_READ_RETRY_DELAY);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Saves the pages and LED levels to the state file, if there is one
+    fn save_snapshot(&self) {
+        let Some(ref path) = self.state_file else {
+            return;
+        };
+        let snapshot = Snapshot {
+            pages: self.page_numbers(),
+            active_page: self.active_page(),
+            leds: self.led_levels(),
+        };
+        snapshots::save(path, &self.serial_number(), &snapshot);
+    }
+
+    /// Restores the pages and LED levels saved to the state file, if there is one.
+    /// The images are not saved, so the host has to send them again.
+    /// Requests are not made once the init `deadline` has passed.
+    fn restore_snapshot(&self, deadline: Instant) {
+        let Some(ref path) = self.state_file else {
+            return;
+        };
+        let Some(snapshot) = snapshots::load(path, &self.serial_number()) else {
+            return;
+        };
+        log::info!(
+            "{}Restoring pages {:?} and LED levels of the device",
+            self.log_prefix,
+            snapshot.pages
+        );
+        {
+            let mut pages = self.pages.lock().expect("Device is poisoned");
+            snapshot.pages.iter().for_each(|page| {
+                pages.restore(*page, snapshot.active_page == Some(*page));
+            });
+        }
+        for ((page, index), level) in snapshot.leds {
+            if !LED_INDICES.contains(&index) {
+                continue;
+            }
+            let level = level.min(LED_LEVEL_MAX);
+            let Ok(timeout) = init_time_left(deadline, TRANSFER_TIMEOUT) else {
+                log::warn!(
+                    "{}Device has not been initialized in time, not restoring the rest of the LED levels",
+                    self.log_prefix
+                );
+                break;
+            };
+            if self
+                .send_led_with_timeout(page, index, level, timeout)
+                .is_ok()
+            {
+                self.cache
+                    .lock()
+                    .expect("Device is poisoned")
+                    .leds
+                    .insert((page, index), level);
+            }
+        }
+    }
+
+    fn soft_buttons_changed(&self, buttons: SoftButtons) {
+        let handler = {
+            let mut soft_buttons = self.soft_buttons.lock().expect("Device is poisoned");
+            if soft_buttons.last.replace(buttons) == Some(buttons) {
+                return;
+            }
+            if let Some(ref mut events) = soft_buttons.events {
+                if events.len() >= BUTTON_EVENTS_CAPACITY {
+                    log::warn!("Button events are not taken in time, dropping the oldest one");
+                    events.pop_front();
+                }
+                events.push_back(buttons);
+                self.soft_buttons_condvar.notify_all();
+            }
+            soft_buttons.handler.clone()
+        };
+        if let Some(handler) = handler {
+            handler
+                .lock()
+                .expect("Device is poisoned")
+                .soft_buttons_changed(buttons);
+        }
+    }
+
+    fn page_changed(&self, page: u8, is_active: bool) {
+        log::debug!(
+            "Page {} is {}",
+            page,
+            if is_active {
+                "activated"
+            } else {
+                "deactivated"
+            }
+        );
+        let handler = self
+            .page_change_handler
+            .lock()
+            .expect("Device is poisoned")
+            .clone();
+        if let Some(handler) = handler {
+            handler
+                .lock()
+                .expect("Device is poisoned")
+                .page_changed(page, is_active);
+        }
+    }
+
+    /// Returns the last image set to the slot, or a black one, in a buffer
+    /// taken from the pool (to be given back once it is set)
+    fn current_image(&self, page: u8, index: u8) -> Vec<u8> {
+        let mut image = self.image_buffers.take();
+        if let Some(ref batch) = *self.batch.lock().expect("Device is poisoned") {
+            if let Some(data) = batch.images.get(&(page, index)) {
+                image.copy_from_slice(data);
+                return image;
+            }
+        }
+        match self
+            .cache
+            .lock()
+            .expect("Device is poisoned")
+            .images
+            .get(&(page, index))
+        {
+            Some(data) => image.copy_from_slice(data),
+            None => image.fill(0),
+        }
+        image
+    }
+
+    /// Commits the frames that have been begun too long ago (checked by the auto-commit
+    /// thread, and whenever the frame functions are called)
+    fn commit_expired_frames(&self) {
+        let expired = self
+            .pending_frames
+            .lock()
+            .expect("Device is poisoned")
+            .expired(FRAME_COMMIT_TIMEOUT);
+        for page in expired {
+            log::warn!(
+                "Frame of page {} has not been committed in time, committing it",
+                page
+            );
+            _ = self.commit_frame(page);
+        }
+    }
+
+    /// Ends the batch if it has been begun too long ago (checked by the auto-commit thread)
+    fn end_expired_batch(&self) {
+        let is_expired = self
+            .batch
+            .lock()
+            .expect("Device is poisoned")
+            .as_ref()
+            .is_some_and(|batch| batch.begun.elapsed() > BATCH_TIMEOUT);
+        if is_expired {
+            log::warn!("Batch has not been ended in time, ending it");
+            _ = self.end_batch();
+        }
+    }
+
+    /// Returns the images last sent to the slots of the page, by index
+    fn slot_images(&self, page: u8) -> BTreeMap<u8, Arc<[u8]>> {
+        self.cache
+            .lock()
+            .expect("Device is poisoned")
+            .images
+            .range((page, u8::MIN)..=(page, u8::MAX))
+            .map(|((_, index), data)| (*index, data.clone()))
+            .collect()
+    }
+
+    /// Draws the slot images over each other in index order, in a buffer taken from the pool
+    fn composite(&self, slots: &BTreeMap<u8, Arc<[u8]>>) -> Vec<u8> {
+        let mut image = self.image_buffers.take();
+        image.fill(0);
+        slots
+            .values()
+            .for_each(|data| images::draw_over(&mut image, data));
+        image
+    }
+
+    /// Sends the slot images composited as the image of the page,
+    /// or clears it if there are none
+    fn send_slot_images(&self, page: u8, slots: &BTreeMap<u8, Arc<[u8]>>) -> Result<(), ()> {
+        if slots.is_empty() {
+            return self.send_clear_image(page);
+        }
+        let image = self.composite(slots);
+        let result = self.send_image_data(page, 0, &image);
+        self.image_buffers.give(image);
+        result
+    }
+
+    /// Sends the image to the slot of the page, compositing it with the other slots,
+    /// and caches it once it is sent
+    fn upload_image(&self, page: u8, index: u8, data: Arc<[u8]>) -> Result<(), ()> {
+        let mut slots = self.slot_images(page);
+        slots.insert(index, data.clone());
+        self.send_slot_images(page, &slots)?;
+        self.cache
+            .lock()
+            .expect("Device is poisoned")
+            .images
+            .insert((page, index), data);
+        Ok(())
+    }
+
+    /// Sends the last images set on the page again
+    fn resend_page_images(&self, page: u8) -> Result<(), ()> {
+        let slots = self.slot_images(page);
+        if slots.is_empty() {
+            return Ok(());
+        }
+        self.send_slot_images(page, &slots)
+    }
+
+    fn invalidate(&self) {
+        if let Ok(mut guard) = self.int.write() {
+            drop(guard.take());
+        }
+        // the handler is called on its own thread, as the device may be invalidated
+        // while a library function is called (with the library state locked)
+        let on_invalidated = self
+            .on_invalidated
+            .lock()
+            .expect("Device is poisoned")
+            .take();
+        if let Some(on_invalidated) = on_invalidated {
+            let spawn_result = std::thread::Builder::new()
+                .name("Saitek FIP invalidation".to_owned())
+                .spawn(on_invalidated);
+            if let Err(err) = spawn_result {
+                log::error!("Could not start invalidation thread: {}", err);
+            }
         }
     }
 
+    fn finish_init(&self) {
+        *self.init_finished.lock().expect("Device is poisoned") = true;
+        self.init_finished_condvar.notify_all();
+    }
+
+    /// Gives up on the device for good, as it has not been initialized in time
+    fn abandon_init(&self) {
+        log::error!(
+            "{}Device has not been initialized in {:?}, giving up on it",
+            self.log_prefix,
+            self.init_timeout
+        );
+        self.init_timed_out.store(true, Ordering::Relaxed);
+        self.finish_init();
+    }
+
+    fn _thread_target(device_weak: Weak<UsbSaitekFipLcd<T>>) {
+        let Some(device) = device_weak.upgrade() else { return };
+        // opening and reading the descriptors is slow, the device may have been dropped meanwhile
+        let is_abandoned = |device: &Arc<UsbSaitekFipLcd<T>>| {
+            if Arc::strong_count(device) > 1 {
+                return false;
+            }
+            log::debug!(
+                "{}Device has been dropped while initializing, stopping",
+                device.log_prefix
+            );
+            true
+        };
+        // a device that enumerates but never responds would keep the thread busy otherwise
+        let deadline = Instant::now() + device.init_timeout;
+        let device_int = match UsbSaitekFipLcdInt::new(&device, deadline) {
+            Err(rusb::Error::Access) => {
+                sleep(Duration::from_secs(1));
+                if is_abandoned(&device) {
+                    return;
+                }
+                UsbSaitekFipLcdInt::new(&device, deadline)
+            }
+            result => result,
+        };
+        if is_abandoned(&device) {
+            return;
+        }
+        let device_int = match device_int {
+            Ok(device_int) => device_int,
+            Err(err @ (rusb::Error::Busy | rusb::Error::Access)) => {
+                log::error!(
+                    "{}Cannot open device ({}), it is in use by another application (or access to it is not permitted)",
+                    device.log_prefix,
+                    err
+                );
+                device.in_use_elsewhere.store(true, Ordering::Relaxed);
+                device.finish_init();
+                return;
+            }
+            Err(_) if Instant::now() >= deadline => {
+                device.abandon_init();
+                return;
+            }
+            Err(err) => {
+                log::error!("{}Cannot open device: {}", device.log_prefix, err);
+                device.finish_init();
+                return;
+            }
+        };
+
+        // the mode can only be told from the response
+        if !device.skip_factory_mode_check && device_int.handle.read_endpoint_address.is_some() {
+            let response = init_time_left(deadline, TRANSFER_TIMEOUT).and_then(|timeout| {
+                device_int.transcieve_cancellable(
+                    ControlPacket::new(device_int.factory_mode_probe.request()),
+                    None,
+                    &|| false,
+                    timeout,
+                )
+            });
+            let response = match response {
+                Ok(response) => response,
+                Err(_) if Instant::now() >= deadline => {
+                    device.abandon_init();
+                    return;
+                }
+                Err(err) => {
+                    log::error!(
+                        "{}Could not transcieve with the device: {}",
+                        device.log_prefix,
+                        err
+                    );
+                    device.finish_init();
+                    return;
+                }
+            };
+            log::debug!(
+                "{}Factory mode probe response: {:02x?}",
+                device.log_prefix,
+                response.packet.as_bytes()
+            );
+            if device_int.factory_mode_probe.is_factory_mode(&response) {
+                log::warn!(
+                    "{}Device is set to 'Factory Mode', whatever that means - skipping it",
+                    device.log_prefix
+                );
+                // the device stays known, so the host can tell it is there but unusable
+                device.factory_mode.store(true, Ordering::Relaxed);
+                device.finish_init();
+                return;
+            }
+            if is_abandoned(&device) {
+                return;
+            }
+        }
+
+        // thread name only has the USB location, which changes on replug
+        log::info!(
+            "{}Thread {:?} now serving serial number {:?}",
+            device.log_prefix,
+            std::thread::current().name().unwrap_or_default(),
+            device_int.serial_number
+        );
+
+        let has_hid = device_int.handle.hid_endpoint_address.is_some();
+        // a single packet is read at once, so a read returns as soon as it comes;
+        // it may still hold a burst of several reports
+        let hid_packet_size = device_int
+            .handle
+            .hid_max_packet_size
+            .max(HID_BUTTONS_REPORT_SIZE);
+        let mut hid_buffer = vec![0_u8; hid_packet_size];
+        let mut malformed_reports = 0;
+        let mirror_buttons = has_hid
+            && device
+                .uinput_serial_numbers
+                .as_ref()
+                .is_some_and(|serial_numbers| {
+                    serial_numbers.is_empty() || serial_numbers.contains(&device_int.serial_number)
+                });
+        let mut joystick = match mirror_buttons {
+            true => VirtualJoystick::new(&format!("Saitek FIP {}", device_int.serial_number)),
+            false => None,
+        };
+
+        _ = device
+            .int
+            .write()
+            .expect("Device is poisoned")
+            .replace(device_int);
+        if device.reset_on_init {
+            device.reset_display(deadline);
+        }
+        device.restore_snapshot(deadline);
+        if Instant::now() >= deadline {
+            drop(device.int.write().expect("Device is poisoned").take());
+            device.abandon_init();
+            return;
+        }
+        device.finish_init();
+
+        if !has_hid {
+            log::info!(
+                "{}Device has no HID interface, not listening for buttons",
+                device.log_prefix
+            );
+            return;
+        }
+
+        loop {
+            let device = match device_weak.upgrade() {
+                Some(device) => device,
+                None => return, // device is dropped
+            };
+            // the read guard must be released before the device can be invalidated below
+            let (result, button_decoder, layout) =
+                match device.int.read().expect("Device is poisoned").as_ref() {
+                    Some(int) => {
+                        let result = int.handle.read_hid(&mut hid_buffer, Duration::from_secs(5));
+                        let layout = match result {
+                            Ok(len) if len >= HID_BUTTONS_REPORT_SIZE => {
+                                Some(int.hid_report_layout(&hid_buffer[..len]))
+                            }
+                            _ => None,
+                        };
+                        (result, int.button_decoder, layout)
+                    }
+                    None => return, // device is invalidated
+                };
+            match result {
+                Ok(len) if len < HID_BUTTONS_REPORT_SIZE => {
+                    // zero-length packets are sent by some endpoints as keep-alives
+                    log::trace!("Got short HID read ({} bytes), skipping it", len);
+                    continue;
+                }
+                Ok(len) => {
+                    let Some(layout) = layout else { continue };
+                    for report in hid_buffer[..len].chunks(layout.report_size) {
+                        let buttons = layout
+                            .buttons_bytes(report)
+                            .and_then(|buttons_bytes| button_decoder.decode(buttons_bytes));
+                        let Some(buttons) = buttons else {
+                            log::warn!("Got malformed HID report: {:02x?}", report);
+                            malformed_reports += 1;
+                            device.malformed_hid_reports.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        };
+                        malformed_reports = 0;
+                        log::debug!("Got HID buttons: {:#?}", buttons);
+                        device.soft_buttons_changed(buttons);
+                        if let Some(ref mut joystick) = joystick {
+                            joystick.send(buttons);
+                        }
+                    }
+                    if malformed_reports >= MALFORMED_HID_REPORTS_LIMIT {
+                        log::error!(
+                            "{}Got {} malformed HID reports in a row, invalidating the device",
+                            device.log_prefix,
+                            malformed_reports
+                        );
+                        device.invalidate();
+                        return;
+                    }
+                }
+                Err(rusb::Error::Timeout) => {
+                    continue;
+                }
+                Err(rusb::Error::NoDevice) => {
+                    log::info!(
+                        "{}Device is disconnected, invalidating it",
+                        device.log_prefix
+                    );
+                    device.invalidate();
+                    return;
+                }
+                Err(err) => {
+                    log::error!(
+                        "{}Could not read from device ({}), invalidating it",
+                        device.log_prefix,
+                        err
+                    );
+                    device.invalidate();
+                    return;
+                }
+            };
+            drop(device);
+        }
+    }
+
+    /// Commits the frames and ends the batch the host has left open for too long,
+    /// until the device is dropped
+    fn _auto_commit_thread_target(device_weak: Weak<UsbSaitekFipLcd<T>>) {
+        let mut wait = FRAME_COMMIT_TIMEOUT;
+        loop {
+            // a frame or batch begun meanwhile is checked the next time at the latest
+            sleep(wait);
+            // the device is only held while checking, so it can be dropped meanwhile
+            let Some(device) = device_weak.upgrade() else {
+                return;
+            };
+            device.commit_expired_frames();
+            device.end_expired_batch();
+            let frame_expiry = device
+                .pending_frames
+                .lock()
+                .expect("Device is poisoned")
+                .next_expiry(FRAME_COMMIT_TIMEOUT);
+            let batch_expiry = device
+                .batch
+                .lock()
+                .expect("Device is poisoned")
+                .as_ref()
+                .map(|batch| BATCH_TIMEOUT.saturating_sub(batch.begun.elapsed()));
+            wait = frame_expiry
+                .into_iter()
+                .chain(batch_expiry)
+                .min()
+                .unwrap_or(FRAME_COMMIT_TIMEOUT);
+        }
+    }
+}
+
+/// `on_invalidated` is called once the device is disconnected or fails,
+/// and so is not usable anymore.
+pub fn new_from_libusb<T: rusb::UsbContext + 'static>(
+    libusb_device: rusb::Device<T>,
+    options: &Options,
+    on_invalidated: InvalidationHandler,
+) -> Arc<dyn ManagedDisplay> {
+    spawn(libusb_device, options, on_invalidated)
+}
+
+fn spawn<T: rusb::UsbContext + 'static>(
+    libusb_device: rusb::Device<T>,
+    options: &Options,
+    on_invalidated: InvalidationHandler,
+) -> Arc<UsbSaitekFipLcd<T>> {
+    let device = Arc::new(UsbSaitekFipLcd {
+        libusb_device: libusb_device.clone(),
+        int: Arc::default(),
+        cache: Mutex::default(),
+        pages: Mutex::default(),
+        soft_buttons: Mutex::default(),
+        soft_buttons_condvar: Condvar::default(),
+        page_change_handler: Mutex::default(),
+        frame_limiter: FrameLimiter::new(
+            options.min_frame_interval,
+            options.frame_queue_depth(),
+            options.frame_overflow_policy,
+        ),
+        image_uploads: UploadLimiter::new(
+            options.image_upload_cap(),
+            options.frame_overflow_policy,
+        ),
+        pending_frames: Mutex::default(),
+        image_buffers: ImageBufferPool::new(options.image_buffer_pool_size()),
+        batch: Mutex::default(),
+        last_status: Mutex::default(),
+        cancelled_transfers: AtomicUsize::default(),
+        malformed_hid_reports: AtomicUsize::default(),
+        image_sequence: AtomicU32::default(),
+        init_finished: Mutex::default(),
+        init_finished_condvar: Condvar::default(),
+        in_use_elsewhere: AtomicBool::default(),
+        factory_mode: AtomicBool::default(),
+        init_timeout: options.init_timeout_duration(),
+        init_timed_out: AtomicBool::default(),
+        image_adjustment: match options.image_dither_bits {
+            Some(bits) => options.image_adjustment.dithered(bits),
+            None => options.image_adjustment,
+        },
+        reset_on_init: options.reset_on_init,
+        keep_hid_driver: options.keep_hid_driver,
+        skip_factory_mode_check: options.skip_factory_mode_check,
+        image_sequence_numbers: options.image_sequence_numbers,
+        file_transfer_endpoint: options.file_transfer_endpoint,
+        state_file: options.state_file.clone(),
+        uinput_serial_numbers: options.uinput_serial_numbers.clone(),
+        log_prefix: match options.app_name {
+            Some(ref app_name) => format!("[{}] ", app_name),
+            None => String::new(),
+        },
+        on_invalidated: Mutex::new(Some(on_invalidated)),
+    });
+
+    let device_ref = Arc::downgrade(&device);
+    let thread_tuning = options.device_thread_tuning;
+    std::thread::Builder::new()
+        .name(format!(
+            "Saitek FIP @ {:03}-{:03}",
+            libusb_device.bus_number(),
+            libusb_device.address()
+        ))
+        .spawn(move || {
+            thread_tuning.apply();
+            UsbSaitekFipLcd::_thread_target(device_ref)
+        })
+        .expect("Could not start device thread");
+
+    let device_ref = Arc::downgrade(&device);
+    let spawn_result = std::thread::Builder::new()
+        .name(format!(
+            "Saitek FIP @ {:03}-{:03} auto-commits",
+            libusb_device.bus_number(),
+            libusb_device.address()
+        ))
+        .spawn(move || UsbSaitekFipLcd::_auto_commit_thread_target(device_ref));
+    if let Err(err) = spawn_result {
+        log::error!(
+            "{}Could not start auto-commit thread, frames and batches left open will not be ended in time: {}",
+            device.log_prefix,
+            err
+        );
+    }
+
+    device
+}
+
+impl<T: rusb::UsbContext> ManagedDisplay for UsbSaitekFipLcd<T> {
+    fn ready(&self) -> bool {
+        // buttons (HID interface) are not required for images and LEDs to work
+        self.int.read().is_ok_and(|int| int.is_some())
+    }
+
+    fn initializing(&self) -> bool {
+        !*self.init_finished.lock().expect("Device is poisoned")
+    }
+
+    fn wait_for_init(&self, timeout: Duration) -> bool {
+        let (init_finished, wait_result) = self
+            .init_finished_condvar
+            .wait_timeout_while(
+                self.init_finished.lock().expect("Device is poisoned"),
+                timeout,
+                |init_finished| !*init_finished,
+            )
+            .expect("Device is poisoned");
+        drop(init_finished);
+        if wait_result.timed_out() {
+            log::warn!(
+                "{}Device has not been initialized in {:?}",
+                self.log_prefix,
+                timeout
+            );
+        }
+        !wait_result.timed_out()
+    }
+
+    fn in_use_elsewhere(&self) -> bool {
+        self.in_use_elsewhere.load(Ordering::Relaxed)
+    }
+
+    fn is_factory_mode(&self) -> bool {
+        self.factory_mode.load(Ordering::Relaxed)
+    }
+
+    fn init_timed_out(&self) -> bool {
+        self.init_timed_out.load(Ordering::Relaxed)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let int_guard = self.int.read().expect("Device is poisoned");
+        let Some(int) = int_guard.as_ref() else {
+            return Capabilities::none();
+        };
+        let capabilities = Capabilities::IMAGE | Capabilities::LED | Capabilities::FILES;
+        match int.handle.hid_endpoint_address {
+            Some(_) => capabilities | Capabilities::BUTTONS,
+            None => capabilities,
+        }
+    }
+
+    fn queue_depth(&self) -> QueueDepth {
+        match self.int.read().expect("Device is poisoned").as_ref() {
+            Some(int) => int.vendor_if_lock.queue_depth(),
+            None => QueueDepth::default(),
+        }
+    }
+
+    fn transfer_stats(&self) -> TransferStats {
+        let stats = match self.int.read().expect("Device is poisoned").as_ref() {
+            Some(int) => int.handle.stats.snapshot(),
+            None => TransferStats::default(),
+        };
+        TransferStats {
+            last_image_sequence: self.image_sequence.load(Ordering::Relaxed),
+            image_uploads_in_flight: self.image_uploads.in_flight(),
+            malformed_hid_reports: self.malformed_hid_reports.load(Ordering::Relaxed),
+            ..stats
+        }
+    }
+
+    fn flush(&self, timeout: Duration) -> Result<(), ()> {
+        let started = Instant::now();
+        loop {
+            let is_idle = match self.int.read().expect("Device is poisoned").as_ref() {
+                Some(int) => int.vendor_if_lock.is_idle(),
+                None => true, // nothing can be sent to an invalidated device anyway
+            };
+            if is_idle && !self.frame_limiter.has_pending() {
+                return Ok(());
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                return Err(());
+            }
+            sleep(FLUSH_POLL_INTERVAL.min(timeout - elapsed));
+        }
+    }
+
+    fn cancelled_transfers(&self) -> usize {
+        self.cancelled_transfers.load(Ordering::Relaxed)
+    }
+
+    fn dropped_frames(&self) -> DroppedFrames {
+        let dropped = self.frame_limiter.dropped_frames();
+        let dropped_uploads = self.image_uploads.dropped();
+        DroppedFrames {
+            oldest: dropped.oldest + dropped_uploads.oldest,
+            newest: dropped.newest + dropped_uploads.newest,
+        }
+    }
+
+    fn last_request_status(&self) -> Option<RequestStatus> {
+        *self.last_status.lock().expect("Device is poisoned")
+    }
+
+    fn serial_number(&self) -> String {
+        let int_guard = self.int.read().expect("Device is poisoned");
+        let Some(int) = int_guard.as_ref() else {
+            return String::new();
+        };
+        int.serial_number.clone()
+    }
+
+    fn manufacturer(&self) -> Option<String> {
+        let int_guard = self.int.read().expect("Device is poisoned");
+        int_guard.as_ref()?.manufacturer.clone()
+    }
+
+    fn product(&self) -> Option<String> {
+        let int_guard = self.int.read().expect("Device is poisoned");
+        int_guard.as_ref()?.product.clone()
+    }
+
+    fn device_type_uuid(&self) -> Uuid {
+        let int_guard = self.int.read().expect("Device is poisoned");
+        let Some(int) = int_guard.as_ref() else {
+            return Uuid::nil();
+        };
+        int.device_type_uuid
+    }
+
+    fn set_soft_buttons_handler(&self, handler: Box<dyn SoftButtonsHandler>) {
+        let handler = Arc::new(Mutex::new(handler));
+        let last = {
+            let mut soft_buttons = self.soft_buttons.lock().expect("Device is poisoned");
+            soft_buttons.handler.replace(handler.clone());
+            soft_buttons.last
+        };
+        if let Some(buttons) = last {
+            handler
+                .lock()
+                .expect("Device is poisoned")
+                .soft_buttons_changed(buttons);
+        }
+    }
+
+    fn next_button_event(&self, timeout: Duration) -> Option<SoftButtons> {
+        let (mut soft_buttons, _) = self
+            .soft_buttons_condvar
+            .wait_timeout_while(
+                self.soft_buttons.lock().expect("Device is poisoned"),
+                timeout,
+                |soft_buttons| {
+                    soft_buttons
+                        .events
+                        .get_or_insert_with(VecDeque::new)
+                        .is_empty()
+                },
+            )
+            .expect("Device is poisoned");
+        soft_buttons.events.as_mut()?.pop_front()
+    }
+
+    fn read_soft_buttons(&self) -> Result<SoftButtons, ()> {
+        let int_guard = self.int.read().expect("Device is poisoned");
+        let Some(int) = int_guard.as_ref() else {
+            return Err(());
+        };
+        let mut buffer = vec![0_u8; int.handle.hid_max_packet_size.max(HID_BUTTONS_REPORT_SIZE)];
+        let len = int
+            .handle
+            .get_hid_input_report(&mut buffer, Duration::from_secs(5))
+            .map_err(|err| log::error!("Could not read buttons state: {}", err))?;
+        let button_decoder = int.button_decoder;
+        let layout = int.hid_report_layout(&buffer[..len]);
+        drop(int_guard);
+        let buttons = layout
+            .buttons_bytes(&buffer[..len])
+            .and_then(|buttons_bytes| button_decoder.decode(buttons_bytes));
+        let Some(buttons) = buttons else {
+            log::warn!("Got malformed HID report: {:02x?}", &buffer[..len]);
+            return Err(());
+        };
+        // keeps the handler in sync with the state just read
+        self.soft_buttons_changed(buttons);
+        Ok(buttons)
+    }
+
+    fn set_page_change_handler(&self, handler: Box<dyn PageChangeHandler>) {
+        self.page_change_handler
+            .lock()
+            .expect("Device is poisoned")
+            .replace(Arc::new(Mutex::new(handler)));
+    }
+
+    fn add_page(&self, page: u8, debug_name: Option<String>, flags: PageFlags) -> Result<(), ()> {
+        log::debug!("Adding page {} ({:?})", page, debug_name);
+        let is_active = {
+            let mut pages = self.pages.lock().expect("Device is poisoned");
+            pages.add(page, debug_name, flags)?;
+            pages.active() == Some(page)
+        };
+        // a frame may have been begun before the page was added
+        let has_frame = is_active
+            && self
+                .pending_frames
+                .lock()
+                .expect("Device is poisoned")
+                .is_begun(page);
+        if has_frame {
+            _ = self.commit_frame(page);
+        }
+        self.save_snapshot();
+        Ok(())
+    }
+
+    fn remove_page(&self, page: u8) -> Result<(), ()> {
+        let (removed, was_active, activated) = {
+            let mut pages = self.pages.lock().expect("Device is poisoned");
+            let was_active = pages.active() == Some(page);
+            let removed = pages.remove(page)?;
+            (removed, was_active, pages.active())
+        };
+        log::debug!("Removed page {} ({:?})", page, removed.debug_name);
+        // transfers already waiting for the device are cancelled through the page epoch
+        if self.frame_limiter.cancel(page) {
+            self.cancelled_transfers.fetch_add(1, Ordering::Relaxed);
+        }
+        self.image_uploads.cancel(page);
+        self.pending_frames
+            .lock()
+            .expect("Device is poisoned")
+            .take(page);
+        if let Some(ref mut batch) = *self.batch.lock().expect("Device is poisoned") {
+            batch
+                .images
+                .retain(|(batch_page, _), _| *batch_page != page);
+            batch.leds.retain(|(batch_page, _), _| *batch_page != page);
+        }
+
+        if was_active {
+            self.page_changed(page, false);
+            // the page is removed anyway, so failing to update the display is not an error
+            match activated {
+                Some(activated) => {
+                    let has_frame = self
+                        .pending_frames
+                        .lock()
+                        .expect("Device is poisoned")
+                        .is_begun(activated);
+                    _ = match has_frame {
+                        true => self.commit_frame(activated),
+                        false => self.resend_page_images(activated),
+                    };
+                    self.page_changed(activated, true);
+                }
+                None => _ = self.send_clear_image(page),
+            }
+        }
+        self.save_snapshot();
+        Ok(())
+    }
+
+    fn active_page(&self) -> Option<u8> {
+        self.pages.lock().expect("Device is poisoned").active()
+    }
+
+    fn page_numbers(&self) -> Vec<u8> {
+        self.pages
+            .lock()
+            .expect("Device is poisoned")
+            .page_numbers()
+    }
+
+    fn image_adjustment(&self) -> ImageAdjustment {
+        self.image_adjustment
+    }
+
+    fn image_indices(&self, _page: u8) -> Vec<u8> {
+        IMAGE_INDICES.to_vec()
+    }
+
+    fn get_image_data(&self, page: u8, index: u8) -> Result<Vec<u8>, ()> {
+        // there is no known request to read the image back from the device,
+        // so the last image sent to it is returned instead
+        match self
+            .cache
+            .lock()
+            .expect("Device is poisoned")
+            .images
+            .get(&(page, index))
+        {
+            Some(data) => Ok(data.to_vec()),
+            None => Err(()),
+        }
+    }
+
+    fn get_page_image(&self, page: u8) -> Result<Vec<u8>, ()> {
+        // a page without images is cleared, so it's blank
+        Ok(self.composite(&self.slot_images(page)))
+    }
+
+    fn image_data_size(&self) -> usize {
+        images::IMAGE_DATA_SIZE
+    }
+
+    fn set_image_data(&self, page: u8, index: u8, data: &[u8]) -> Result<(), ()> {
+        if !IMAGE_INDICES.contains(&index) {
+            log::error!("Got invalid image index ({})", index);
+            return Err(());
+        }
+        if data.len() != images::IMAGE_DATA_SIZE {
+            log::error!("Got image data of invalid size ({:#x})", data.len());
+            return Err(());
+        }
+        if let Some(ref mut batch) = *self.batch.lock().expect("Device is poisoned") {
+            batch.images.insert((page, index), Arc::from(data));
+            return Ok(());
+        }
+        let Some(data) = self.frame_limiter.throttle(page, index, Arc::from(data)) else {
+            return Ok(()); // will be sent by the caller that is already waiting
+        };
+        let Some(mut upload) = self.image_uploads.start(page, index, &data) else {
+            // too many uploads are in flight, dropped or left for one of them to upload
+            return Ok(());
+        };
+        let result = self.upload_image(page, index, data);
+        while let Some(((page, index), data)) = upload.next_deferred() {
+            _ = self.upload_image(page, index, data);
+        }
+        result
+    }
+
+    fn set_image_region(
+        &self,
+        page: u8,
+        index: u8,
+        region: images::Region,
+        data: &[u8],
+    ) -> Result<(), ()> {
+        if !IMAGE_INDICES.contains(&index) {
+            log::error!("Got invalid image index ({})", index);
+            return Err(());
+        }
+        self.commit_expired_frames();
+
+        {
+            let mut pending_frames = self.pending_frames.lock().expect("Device is poisoned");
+            if let Some(image) =
+                pending_frames.image_mut(page, index, || self.current_image(page, index))
+            {
+                return images::blit(image, region, data);
+            }
+        }
+        let mut image = self.current_image(page, index);
+        let result = images::blit(&mut image, region, data)
+            .and_then(|()| self.set_image_data(page, index, &image));
+        self.image_buffers.give(image);
+        result
+    }
+
+    fn begin_frame(&self, page: u8) {
+        self.commit_expired_frames();
+        self.pending_frames
+            .lock()
+            .expect("Device is poisoned")
+            .begin(page);
+    }
+
+    fn commit_frame(&self, page: u8) -> Result<(), ()> {
+        let Some(images) = self
+            .pending_frames
+            .lock()
+            .expect("Device is poisoned")
+            .take(page)
+        else {
+            return Ok(()); // may have been committed automatically
+        };
+        let mut result = Ok(());
+        for (index, data) in images {
+            result = result.and_then(|()| self.set_image_data(page, index, &data));
+            self.image_buffers.give(data);
+        }
+        result
+    }
+
+    fn fill_color(&self, page: u8, index: u8, r: u8, g: u8, b: u8) -> Result<(), ()> {
+        let mut image = self.image_buffers.take();
+        image
+            .chunks_exact_mut(3)
+            .for_each(|pixel| pixel.copy_from_slice(&[b, g, r]));
+        let result = self.set_image_data(page, index, &image);
+        self.image_buffers.give(image);
+        result
+    }
+
+    fn begin_batch(&self) {
+        self.batch
+            .lock()
+            .expect("Device is poisoned")
+            .get_or_insert_with(|| Batch {
+                begun: Instant::now(),
+                images: BTreeMap::new(),
+                leds: BTreeMap::new(),
+            });
+    }
+
+    fn end_batch(&self) -> Result<(), ()> {
+        let Some(batch) = self.batch.lock().expect("Device is poisoned").take() else {
+            return Ok(());
+        };
+        // everything is sent even if something fails, so the device ends up close to the batch
+        let mut result = Ok(());
+        for ((page, index), data) in batch.images {
+            result = result.and(self.set_image_data(page, index, &data));
+        }
+        let leds = self.led_levels();
+        for ((page, index), level) in batch.leds {
+            if leds.get(&(page, index)) != Some(&level) {
+                result = result.and(self.set_led_level(page, index, level));
+            }
+        }
+        result
+    }
+
+    fn led_indices(&self, _page: u8) -> Vec<u8> {
+        // every page has the same LEDs
+        LED_INDICES.to_vec()
+    }
+
+    fn led_levels(&self) -> BTreeMap<(u8, u8), u8> {
+        self.cache.lock().expect("Device is poisoned").leds.clone()
+    }
+
+    fn set_led_level(&self, page: u8, index: u8, level: u8) -> Result<(), ()> {
+        if !LED_INDICES.contains(&index) {
+            log::error!("Got invalid LED index ({})", index);
+            return Err(());
+        }
+        let level = level.min(LED_LEVEL_MAX);
+        if let Some(ref mut batch) = *self.batch.lock().expect("Device is poisoned") {
+            batch.leds.insert((page, index), level);
+            return Ok(());
+        }
+        self.send_led(page, index, level)?;
+        self.cache
+            .lock()
+            .expect("Device is poisoned")
+            .leds
+            .insert((page, index), level);
+        self.save_snapshot();
+        Ok(())
+    }
+
+    fn release_leds(&self) -> Result<(), ()> {
+        self.batch.lock().expect("Device is poisoned").take();
+        // sent directly, so the levels are neither batched nor saved
+        self.led_levels()
+            .into_iter()
+            .filter(|(_, level)| *level > 0)
+            .fold(Ok(()), |result, ((page, index), _)| {
+                result.and(self.send_led(page, index, 0))
+            })
+    }
+
+    fn clear_image(&self, page: u8) -> Result<(), ()> {
+        // images batched before are not to be shown after the clearing
+        if let Some(ref mut batch) = *self.batch.lock().expect("Device is poisoned") {
+            batch
+                .images
+                .retain(|(batch_page, _), _| *batch_page != page);
+        }
+        self.send_clear_image(page)?;
+        self.cache
+            .lock()
+            .expect("Device is poisoned")
+            .images
+            .retain(|(image_page, _), _| *image_page != page);
+        Ok(())
+    }
+
+    fn clear_image_slot(&self, page: u8, index: u8) -> Result<(), ()> {
+        if !IMAGE_INDICES.contains(&index) {
+            log::error!("Got invalid image index ({})", index);
+            return Err(());
+        }
+        if let Some(ref mut batch) = *self.batch.lock().expect("Device is poisoned") {
+            batch.images.remove(&(page, index));
+        }
+        let mut slots = self.slot_images(page);
+        slots.remove(&index);
+        self.send_slot_images(page, &slots)?;
+        self.cache
+            .lock()
+            .expect("Device is poisoned")
+            .images
+            .remove(&(page, index));
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<(), ()> {
+        // there is no known reset request in the protocol, so clear everything
+        // we have ever touched and send the last known state again
+        let cache = self.cache.lock().expect("Device is poisoned").clone();
+        let pages: BTreeSet<_> = cache.images.keys().map(|(page, _)| *page).collect();
+        for page in pages.iter() {
+            self.send_clear_image(*page)?;
+        }
+        for (page, index) in cache.leds.keys() {
+            self.send_led(*page, *index, 0)?;
+        }
+        for page in pages {
+            self.resend_page_images(page)?;
+        }
+        for ((page, index), value) in cache.leds.iter() {
+            self.send_led(*page, *index, *value)?;
+        }
+        Ok(())
+    }
+
+    fn save_file_bytes(
+        &self,
+        page: u8,
+        file: u8,
+        data: &[u8],
+        status: &mut RequestStatus,
+    ) -> Result<(), RequestError> {
+        self.save_file_bytes_with_timeout(page, file, data, TRANSFER_TIMEOUT, status)
+    }
+
+    fn save_file_bytes_with_timeout(
+        &self,
+        page: u8,
+        file: u8,
+        data: &[u8],
+        timeout: Duration,
+        status: &mut RequestStatus,
+    ) -> Result<(), RequestError> {
+        let (mut packet, data) = ControlPacket::with_data(Request::SaveFile, data);
+        packet.set_param_1(page.into());
+        packet.set_param_3(file.into());
+        let response = match self.transmit_for_page(page, packet, data, timeout) {
+            Ok(response) => response,
+            Err(rusb::Error::Timeout) => {
+                log::error!("Request has timed out after {:?}", timeout);
+                return Err(RequestError::Timeout);
+            }
+            Err(err) => {
+                return checked(Err(err))
+                    .map(drop)
+                    .map_err(|()| RequestError::Failed)
+            }
+        };
+        *status = response.status();
+        checked(Ok(response))
+            .map(drop)
+            .map_err(|()| RequestError::Failed)
+    }
+
+    fn display_file(&self, page: u8, index: u8, file: u8) -> Result<(), ()> {
+        let mut packet = ControlPacket::new(Request::SetImageFile);
+        packet.set_param_1(page.into());
+        packet.set_param_2(index.into());
+        packet.set_param_3(file.into());
+        checked(self.transmit(packet, None)).map(drop)
+    }
+
     fn delete_file(&self, page: u8, file: u8) -> Result<(), ()> {
-        let mut packet = ControlPacket::new(Request::SaveFile);
+        let mut packet = ControlPacket::new(Request::DeleteFile);
         packet.set_param_1(page.into());
         packet.set_param_3(file.into());
-        let (packet, _) = self.transmit(packet, None).map_err(|_| ())?; // TODO: error
-        match packet.has_error() {
-            false => Ok(()),
-            true => Err(()), // TODO
+        checked(self.transmit(packet, None)).map(drop)
+    }
+
+    fn start_server(&self, data: &mut dyn Read, status: &mut RequestStatus) -> Result<u32, ()> {
+        let mut buffer = Vec::new();
+        if let Err(err) = data.read_to_end(&mut buffer) {
+            log::error!("Cannot read data: {:?}", err);
+            return Err(());
         }
+
+        let (packet, data) = ControlPacket::with_data(Request::StartServer, &buffer);
+        let response = self.transmit(packet, data).map_err(|err| {
+            log::error!("Request has failed: {:?}", err);
+        })?;
+        *status = response.status();
+        started_server_id(&response.packet)
+    }
+}
+
+/// Returns the id the device has assigned to the server started, failing unless
+/// the response tells the server has been accepted
+fn started_server_id(packet: &ControlPacket) -> Result<u32, ()> {
+    if !matches!(packet.request(), Request::StartServer) {
+        log::error!(
+            "Got response to another request while starting server: {:?}",
+            packet
+        );
+        return Err(());
+    }
+    if packet.has_error() {
+        log::error!("Device has rejected the server: {:?}", packet);
+        return Err(());
+    }
+    if packet.server_id() == 0 {
+        log::error!("Device has not assigned an id to the server: {:?}", packet);
+        return Err(());
+    }
+    Ok(packet.server_id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 16 one-bit buttons, sent as bare 2-byte reports
+    const BARE_DESCRIPTOR: &[u8] = &[
+        0x05, 0x09, // Usage Page (Button)
+        0xa1, 0x01, // Collection (Application)
+        0x19, 0x01, 0x29, 0x10, // Usage Minimum (1), Usage Maximum (16)
+        0x15, 0x00, 0x25, 0x01, // Logical Minimum (0), Logical Maximum (1)
+        0x75, 0x01, 0x95, 0x10, // Report Size (1), Report Count (16)
+        0x81, 0x02, // Input (Data, Variable, Absolute)
+        0xc0, // End Collection
+    ];
+
+    // the same buttons, after report ID 1 and padded to 8 bytes
+    const REPORT_ID_DESCRIPTOR: &[u8] = &[
+        0x05, 0x09, 0xa1, 0x01, //
+        0x85, 0x01, // Report ID (1)
+        0x19, 0x01, 0x29, 0x10, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x10, 0x81, 0x02, //
+        0x75, 0x08, 0x95, 0x05, // Report Size (8), Report Count (5)
+        0x81, 0x03, // Input (Constant)
+        0xc0,
+    ];
+
+    // the same buttons, without a report ID but padded to 8 bytes
+    const PADDED_DESCRIPTOR: &[u8] = &[
+        0x05, 0x09, 0xa1, 0x01, //
+        0x19, 0x01, 0x29, 0x10, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x10, 0x81, 0x02, //
+        0x75, 0x08, 0x95, 0x06, // Report Size (8), Report Count (6)
+        0x81, 0x03, // Input (Constant)
+        0xc0,
+    ];
+
+    fn decode(layout: HidReportLayout, report: &[u8]) -> Option<Buttons> {
+        layout.buttons_bytes(report).and_then(parse_hid_report)
+    }
+
+    #[test]
+    fn report_layout_from_descriptor() {
+        let layout = |report_id, report_size| HidReportLayout {
+            report_id,
+            report_size,
+        };
+        assert_eq!(
+            HidReportLayout::from_report_descriptor(BARE_DESCRIPTOR),
+            Some(layout(false, 2))
+        );
+        assert_eq!(
+            HidReportLayout::from_report_descriptor(REPORT_ID_DESCRIPTOR),
+            Some(layout(true, 8))
+        );
+        assert_eq!(
+            HidReportLayout::from_report_descriptor(PADDED_DESCRIPTOR),
+            Some(layout(false, 8))
+        );
+    }
+
+    #[test]
+    fn report_layout_from_invalid_descriptor() {
+        // item data cut off
+        let truncated = &BARE_DESCRIPTOR[..BARE_DESCRIPTOR.len() - 4];
+        assert_eq!(HidReportLayout::from_report_descriptor(truncated), None);
+        // input reports of a single byte
+        let short = [0x75, 0x01, 0x95, 0x08, 0x81, 0x02];
+        assert_eq!(HidReportLayout::from_report_descriptor(&short), None);
+        assert_eq!(HidReportLayout::from_report_descriptor(&[]), None);
+    }
+
+    #[test]
+    fn decode_reports() {
+        let bare = HidReportLayout::from_report_descriptor(BARE_DESCRIPTOR).unwrap();
+        let report_id = HidReportLayout::from_report_descriptor(REPORT_ID_DESCRIPTOR).unwrap();
+        let padded = HidReportLayout::from_report_descriptor(PADDED_DESCRIPTOR).unwrap();
+        let expected = Some(Buttons::S1 | Buttons::DOWN);
+
+        assert_eq!(decode(bare, &[0x01, 0x02]), expected);
+        assert_eq!(
+            decode(report_id, &[0x01, 0x01, 0x02, 0, 0, 0, 0, 0]),
+            expected
+        );
+        // a padded bare report is not taken for one with a report ID
+        assert_eq!(decode(padded, &[0x01, 0x02, 0, 0, 0, 0, 0, 0]), expected);
+        assert_eq!(decode(report_id, &[0x01, 0x01]), None);
+    }
+
+    #[test]
+    fn report_layout_from_first_report() {
+        let bare = HidReportLayout::from_first_report(2);
+        assert_eq!(
+            decode(bare, &[0x01, 0x02]),
+            Some(Buttons::S1 | Buttons::DOWN)
+        );
+        let report_id = HidReportLayout::from_first_report(3);
+        assert_eq!(
+            decode(report_id, &[0x01, 0x01, 0x02]),
+            Some(Buttons::S1 | Buttons::DOWN)
+        );
+    }
+
+    #[test]
+    fn reject_garbage_reports() {
+        // a knob turned both ways at once
+        assert_eq!(parse_hid_report(&[0b1100_0000, 0]), None);
+        assert_eq!(parse_hid_report(&[0, 0b0000_1100]), None);
+        // reserved bits
+        assert_eq!(parse_hid_report(&[0, 0b0001_0000]), None);
+        assert_eq!(parse_hid_report(&[0x01]), None);
+        assert_eq!(parse_hid_report(&[0, 0]), Some(Buttons::none()));
+    }
+
+    fn start_server_response(server_id: u32) -> ControlPacket {
+        let mut packet = ControlPacket::new(Request::StartServer);
+        packet.set_server_id(server_id);
+        packet
+    }
+
+    #[test]
+    fn accepts_started_server() {
+        assert_eq!(started_server_id(&start_server_response(3)), Ok(3));
+    }
+
+    #[test]
+    fn rejects_servers_not_started() {
+        let mut rejected = start_server_response(3);
+        rejected.set_request_error(1);
+        assert_eq!(started_server_id(&rejected), Err(()));
+
+        let mut header_error = start_server_response(3);
+        header_error.set_header_error(1);
+        assert_eq!(started_server_id(&header_error), Err(()));
+
+        // the host could not use a server without an id
+        assert_eq!(started_server_id(&start_server_response(0)), Err(()));
+
+        let mut other_request = start_server_response(3);
+        other_request.set_request(Request::SaveFile);
+        assert_eq!(started_server_id(&other_request), Err(()));
     }
 }
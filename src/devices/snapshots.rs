@@ -0,0 +1,311 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex, Once},
+    time::{Duration, Instant},
+};
+
+/// How long a changed snapshot waits to be written, so a burst of changes
+/// (e.g. a host adding all of its pages) is written to the state file once
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// Pages and LED levels of a device, saved so a restarted host finds its panels as they were
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub pages: Vec<u8>,
+    pub active_page: Option<u8>,
+    // keyed by page and LED index
+    pub leds: BTreeMap<(u8, u8), u8>,
+}
+
+// devices save their snapshots from their own threads, into the same file;
+// the pending snapshots are only taken with it locked, so they are written in order
+static STATE_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Snapshots not written yet, keyed by the state file and the serial number
+struct PendingSnapshots {
+    snapshots: BTreeMap<(PathBuf, String), Snapshot>,
+    // when the oldest of them has been saved
+    since: Option<Instant>,
+}
+
+static PENDING: Mutex<PendingSnapshots> = Mutex::new(PendingSnapshots {
+    snapshots: BTreeMap::new(),
+    since: None,
+});
+static PENDING_CONDVAR: Condvar = Condvar::new();
+static WRITING_THREAD: Once = Once::new();
+
+/// Returns the snapshot of the device with the serial number saved in the state file, if any
+pub fn load(path: &Path, serial_number: &str) -> Option<Snapshot> {
+    let _guard = STATE_FILE_LOCK.lock().expect("State file lock is poisoned");
+    let pending = PENDING
+        .lock()
+        .expect("Pending snapshots are poisoned")
+        .snapshots
+        .get(&(path.to_owned(), serial_number.to_owned()))
+        .cloned();
+    pending.or_else(|| read(path).remove(serial_number))
+}
+
+/// Replaces the snapshot of the device with the serial number in the state file,
+/// keeping the snapshots of other devices.
+///
+/// The file is written shortly after, from a thread of its own, so the device
+/// is not held up by the file I/O.
+pub fn save(path: &Path, serial_number: &str, snapshot: &Snapshot) {
+    if serial_number.is_empty() || serial_number.contains(char::is_whitespace) {
+        log::warn!(
+            "Cannot save the state of device with serial number {:?}",
+            serial_number
+        );
+        return;
+    }
+    WRITING_THREAD.call_once(|| {
+        let spawn_result = std::thread::Builder::new()
+            .name("State file writing".to_owned())
+            .spawn(write_pending_loop);
+        if let Err(err) = spawn_result {
+            log::error!("Could not start state file writing thread: {}", err);
+        }
+    });
+    let mut pending = PENDING.lock().expect("Pending snapshots are poisoned");
+    pending.snapshots.insert(
+        (path.to_owned(), serial_number.to_owned()),
+        snapshot.clone(),
+    );
+    pending.since.get_or_insert_with(Instant::now);
+    PENDING_CONDVAR.notify_all();
+}
+
+/// Writes the snapshots not written yet at once, e.g. before the library is deinitialized
+pub fn flush() {
+    let _guard = STATE_FILE_LOCK.lock().expect("State file lock is poisoned");
+    write(take_pending());
+}
+
+fn take_pending() -> BTreeMap<(PathBuf, String), Snapshot> {
+    let mut pending = PENDING.lock().expect("Pending snapshots are poisoned");
+    pending.since = None;
+    std::mem::take(&mut pending.snapshots)
+}
+
+fn write_pending_loop() {
+    loop {
+        {
+            let mut pending = PENDING.lock().expect("Pending snapshots are poisoned");
+            loop {
+                match pending.since.map(|since| since.elapsed()) {
+                    Some(elapsed) if elapsed >= SAVE_DELAY => break,
+                    Some(elapsed) => {
+                        pending = PENDING_CONDVAR
+                            .wait_timeout(pending, SAVE_DELAY - elapsed)
+                            .expect("Pending snapshots are poisoned")
+                            .0;
+                    }
+                    None => {
+                        pending = PENDING_CONDVAR
+                            .wait(pending)
+                            .expect("Pending snapshots are poisoned");
+                    }
+                }
+            }
+        }
+        let _guard = STATE_FILE_LOCK.lock().expect("State file lock is poisoned");
+        write(take_pending());
+    }
+}
+
+/// Writes the snapshots to their state files, keeping the snapshots of other devices
+fn write(snapshots: BTreeMap<(PathBuf, String), Snapshot>) {
+    let mut by_path: BTreeMap<PathBuf, Vec<(String, Snapshot)>> = BTreeMap::new();
+    snapshots
+        .into_iter()
+        .for_each(|((path, serial_number), snapshot)| {
+            by_path
+                .entry(path)
+                .or_default()
+                .push((serial_number, snapshot))
+        });
+    for (path, changed) in by_path {
+        let mut snapshots = read(&path);
+        snapshots.extend(changed);
+
+        let mut text =
+            "# <serial number> <active page> <pages> <page:LED index:level>\n".to_owned();
+        snapshots.iter().for_each(|(serial_number, snapshot)| {
+            text.push_str(&format_line(serial_number, snapshot));
+            text.push('\n');
+        });
+        // the file is replaced at once, so a crash while writing doesn't corrupt it
+        let temp_path = path.with_extension("tmp");
+        if let Err(err) = fs::write(&temp_path, text).and_then(|()| fs::rename(&temp_path, &path)) {
+            log::error!("Cannot write state file {:?}: {}", path, err);
+        }
+    }
+}
+
+/// Reads every snapshot of the state file. A missing file has none,
+/// malformed lines are skipped (with a warning).
+fn read(path: &Path) -> BTreeMap<String, Snapshot> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(err) => {
+            log::error!("Cannot read state file {:?}: {}", path, err);
+            return BTreeMap::new();
+        }
+    };
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|(line_number, line)| {
+            let parsed = parse_line(line);
+            if parsed.is_none() {
+                log::warn!(
+                    "Ignoring malformed state file line {}: {:?}",
+                    line_number,
+                    line
+                );
+            }
+            parsed
+        })
+        .collect()
+}
+
+// empty lists and no active page are written as `-`
+fn format_line(serial_number: &str, snapshot: &Snapshot) -> String {
+    let list = |items: Vec<String>| match items.is_empty() {
+        true => "-".to_owned(),
+        false => items.join(","),
+    };
+    format!(
+        "{} {} {} {}",
+        serial_number,
+        snapshot
+            .active_page
+            .map_or("-".to_owned(), |page| page.to_string()),
+        list(snapshot.pages.iter().map(u8::to_string).collect()),
+        list(
+            snapshot
+                .leds
+                .iter()
+                .map(|((page, index), level)| format!("{}:{}:{}", page, index, level))
+                .collect()
+        )
+    )
+}
+
+fn parse_line(line: &str) -> Option<(String, Snapshot)> {
+    let mut fields = line.split_whitespace();
+    let (Some(serial_number), Some(active_page), Some(pages), Some(leds), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return None;
+    };
+    let list = |field: &str| match field {
+        "-" => Vec::new(),
+        field => field.split(',').map(str::to_owned).collect(),
+    };
+
+    let active_page = match active_page {
+        "-" => None,
+        page => Some(page.parse().ok()?),
+    };
+    let pages = list(pages)
+        .iter()
+        .map(|page| page.parse().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    if active_page.is_some_and(|page| !pages.contains(&page)) {
+        return None;
+    }
+    let leds = list(leds)
+        .iter()
+        .map(|led| {
+            let mut parts = led.split(':').map(str::parse::<u8>);
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(Ok(page)), Some(Ok(index)), Some(Ok(level)), None) => {
+                    Some(((page, index), level))
+                }
+                _ => None,
+            }
+        })
+        .collect::<Option<BTreeMap<(u8, u8), u8>>>()?;
+
+    Some((
+        serial_number.to_owned(),
+        Snapshot {
+            pages,
+            active_page,
+            leds,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            pages: vec![0, 3],
+            active_page: Some(3),
+            leds: BTreeMap::from([((0, 1), 1), ((3, 5), 0)]),
+        }
+    }
+
+    #[test]
+    fn line_round_trip() {
+        let line = format_line("ABC123", &snapshot());
+        assert_eq!(line, "ABC123 3 0,3 0:1:1,3:5:0");
+        assert_eq!(parse_line(&line), Some(("ABC123".to_owned(), snapshot())));
+
+        let empty = Snapshot::default();
+        let line = format_line("ABC123", &empty);
+        assert_eq!(line, "ABC123 - - -");
+        assert_eq!(parse_line(&line), Some(("ABC123".to_owned(), empty)));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for line in [
+            "ABC123 3 0,3",
+            "ABC123 3 0,3 0:1:1 extra",
+            "ABC123 4 0,3 0:1:1",
+            "ABC123 - 0,x 0:1:1",
+            "ABC123 - 0,256 0:1:1",
+            "ABC123 - 0,3 0:1",
+            "ABC123 - 0,3 0:1:1:1",
+            "ABC123 - 0,3 0:1:x",
+        ] {
+            assert_eq!(parse_line(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn read_skips_malformed_lines() {
+        let path = std::env::temp_dir().join(format!("libfip-snapshots-{}", std::process::id()));
+        let text = format!(
+            "# comment\n\n{}\nDEF456 4 0,3 -\n  GHI789 - - -  \n",
+            format_line("ABC123", &snapshot())
+        );
+        fs::write(&path, text).expect("Cannot write the state file");
+        let snapshots = read(&path);
+        fs::remove_file(&path).expect("Cannot remove the state file");
+
+        assert_eq!(
+            snapshots,
+            BTreeMap::from([
+                ("ABC123".to_owned(), snapshot()),
+                ("GHI789".to_owned(), Snapshot::default()),
+            ])
+        );
+        assert!(read(&path).is_empty());
+    }
+}
@@ -0,0 +1,61 @@
+/// Scheduling settings of device threads, see `Options::device_thread_priority`
+/// and `Options::device_thread_cpu`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadTuning {
+    /// Nice value, lower is a higher priority (below 0 needs a privileged process)
+    pub nice: Option<i32>,
+    pub cpu: Option<usize>,
+}
+
+impl ThreadTuning {
+    /// Applies the settings to the calling thread; the ones that cannot be
+    /// applied are logged and skipped
+    pub fn apply(&self) {
+        if let Some(nice) = self.nice {
+            match set_nice(nice) {
+                Ok(()) => log::info!("Device thread priority is set to nice {}", nice),
+                Err(err) => log::warn!("Cannot set device thread priority: {}", err),
+            }
+        }
+        if let Some(cpu) = self.cpu {
+            match set_cpu(cpu) {
+                Ok(()) => log::info!("Device thread is pinned to CPU {}", cpu),
+                Err(err) => log::warn!("Cannot pin device thread to CPU {}: {}", cpu, err),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> std::io::Result<()> {
+    // on Linux, the priority of a thread id is that of the thread alone
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_cpu(cpu: usize) -> std::io::Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(std::io::ErrorKind::InvalidInput.into());
+    }
+    let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut cpu_set) };
+    // pid 0 is the calling thread
+    match unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i32) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cpu(_cpu: usize) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,186 @@
+//! Virtual joystick mirroring the soft buttons of a device, for sims that don't talk DirectOutput.
+//!
+//! Only available on Linux with the `uinput` feature. The process needs write access
+//! to `/dev/uinput`, e.g. with a udev rule like `KERNEL=="uinput", GROUP="input", MODE="0660"`
+//! and the user in the `input` group.
+
+#[cfg(all(feature = "uinput", target_os = "linux"))]
+pub use linux::VirtualJoystick;
+
+/// Stands in for the virtual joystick where uinput is not available
+#[cfg(not(all(feature = "uinput", target_os = "linux")))]
+pub struct VirtualJoystick;
+
+#[cfg(not(all(feature = "uinput", target_os = "linux")))]
+impl VirtualJoystick {
+    pub fn new(_name: &str) -> Option<VirtualJoystick> {
+        log::warn!("Virtual joysticks are only supported on Linux, with the uinput feature");
+        None
+    }
+
+    pub fn send(&mut self, _buttons: super::SoftButtons) {}
+}
+
+#[cfg(all(feature = "uinput", target_os = "linux"))]
+mod linux {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{self, Write},
+        mem,
+        os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+        slice,
+    };
+
+    use crate::devices::{usb_ids, SoftButtons};
+
+    // ioctl requests of linux/uinput.h, their encoding differs between architectures
+    const UINPUT_IOCTL_BASE: u32 = b'U' as u32;
+    const UI_DEV_CREATE: libc::Ioctl = libc::_IO(UINPUT_IOCTL_BASE, 1);
+    const UI_DEV_DESTROY: libc::Ioctl = libc::_IO(UINPUT_IOCTL_BASE, 2);
+    const UI_DEV_SETUP: libc::Ioctl = libc::_IOW::<libc::uinput_setup>(UINPUT_IOCTL_BASE, 3);
+    const UI_SET_EVBIT: libc::Ioctl = libc::_IOW::<libc::c_int>(UINPUT_IOCTL_BASE, 100);
+    const UI_SET_KEYBIT: libc::Ioctl = libc::_IOW::<libc::c_int>(UINPUT_IOCTL_BASE, 101);
+    const UI_SET_RELBIT: libc::Ioctl = libc::_IOW::<libc::c_int>(UINPUT_IOCTL_BASE, 102);
+
+    // event types and codes of linux/input-event-codes.h
+    const EV_SYN: u16 = 0x00;
+    const EV_KEY: u16 = 0x01;
+    const EV_REL: u16 = 0x02;
+    const SYN_REPORT: u16 = 0x00;
+    const REL_DIAL: u16 = 0x07;
+    const REL_WHEEL: u16 = 0x08;
+    const BTN_TRIGGER_HAPPY1: u16 = 0x2c0;
+    const BUS_USB: u16 = 0x03;
+
+    /// S1-S6 soft buttons, as joystick buttons 1-6
+    const BUTTONS: [SoftButtons; 6] = [
+        SoftButtons::B1,
+        SoftButtons::B2,
+        SoftButtons::B3,
+        SoftButtons::B4,
+        SoftButtons::B5,
+        SoftButtons::B6,
+    ];
+
+    /// Knob turns, as steps of relative axes: the left knob is the dial, the right one the wheel
+    const KNOB_STEPS: [(SoftButtons, u16, i32); 4] = [
+        (SoftButtons::LEFT, REL_DIAL, -1),
+        (SoftButtons::RIGHT, REL_DIAL, 1),
+        (SoftButtons::UP, REL_WHEEL, 1),
+        (SoftButtons::DOWN, REL_WHEEL, -1),
+    ];
+
+    pub struct VirtualJoystick {
+        file: File,
+        last: SoftButtons,
+    }
+
+    impl VirtualJoystick {
+        /// Creates the virtual device, or returns `None` (with an error logged) if it cannot be
+        pub fn new(name: &str) -> Option<VirtualJoystick> {
+            let file = match OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open("/dev/uinput")
+            {
+                Ok(file) => file,
+                Err(err) => {
+                    log::error!("Cannot open /dev/uinput: {}", err);
+                    return None;
+                }
+            };
+
+            let mut setup: libc::uinput_setup = unsafe { mem::zeroed() };
+            setup.id.bustype = BUS_USB;
+            setup.id.vendor = usb_ids::VID_SAITEK;
+            setup.id.product = usb_ids::PID_SAITEK_FIP;
+            // the name must stay NUL-terminated
+            name.bytes()
+                .take(libc::UINPUT_MAX_NAME_SIZE - 1)
+                .enumerate()
+                .for_each(|(i, byte)| setup.name[i] = byte as libc::c_char);
+
+            let fd = file.as_raw_fd();
+            let mut bits = vec![
+                (UI_SET_EVBIT, EV_KEY),
+                (UI_SET_EVBIT, EV_REL),
+                (UI_SET_RELBIT, REL_DIAL),
+                (UI_SET_RELBIT, REL_WHEEL),
+            ];
+            bits.extend((0..BUTTONS.len() as u16).map(|i| (UI_SET_KEYBIT, BTN_TRIGGER_HAPPY1 + i)));
+            // the bits have to be set before the device is created
+            let set_bits = bits.into_iter().all(
+                |(request, bit)| unsafe { libc::ioctl(fd, request, libc::c_int::from(bit)) } >= 0,
+            );
+            let failed = !set_bits
+                || unsafe { libc::ioctl(fd, UI_DEV_SETUP, &setup as *const libc::uinput_setup) }
+                    < 0
+                || unsafe { libc::ioctl(fd, UI_DEV_CREATE) } < 0;
+            if failed {
+                log::error!(
+                    "Cannot create virtual joystick: {}",
+                    io::Error::last_os_error()
+                );
+                return None;
+            }
+            log::info!("Virtual joystick {:?} created", name);
+            Some(VirtualJoystick {
+                file,
+                last: SoftButtons::none(),
+            })
+        }
+
+        /// Sends the changes since the last buttons state, knob turns are sent on their start
+        pub fn send(&mut self, buttons: SoftButtons) {
+            let changed = self.last ^ buttons;
+            let pressed = buttons & changed;
+            self.last = buttons;
+
+            let mut events: Vec<(u16, u16, i32)> = BUTTONS
+                .iter()
+                .enumerate()
+                .filter(|(_, button)| changed.contains(**button))
+                .map(|(i, button)| {
+                    (
+                        EV_KEY,
+                        BTN_TRIGGER_HAPPY1 + i as u16,
+                        buttons.contains(*button).into(),
+                    )
+                })
+                .collect();
+            events.extend(
+                KNOB_STEPS
+                    .iter()
+                    .filter(|(turn, _, _)| pressed.contains(*turn))
+                    .map(|(_, axis, step)| (EV_REL, *axis, *step)),
+            );
+            if events.is_empty() {
+                return;
+            }
+            events.push((EV_SYN, SYN_REPORT, 0));
+
+            for (type_, code, value) in events {
+                let mut event: libc::input_event = unsafe { mem::zeroed() };
+                event.type_ = type_;
+                event.code = code;
+                event.value = value;
+                let bytes = unsafe {
+                    slice::from_raw_parts(
+                        (&event as *const libc::input_event).cast::<u8>(),
+                        mem::size_of::<libc::input_event>(),
+                    )
+                };
+                if let Err(err) = self.file.write_all(bytes) {
+                    log::warn!("Cannot send virtual joystick event: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    impl Drop for VirtualJoystick {
+        fn drop(&mut self) {
+            unsafe { libc::ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY) };
+        }
+    }
+}
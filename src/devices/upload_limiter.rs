@@ -0,0 +1,144 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use super::{DroppedFrames, FrameOverflowPolicy};
+
+/// Caps the number of image uploads of a device in flight at once (being sent or waiting
+/// for the device), so a host setting images faster than they are sent doesn't pile them up.
+///
+/// An upload that comes when the cap is reached waits for another one to finish
+/// with the `Block` overflow policy, and is dropped with `DropNewest`. Uploads that have
+/// been started cannot be taken back, so with `DropOldest` the image is deferred instead,
+/// replacing the one of the same image slot deferred before, and is uploaded by the caller
+/// of an upload in flight once it is done. The display so ends up with the latest image.
+pub struct UploadLimiter {
+    max_in_flight: usize,
+    overflow_policy: FrameOverflowPolicy,
+    state: Mutex<UploadLimiterState>,
+    // notified when an upload finishes
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct UploadLimiterState {
+    in_flight: usize,
+    // keyed by page and image index
+    deferred: BTreeMap<(u8, u8), Arc<[u8]>>,
+    dropped: DroppedFrames,
+}
+
+/// Upload counted as in flight until dropped, or until there are no deferred images
+/// left to upload (see `UploadGuard::next_deferred`)
+pub struct UploadGuard<'a> {
+    limiter: &'a UploadLimiter,
+    finished: bool,
+}
+
+impl UploadLimiter {
+    pub fn new(max_in_flight: usize, overflow_policy: FrameOverflowPolicy) -> UploadLimiter {
+        UploadLimiter {
+            max_in_flight: max_in_flight.max(1),
+            overflow_policy,
+            state: Mutex::default(),
+            condvar: Condvar::default(),
+        }
+    }
+
+    /// Returns a guard to hold while the upload of the image slot's `data` is in flight,
+    /// or `None` if the upload has been dropped or deferred
+    pub fn start(&self, page: u8, index: u8, data: &Arc<[u8]>) -> Option<UploadGuard<'_>> {
+        let mut state = self.state.lock().expect("Upload limiter is poisoned");
+        if state.in_flight >= self.max_in_flight {
+            match self.overflow_policy {
+                FrameOverflowPolicy::DropOldest => {
+                    if state.deferred.insert((page, index), data.clone()).is_some() {
+                        state.dropped.oldest += 1;
+                    }
+                    log::debug!(
+                        "{} image uploads are in flight already, deferring the image",
+                        state.in_flight
+                    );
+                    return None;
+                }
+                FrameOverflowPolicy::DropNewest => {
+                    state.dropped.newest += 1;
+                    log::debug!(
+                        "{} image uploads are in flight already, dropping the image",
+                        state.in_flight
+                    );
+                    return None;
+                }
+                FrameOverflowPolicy::Block => {
+                    state = self
+                        .condvar
+                        .wait_while(state, |state| state.in_flight >= self.max_in_flight)
+                        .expect("Upload limiter is poisoned");
+                }
+            }
+        }
+        // the image is newer than the deferred one
+        state.deferred.remove(&(page, index));
+        state.in_flight += 1;
+        Some(UploadGuard {
+            limiter: self,
+            finished: false,
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state
+            .lock()
+            .expect("Upload limiter is poisoned")
+            .in_flight
+    }
+
+    /// Number of uploads dropped because of the cap
+    pub fn dropped(&self) -> DroppedFrames {
+        self.state
+            .lock()
+            .expect("Upload limiter is poisoned")
+            .dropped
+    }
+
+    /// Drops the images deferred for the page
+    pub fn cancel(&self, page: u8) {
+        self.state
+            .lock()
+            .expect("Upload limiter is poisoned")
+            .deferred
+            .retain(|(deferred_page, _), _| *deferred_page != page);
+    }
+}
+
+impl UploadGuard<'_> {
+    /// Returns the next image (with its page and index) deferred while the uploads
+    /// were in flight, for the caller to upload as well. Once there are none left,
+    /// the upload is finished.
+    pub fn next_deferred(&mut self) -> Option<((u8, u8), Arc<[u8]>)> {
+        let mut state = self.limiter.state.lock().expect("Upload limiter is poisoned");
+        if let Some(deferred) = state.deferred.pop_first() {
+            return Some(deferred);
+        }
+        // finished with the limiter locked, so no image is deferred to a finished upload
+        if !self.finished {
+            self.finished = true;
+            state.in_flight -= 1;
+            self.limiter.condvar.notify_all();
+        }
+        None
+    }
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Ok(mut state) = self.limiter.state.lock() {
+            state.in_flight -= 1;
+        }
+        self.limiter.condvar.notify_all();
+    }
+}
@@ -1,2 +1,134 @@
+use uuid::Uuid;
+
+// Logitech-branded FIPs report the same IDs; units with other IDs
+// can be mapped to a device type with `parse_device_ids`
 pub const VID_SAITEK: u16 = 0x06a3;
 pub const PID_SAITEK_FIP: u16 = 0xa2ae;
+
+/// Device type GUID of the FIP, as returned by `DirectOutput_GetDeviceType`.
+/// It seems to be just hardcoded, with no way of retrieving it from the device itself.
+pub const DEVICE_TYPE_SAITEK_FIP: Uuid = uuid::uuid!("3E083CD8-6A37-4A58-80A8-3D6A2C07513E");
+
+/// Device implementations a USB device can be handled by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    SaitekFip,
+}
+
+impl DeviceType {
+    fn from_name(name: &str) -> Option<DeviceType> {
+        match name {
+            "saitek-fip" => Some(DeviceType::SaitekFip),
+            _ => None,
+        }
+    }
+}
+
+pub type DeviceIds = Vec<((u16, u16), DeviceType)>;
+
+const BUILTIN_DEVICE_IDS: [((u16, u16), DeviceType); 1] =
+    [((VID_SAITEK, PID_SAITEK_FIP), DeviceType::SaitekFip)];
+
+pub fn builtin_device_type(vendor_id: u16, product_id: u16) -> Option<DeviceType> {
+    BUILTIN_DEVICE_IDS
+        .iter()
+        .find(|(ids, _)| *ids == (vendor_id, product_id))
+        .map(|(_, device_type)| *device_type)
+}
+
+/// Parses a mapping of USB IDs to device types, one `<vid>:<pid> <device type>` per line,
+/// IDs being hexadecimal (e.g. `06a3:a2ae saitek-fip`).
+///
+/// Empty lines and lines starting with `#` are skipped, so are the lines
+/// with unknown device types (with a warning). Any other malformed line
+/// makes the whole mapping invalid.
+pub fn parse_device_ids(text: &str) -> Result<DeviceIds, ()> {
+    let mut device_ids = DeviceIds::new();
+    for (line_number, line) in text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+    {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(ids), Some(device_type_name), None) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            log::error!("Malformed device IDs line {}: {:?}", line_number, line);
+            return Err(());
+        };
+        let Some((vendor_id, product_id)) = ids.split_once(':') else {
+            log::error!(
+                "Malformed USB IDs on device IDs line {}: {:?}",
+                line_number,
+                ids
+            );
+            return Err(());
+        };
+        let (Ok(vendor_id), Ok(product_id)) = (
+            u16::from_str_radix(vendor_id, 16),
+            u16::from_str_radix(product_id, 16),
+        ) else {
+            log::error!(
+                "Malformed USB IDs on device IDs line {}: {:?}",
+                line_number,
+                ids
+            );
+            return Err(());
+        };
+        let Some(device_type) = DeviceType::from_name(device_type_name) else {
+            log::warn!(
+                "Ignoring unknown device type on device IDs line {}: {:?}",
+                line_number,
+                device_type_name
+            );
+            continue;
+        };
+        device_ids.push(((vendor_id, product_id), device_type));
+    }
+    Ok(device_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid() {
+        let text = "# Logitech FIPs\n\n06a3:a2ae saitek-fip\n  046D:0A2B\tsaitek-fip  \n";
+        assert_eq!(
+            parse_device_ids(text),
+            Ok(vec![
+                ((0x06a3, 0xa2ae), DeviceType::SaitekFip),
+                ((0x046d, 0x0a2b), DeviceType::SaitekFip),
+            ])
+        );
+        assert_eq!(parse_device_ids(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn parse_skips_unknown_device_types() {
+        assert_eq!(
+            parse_device_ids("06a3:a2af saitek-x52\n06a3:a2ae saitek-fip"),
+            Ok(vec![((0x06a3, 0xa2ae), DeviceType::SaitekFip)])
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        for text in [
+            "06a3:a2ae",
+            "06a3:a2ae saitek-fip extra",
+            "06a3a2ae saitek-fip",
+            "06a3:xyz saitek-fip",
+            "06a3:1a2ae saitek-fip",
+            ":a2ae saitek-fip",
+            // a malformed line invalidates the valid ones too
+            "06a3:a2ae saitek-fip\n06a3 saitek-fip",
+        ] {
+            assert_eq!(parse_device_ids(text), Err(()), "{:?}", text);
+        }
+    }
+}
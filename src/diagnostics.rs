@@ -0,0 +1,86 @@
+use std::fmt::Write;
+
+use crate::devices::{self, ManagedDisplay, TransferStats, UsbDeviceAddress};
+
+/// Describes the library state and every device as JSON text, for bug reports
+pub fn dump_state(state: &devices::State) -> String {
+    let devices: Vec<String> = state
+        .displays()
+        .iter()
+        .map(|(addr, display)| dump_display(*addr, display.as_ref()))
+        .collect();
+    format!(
+        r#"{{"version":{},"app_name":{},"devices":[{}]}}"#,
+        json_string(env!("CARGO_PKG_VERSION")),
+        state.app_name().map_or("null".to_owned(), json_string),
+        devices.join(",")
+    )
+}
+
+fn dump_display(addr: UsbDeviceAddress, display: &dyn ManagedDisplay) -> String {
+    let mut json = format!(
+        r#"{{"bus_number":{},"address":{},"ready":{},"init_timed_out":{}"#,
+        addr.0,
+        addr.1,
+        display.ready(),
+        display.init_timed_out()
+    );
+    // most of the state is only there once the device is initialized
+    if display.ready() {
+        let pages: Vec<String> = display.page_numbers().iter().map(u8::to_string).collect();
+        let leds: Vec<String> = display
+            .led_levels()
+            .iter()
+            .map(|((page, index), level)| {
+                format!(r#"{{"page":{},"index":{},"level":{}}}"#, page, index, level)
+            })
+            .collect();
+        _ = write!(
+            json,
+            r#","serial_number":{},"manufacturer":{},"product":{},"active_page":{},"pages":[{}],"leds":[{}],"transfer_stats":{}"#,
+            json_string(&display.serial_number()),
+            display
+                .manufacturer()
+                .map_or("null".to_owned(), |value| json_string(&value)),
+            display
+                .product()
+                .map_or("null".to_owned(), |value| json_string(&value)),
+            display
+                .active_page()
+                .map_or("null".to_owned(), |page| page.to_string()),
+            pages.join(","),
+            leds.join(","),
+            dump_transfer_stats(&display.transfer_stats())
+        );
+    }
+    json.push('}');
+    json
+}
+
+fn dump_transfer_stats(stats: &TransferStats) -> String {
+    format!(
+        r#"{{"bytes_written":{},"bytes_read":{},"transfers":{},"errors":{},"timeouts":{},"average_latency_us":{},"last_image_sequence":{},"image_uploads_in_flight":{},"malformed_hid_reports":{}}}"#,
+        stats.bytes_written,
+        stats.bytes_read,
+        stats.transfers,
+        stats.errors,
+        stats.timeouts,
+        stats.average_latency.as_micros(),
+        stats.last_image_sequence,
+        stats.image_uploads_in_flight,
+        stats.malformed_hid_reports
+    )
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    value.chars().for_each(|char| match char {
+        '"' => json.push_str("\\\""),
+        '\\' => json.push_str("\\\\"),
+        char if char.is_control() => _ = write!(json, "\\u{:04x}", char as u32),
+        char => json.push(char),
+    });
+    json.push('"');
+    json
+}
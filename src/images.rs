@@ -0,0 +1,546 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+pub const IMAGE_WIDTH: u32 = 320;
+pub const IMAGE_HEIGHT: u32 = 240;
+/// Size of image data in the device format (24 bpp)
+pub const IMAGE_DATA_SIZE: usize = (IMAGE_WIDTH * IMAGE_HEIGHT * 3) as usize;
+
+const IMAGE_CACHE_CAPACITY: usize = 16;
+
+/// Gamma and contrast adjustment of images, applied as a lookup table over the color bytes,
+/// optionally followed by dithering
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImageAdjustment {
+    // `None` is the identity transform
+    lut: Option<[u8; 256]>,
+    // bits per color channel the image is dithered to
+    dither_bits: Option<u8>,
+}
+
+/// Thresholds of ordered dithering (a 4x4 Bayer matrix), indexed by row and column
+const BAYER_MATRIX: [[u32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+impl ImageAdjustment {
+    /// `gamma` above 1 brightens the image, `contrast` above 1 increases the contrast;
+    /// 1 for both is the identity transform.
+    pub fn new(gamma: f32, contrast: f32) -> ImageAdjustment {
+        if gamma == 1.0 && contrast == 1.0 {
+            return ImageAdjustment::default();
+        }
+        let mut lut = [0_u8; 256];
+        lut.iter_mut().enumerate().for_each(|(value, adjusted)| {
+            let value = (value as f32 / 255.0).powf(1.0 / gamma);
+            let value = (value - 0.5) * contrast + 0.5;
+            *adjusted = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        });
+        ImageAdjustment {
+            lut: Some(lut),
+            dither_bits: None,
+        }
+    }
+
+    /// Adds ordered dithering of the adjusted image to `bits` per color channel,
+    /// which hides the banding of smooth gradients on panels of a lower color depth
+    /// (8 bits, the depth of the image data, disables it)
+    pub fn dithered(self, bits: u8) -> ImageAdjustment {
+        ImageAdjustment {
+            dither_bits: Some(bits.clamp(1, 8)).filter(|bits| *bits < 8),
+            ..self
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.lut.is_none() && self.dither_bits.is_none()
+    }
+
+    /// Adjusts image data in the device format
+    pub fn apply(&self, data: &mut [u8]) {
+        if let Some(ref lut) = self.lut {
+            data.iter_mut()
+                .for_each(|value| *value = lut[*value as usize]);
+        }
+        if let Some(bits) = self.dither_bits {
+            dither(data, bits);
+        }
+    }
+}
+
+/// Quantizes every color byte to `bits` (below 8) with ordered dithering,
+/// in integers so the result is the same everywhere
+fn dither(data: &mut [u8], bits: u8) {
+    let levels = (1_u32 << bits) - 1;
+    data.chunks_exact_mut(3)
+        .enumerate()
+        .for_each(|(pixel, color)| {
+            let x = pixel % IMAGE_WIDTH as usize;
+            let y = pixel / IMAGE_WIDTH as usize;
+            let threshold = BAYER_MATRIX[y % 4][x % 4];
+            color.iter_mut().for_each(|value| {
+                // the threshold shifts the value by -0.5..0.5 of a level before it is rounded down
+                let level = (*value as u32 * levels * 32 + 255 * (2 * threshold + 1)) / (255 * 32);
+                *value = ((level.min(levels) * 255 + levels / 2) / levels) as u8;
+            });
+        });
+}
+
+#[derive(PartialEq, Eq)]
+struct ImageCacheKey {
+    path: PathBuf,
+    mtime: SystemTime,
+    size: u64,
+}
+
+/// Image data of a file, in the device format
+#[derive(Clone)]
+pub struct ImageFile {
+    pub data: Arc<[u8]>,
+    /// Whether the file has been taken as raw image data, which is to be sent as it is
+    pub raw: bool,
+}
+
+/// LRU cache of already converted image files
+pub struct ImageCache {
+    // most recently used entries are at the back
+    entries: VecDeque<(ImageCacheKey, ImageFile)>,
+    raw_files: bool,
+}
+
+impl ImageCache {
+    pub const fn new() -> ImageCache {
+        ImageCache {
+            entries: VecDeque::new(),
+            raw_files: true,
+        }
+    }
+
+    /// Sets whether files of exactly `IMAGE_DATA_SIZE` bytes are taken as raw image data
+    /// in the device format (e.g. dumped from the official tools) instead of being decoded
+    /// (enabled by default)
+    pub fn set_raw_files(&mut self, raw_files: bool) {
+        if raw_files != self.raw_files {
+            self.raw_files = raw_files;
+            // the cached data of such files would be the other way
+            self.entries.clear();
+        }
+    }
+
+    /// Returns the image data for the file, converting it only if the file
+    /// is not in the cache yet or has been changed since it was cached.
+    ///
+    /// The file is converted with the cache unlocked, so loading other files
+    /// is not held up by it.
+    pub fn load(cache: &Mutex<ImageCache>, path: &Path) -> Result<ImageFile, ()> {
+        let metadata = fs::metadata(path).map_err(|err| {
+            log::error!("Cannot stat image file {:?}: {:?}", path, err);
+        })?;
+        let key = ImageCacheKey {
+            path: path.to_owned(),
+            mtime: metadata.modified().map_err(|_| ())?,
+            size: metadata.len(),
+        };
+
+        let raw_files = {
+            let mut cache = cache.lock().expect("Image cache is poisoned");
+            if let Some(image_file) = cache.get(&key) {
+                log::trace!("Image file {:?} is taken from the cache", path);
+                return Ok(image_file);
+            }
+            cache.raw_files
+        };
+
+        let raw = raw_files && key.size == IMAGE_DATA_SIZE as u64;
+        let data = match raw {
+            true => load_raw_image_file(path)?,
+            false => load_image_file(path)?,
+        };
+        let image_file = ImageFile {
+            data: data.into(),
+            raw,
+        };
+        let mut cache = cache.lock().expect("Image cache is poisoned");
+        // the setting may have been changed meanwhile, making the data the other way
+        if cache.raw_files == raw_files {
+            cache.insert(key, image_file.clone());
+        }
+        Ok(image_file)
+    }
+
+    fn get(&mut self, key: &ImageCacheKey) -> Option<ImageFile> {
+        // entries of the same path with an outdated mtime or size are stale
+        self.entries
+            .retain(|(cached_key, _)| cached_key.path != key.path || cached_key == key);
+
+        let position = self
+            .entries
+            .iter()
+            .position(|(cached_key, _)| cached_key == key)?;
+        let entry = self
+            .entries
+            .remove(position)
+            .expect("Position is out of range");
+        let data = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(data)
+    }
+
+    fn insert(&mut self, key: ImageCacheKey, image_file: ImageFile) {
+        // the file may have been loaded by another caller meanwhile
+        self.entries
+            .retain(|(cached_key, _)| cached_key.path != key.path);
+        if self.entries.len() >= IMAGE_CACHE_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, image_file));
+    }
+}
+
+/// Pool of full-screen image buffers, so composing images doesn't allocate on every frame
+pub struct ImageBufferPool {
+    capacity: usize,
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl ImageBufferPool {
+    /// The pool keeps at most `capacity` returned buffers, 0 disables it
+    pub fn new(capacity: usize) -> ImageBufferPool {
+        ImageBufferPool {
+            capacity,
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns a buffer of `IMAGE_DATA_SIZE` with unspecified contents,
+    /// allocating it only if the pool is empty
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers
+            .lock()
+            .expect("Image buffer pool is poisoned")
+            .pop()
+            .unwrap_or_else(|| vec![0_u8; IMAGE_DATA_SIZE])
+    }
+
+    /// Puts the buffer back to be reused, unless the pool is full
+    pub fn give(&self, buffer: Vec<u8>) {
+        if buffer.len() != IMAGE_DATA_SIZE {
+            return;
+        }
+        let mut buffers = self.buffers.lock().expect("Image buffer pool is poisoned");
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// Rectangle of an image, in pixels from the top left corner
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Size of the region data in the device format, or `None` if it doesn't fit `usize`
+    pub fn data_size(&self) -> Option<usize> {
+        (self.width as usize)
+            .checked_mul(self.height as usize)?
+            .checked_mul(3)
+    }
+}
+
+/// Copies data of the region (in the device format, so with bottom-up rows)
+/// into the image data at the region
+pub fn blit(image: &mut [u8], region: Region, data: &[u8]) -> Result<(), ()> {
+    let fits =
+        |start: u32, len: u32, max: u32| start.checked_add(len).is_some_and(|end| end <= max);
+    if image.len() != IMAGE_DATA_SIZE
+        || !fits(region.x, region.width, IMAGE_WIDTH)
+        || !fits(region.y, region.height, IMAGE_HEIGHT)
+        || region.data_size() != Some(data.len())
+    {
+        log::error!(
+            "Got invalid image region {:?} of size {:#x}",
+            region,
+            data.len()
+        );
+        return Err(());
+    }
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let row_len = (region.width * 3) as usize;
+    data.chunks_exact(row_len)
+        .enumerate()
+        .for_each(|(region_row, row)| {
+            // both the region and the image rows are bottom-up,
+            // and the region fits the image, so none of this overflows
+            let image_row = IMAGE_HEIGHT - region.y - region.height + region_row as u32;
+            let start = ((image_row * IMAGE_WIDTH + region.x) * 3) as usize;
+            image[start..start + row_len].copy_from_slice(row);
+        });
+    Ok(())
+}
+
+/// Draws the image data of a slot over the image, its black pixels letting the image show through
+pub fn draw_over(image: &mut [u8], data: &[u8]) {
+    image
+        .chunks_exact_mut(3)
+        .zip(data.chunks_exact(3))
+        .filter(|(_, pixel)| *pixel != [0, 0, 0])
+        .for_each(|(image_pixel, pixel)| image_pixel.copy_from_slice(pixel));
+}
+
+/// Creates image data of a single color in the device format
+pub fn solid_color(r: u8, g: u8, b: u8) -> Vec<u8> {
+    [b, g, r].repeat((IMAGE_WIDTH * IMAGE_HEIGHT) as usize)
+}
+
+/// Reads a file of raw image data in the device format
+pub fn load_raw_image_file(path: &Path) -> Result<Vec<u8>, ()> {
+    let data = fs::read(path).map_err(|err| {
+        log::error!("Cannot read image file {:?}: {:?}", path, err);
+    })?;
+    if data.len() != IMAGE_DATA_SIZE {
+        log::error!(
+            "Got raw image file {:?} of invalid size ({:#x})",
+            path,
+            data.len()
+        );
+        return Err(());
+    }
+    log::debug!("Image file {:?} is taken as raw image data", path);
+    Ok(data)
+}
+
+/// Decodes an image file (BMP or JPEG) into the device format:
+/// 320x240, 24 bpp BGR, bottom-up rows.
+pub fn load_image_file(path: &Path) -> Result<Vec<u8>, ()> {
+    let image = image::open(path).map_err(|err| {
+        log::error!("Cannot decode image file {:?}: {:?}", path, err);
+    })?;
+    let image = if image.width() != IMAGE_WIDTH || image.height() != IMAGE_HEIGHT {
+        image.resize_exact(
+            IMAGE_WIDTH,
+            IMAGE_HEIGHT,
+            image::imageops::FilterType::Triangle,
+        )
+    } else {
+        image
+    };
+    let image = image.to_rgb8();
+
+    let mut data = Vec::with_capacity(IMAGE_DATA_SIZE);
+    image.rows().rev().flatten().for_each(|pixel| {
+        let [r, g, b] = pixel.0;
+        data.extend_from_slice(&[b, g, r]);
+    });
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray_image(value: u8) -> Vec<u8> {
+        vec![value; IMAGE_DATA_SIZE]
+    }
+
+    fn lut(gamma: f32, contrast: f32) -> [u8; 256] {
+        ImageAdjustment::new(gamma, contrast)
+            .lut
+            .expect("Adjustment is the identity")
+    }
+
+    #[test]
+    fn adjustment_identity() {
+        let adjustment = ImageAdjustment::new(1.0, 1.0);
+        assert!(adjustment.is_identity());
+        let mut data: Vec<u8> = (0..=255).collect();
+        adjustment.apply(&mut data);
+        assert!(data.iter().copied().eq(0..=255));
+    }
+
+    #[test]
+    fn adjustment_gamma() {
+        let brighter = lut(2.2, 1.0);
+        assert_eq!(
+            (brighter[0], brighter[64], brighter[128], brighter[255]),
+            (0, 136, 186, 255)
+        );
+        let darker = lut(0.5, 1.0);
+        assert_eq!(
+            (darker[0], darker[64], darker[128], darker[255]),
+            (0, 16, 64, 255)
+        );
+        assert!(brighter.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(darker.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn adjustment_contrast() {
+        // the values far from the middle are clamped
+        let higher = lut(1.0, 1.5);
+        assert_eq!(
+            (higher[0], higher[64], higher[128], higher[255]),
+            (0, 32, 128, 255)
+        );
+        let lower = lut(1.0, 0.5);
+        assert_eq!((lower[0], lower[128], lower[255]), (64, 128, 191));
+    }
+
+    #[test]
+    fn dither_keeps_black_white_and_levels() {
+        for bits in 1..8 {
+            for value in [0, 255] {
+                let mut data = gray_image(value);
+                dither(&mut data, bits);
+                assert!(data.iter().all(|dithered| *dithered == value), "{}", bits);
+            }
+        }
+        // 85 is a level of 2 bits per color channel
+        let mut data = gray_image(85);
+        dither(&mut data, 2);
+        assert!(data.iter().all(|dithered| *dithered == 85));
+    }
+
+    #[test]
+    fn dither_quantizes_to_levels() {
+        let mut data: Vec<u8> = (0..IMAGE_DATA_SIZE).map(|i| (i % 256) as u8).collect();
+        dither(&mut data, 2);
+        assert!(data.iter().all(|value| [0, 85, 170, 255].contains(value)));
+    }
+
+    #[test]
+    fn dither_mid_gray() {
+        let mut data = gray_image(128);
+        dither(&mut data, 1);
+        // half of the pixels of every 4x4 block are lit, the same ones in every block
+        let pixel = |x: usize, y: usize| data[(y * IMAGE_WIDTH as usize + x) * 3];
+        let block = |x0: usize, y0: usize| -> Vec<u8> {
+            (0..16).map(|i| pixel(x0 + i % 4, y0 + i / 4)).collect()
+        };
+        let first_block = block(0, 0);
+        assert_eq!(first_block.iter().filter(|value| **value == 255).count(), 8);
+        assert!(first_block.iter().all(|value| [0, 255].contains(value)));
+        assert_eq!(block(4, 0), first_block);
+        assert_eq!(block(316, 236), first_block);
+        // the color channels of a pixel are dithered the same
+        assert!(data
+            .chunks_exact(3)
+            .all(|color| color[0] == color[1] && color[1] == color[2]));
+    }
+
+    #[test]
+    fn region_data_size_overflow() {
+        let region = |width, height| Region {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        assert_eq!(region(2, 3).data_size(), Some(18));
+        assert_eq!(region(u32::MAX, u32::MAX).data_size(), None);
+    }
+
+    #[test]
+    fn blit_rejects_regions_out_of_image() {
+        let mut image = gray_image(0);
+        let region = |x, y, width, height| Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert!(blit(&mut image, region(u32::MAX, 0, 1, 1), &[255; 3]).is_err());
+        assert!(blit(&mut image, region(0, u32::MAX, 1, 1), &[255; 3]).is_err());
+        assert!(blit(&mut image, region(1, 0, u32::MAX, 1), &[]).is_err());
+        assert!(blit(&mut image, region(0, 1, 1, u32::MAX), &[]).is_err());
+        assert!(blit(&mut image, region(0, 0, u32::MAX, u32::MAX), &[]).is_err());
+        assert!(blit(&mut image, region(IMAGE_WIDTH, 0, 1, 1), &[255; 3]).is_err());
+        assert!(blit(&mut image, region(0, 0, 1, 1), &[255; 6]).is_err());
+        assert!(image.iter().all(|value| *value == 0));
+
+        // the rows are bottom-up, so the bottom right pixel ends the first row
+        assert!(blit(
+            &mut image,
+            region(IMAGE_WIDTH - 1, IMAGE_HEIGHT - 1, 1, 1),
+            &[255; 3]
+        )
+        .is_ok());
+        let end = IMAGE_WIDTH as usize * 3;
+        assert_eq!(&image[end - 3..end], &[255; 3]);
+        assert_eq!(image.iter().filter(|value| **value != 0).count(), 3);
+    }
+
+    #[test]
+    fn image_cache_reloads_changed_files() {
+        let dir = std::env::temp_dir().join(format!("libfip-image-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Cannot create the image directory");
+        let path = dir.join("image.raw");
+        fs::write(&path, gray_image(1)).expect("Cannot write the image file");
+        let cache = Mutex::new(ImageCache::new());
+
+        let first = ImageCache::load(&cache, &path).expect("Cannot load the image file");
+        assert!(first.raw);
+        assert_eq!(first.data[..], gray_image(1)[..]);
+        let cached = ImageCache::load(&cache, &path).expect("Cannot load the image file");
+        assert!(Arc::ptr_eq(&first.data, &cached.data));
+
+        // the same size, so only the mtime tells the file has been changed
+        fs::write(&path, gray_image(2)).expect("Cannot write the image file");
+        let mtime = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .expect("Cannot stat the image file");
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(mtime + std::time::Duration::from_secs(10)))
+            .expect("Cannot touch the image file");
+        let changed = ImageCache::load(&cache, &path).expect("Cannot load the image file");
+        assert_eq!(changed.data[..], gray_image(2)[..]);
+        assert_eq!(cache.lock().unwrap().entries.len(), 1);
+
+        fs::remove_dir_all(&dir).expect("Cannot remove the image directory");
+    }
+
+    #[test]
+    fn image_cache_evicts_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("libfip-image-lru-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Cannot create the image directory");
+        let paths: Vec<PathBuf> = (0..=IMAGE_CACHE_CAPACITY)
+            .map(|i| {
+                let path = dir.join(format!("{}.raw", i));
+                fs::write(&path, gray_image(i as u8)).expect("Cannot write the image file");
+                path
+            })
+            .collect();
+        let cache = Mutex::new(ImageCache::new());
+        let load =
+            |path: &PathBuf| ImageCache::load(&cache, path).expect("Cannot load the image file");
+        let cached = |path: &PathBuf| {
+            let cache = cache.lock().unwrap();
+            cache.entries.iter().any(|(key, _)| key.path == *path)
+        };
+
+        paths[..IMAGE_CACHE_CAPACITY].iter().for_each(|path| {
+            load(path);
+        });
+        // the first file is used again, so the second one is the least recently used
+        load(&paths[0]);
+        load(&paths[IMAGE_CACHE_CAPACITY]);
+        assert_eq!(cache.lock().unwrap().entries.len(), IMAGE_CACHE_CAPACITY);
+        assert!(cached(&paths[0]));
+        assert!(!cached(&paths[1]));
+        assert!(paths[2..].iter().all(cached));
+
+        fs::remove_dir_all(&dir).expect("Cannot remove the image directory");
+    }
+}
@@ -1,19 +1,20 @@
-#![feature(abi_vectorcall)]
-#![feature(let_chains)]
-
 use core::slice;
 use std::{
     fs,
     io::BufReader,
+    path::Path,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 extern crate pretty_env_logger;
 
 mod devices;
+mod diagnostics;
+mod images;
+mod logging;
 
 type PrgCtx = usize;
-type DevicePtr = u64;
 
 #[allow(clippy::upper_case_acronyms)]
 type DWORD = i32;
@@ -27,6 +28,14 @@ type Pfn_DirectOutput_EnumerateCallback =
 type Pfn_DirectOutput_DeviceChange =
     unsafe extern "stdcall" fn(device_ptr: DevicePtr, is_added: bool, prg_ctx: PrgCtx);
 #[allow(non_camel_case_types)]
+type Pfn_DirectOutput_DeviceReconnect =
+    unsafe extern "stdcall" fn(device_ptr: DevicePtr, prg_ctx: PrgCtx);
+#[allow(non_camel_case_types)]
+type Pfn_DirectOutput_DeviceCountChange = unsafe extern "stdcall" fn(count: DWORD, prg_ctx: PrgCtx);
+#[allow(non_camel_case_types)]
+type Pfn_DirectOutput_Log =
+    unsafe extern "stdcall" fn(level: DWORD, message: *const libc::wchar_t, prg_ctx: PrgCtx);
+#[allow(non_camel_case_types)]
 type Pfn_DirectOutput_PageChange = unsafe extern "stdcall" fn(
     device_ptr: DevicePtr,
     page: DWORD,
@@ -42,6 +51,10 @@ pub const E_HANDLE: HRESULT = 0x80070006;
 pub const E_INVALIDARG: HRESULT = 0x80070057;
 pub const E_OUTOFMEMORY: HRESULT = 0x80007000e;
 pub const E_NOTIMPL: HRESULT = 0x80004001;
+pub const E_FAIL: HRESULT = 0x80004005;
+pub const E_TIMEOUT: HRESULT = 0x800705b4; // HRESULT_FROM_WIN32(ERROR_TIMEOUT)
+pub const E_BUSY: HRESULT = 0x800700aa; // HRESULT_FROM_WIN32(ERROR_BUSY)
+pub const E_DEVICENOTREADY: HRESULT = 0x80070015; // HRESULT_FROM_WIN32(ERROR_NOT_READY)
 // library errors
 pub const E_BUFFERTOOSMALL: HRESULT = 0xff04006f;
 pub const E_PAGENOTACTIVE: HRESULT = 0xff040001;
@@ -62,6 +75,16 @@ pub struct SRequestStatus {
     pub dwRequestInfo: DWORD,
 }
 
+#[repr(C)]
+pub struct STransferStats {
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    pub transfers: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+    pub average_latency_us: u64,
+}
+
 #[cfg(target_arch = "x86")]
 macro_rules! directoutputlib_export {
     ($($toks: tt)+) => {
@@ -79,24 +102,189 @@ macro_rules! directoutputlib_export {
     };
 }
 
+/// How long `DirectOutput_Flush` waits for all devices to become idle
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exports that are implemented, as listed by `DirectOutput_GetExportList`;
+/// the ones only returning `E_NOTIMPL` are left out, and new ones have to be added here
+const IMPLEMENTED_EXPORTS: &[&str] = &[
+    "DirectOutput_Initialize",
+    "DirectOutput_InitializeEx",
+    "DirectOutput_GetLibraryVersion",
+    "DirectOutput_GetExportList",
+    "DirectOutput_SetLogCallback",
+    "DirectOutput_Deinitialize",
+    "DirectOutput_RegisterDeviceCallback",
+    "DirectOutput_RegisterReconnectCallback",
+    "DirectOutput_RegisterDeviceCountCallback",
+    "DirectOutput_Rescan",
+    "DirectOutput_Enumerate",
+    "DirectOutput_GetDeviceCount",
+    "DirectOutput_GetDeviceByIndex",
+    "DirectOutput_EnumerateToBuffer",
+    "DirectOutput_RegisterPageCallback",
+    "DirectOutput_RegisterSoftButtonCallback",
+    "DirectOutput_IsFactoryMode",
+    "DirectOutput_GetDeviceType",
+    "DirectOutput_GetCapabilities",
+    "DirectOutput_ReadButtonsNow",
+    "DirectOutput_WaitForButtons",
+    "DirectOutput_AddPage",
+    "DirectOutput_AddPageWithImage",
+    "DirectOutput_RemovePage",
+    "DirectOutput_GetPageList",
+    "DirectOutput_GetLedIndices",
+    "DirectOutput_SetLed",
+    "DirectOutput_AllLedsOff",
+    "DirectOutput_SetLedLevel",
+    "DirectOutput_ClearImageSlot",
+    "DirectOutput_ResetDevice",
+    "DirectOutput_SetString",
+    "DirectOutput_SetImage",
+    "DirectOutput_SetImageRegion",
+    "DirectOutput_BeginFrame",
+    "DirectOutput_CommitFrame",
+    "DirectOutput_BeginBatch",
+    "DirectOutput_EndBatch",
+    "DirectOutput_FillColor",
+    "DirectOutput_GetImage",
+    "DirectOutput_GetPageImage",
+    "DirectOutput_SetImageFromFile",
+    "DirectOutput_StartServer",
+    "DirectOutput_SaveFile",
+    "DirectOutput_SaveFileEx",
+    "DirectOutput_DisplayFile",
+    "DirectOutput_DeleteFile",
+    "DirectOutput_GetLastRequestStatus",
+    "DirectOutput_GetTransferStats",
+    "DirectOutput_GetSerialNumber",
+    "DirectOutput_DumpState",
+    "DirectOutput_Flush",
+];
+
 static STATE: Mutex<Option<devices::State>> = Mutex::new(None);
+static IMAGE_CACHE: Mutex<images::ImageCache> = Mutex::new(images::ImageCache::new());
 
 directoutputlib_export! {
     fn DirectOutput_Initialize(app_name: *const libc::wchar_t) -> HRESULT {
-        pretty_env_logger::init();
+        // the logger is already set up if the library is initialized again
+        logging::init();
         log::trace!("DirectOutput_Initialize");
+        let app_name = if app_name.is_null() {
+            None
+        } else {
+            unsafe { widestring::WideCStr::from_ptr_str(app_name.cast()) }.to_string().ok()
+        };
         let mut state = STATE.lock().expect("State is poisoned");
         if state.is_none() {
-            state.replace(devices::init().expect("Cannot perform library initialization"));
+            IMAGE_CACHE.lock().expect("Image cache is poisoned").set_raw_files(raw_image_files_from_env());
+            let options = match app_name {
+                Some(ref app_name) => options_from_env().app_name(app_name.clone()),
+                None => options_from_env(),
+            };
+            let new_state = state.insert(devices::init(options).expect("Cannot perform library initialization"));
+            // the devices connected by now have been discovered during the initialization
+            new_state.wait_for_all_ready();
         }
         //sleep(Duration::from_secs(1));
 
-        if !app_name.is_null() && log::log_enabled!(log::Level::Info) {
-            match unsafe { widestring::WideCStr::from_ptr_str(app_name.cast()) }.to_string() {
-                Ok(app_name_str) => log::info!("App initialized ({:?})", app_name_str),
-                Err(_) => log::info!("App initialized"),
-            };
+        match app_name {
+            Some(app_name) => log::info!("App initialized ({:?})", app_name),
+            None => log::info!("App initialized"),
+        };
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Devices are initialized in the background, so only the ones that are ready
+    // by now are counted (see LIBFIP_WAIT_FOR_READY_MS)
+    fn DirectOutput_InitializeEx(app_name: *const libc::wchar_t, res_count: *mut DWORD) -> HRESULT {
+        if res_count.is_null() {
+            return E_INVALIDARG;
+        }
+        let result = unsafe { DirectOutput_Initialize(app_name) };
+        if result != S_OK {
+            return result;
+        }
+
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+        let Ok(count) = state.display_addrs().len().try_into() else { return E_OUTOFMEMORY };
+        unsafe { *res_count = count };
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Not a part of the SDK; unlike the other functions, may be called before the initialization
+    fn DirectOutput_GetLibraryVersion(res_major: *mut DWORD, res_minor: *mut DWORD, res_patch: *mut DWORD) -> HRESULT {
+        if res_major.is_null() || res_minor.is_null() || res_patch.is_null() {
+            return E_INVALIDARG;
+        }
+        let version_part = |part: &str| part.parse().expect("Crate version is not numeric");
+        unsafe {
+            *res_major = version_part(env!("CARGO_PKG_VERSION_MAJOR"));
+            *res_minor = version_part(env!("CARGO_PKG_VERSION_MINOR"));
+            *res_patch = version_part(env!("CARGO_PKG_VERSION_PATCH"));
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Not a part of the SDK; may be called before the initialization too.
+    // Writes the names of the implemented exports, separated by newlines, as a wide string
+    // of `*res_size` characters (including the terminating null), setting it to the needed size.
+    fn DirectOutput_GetExportList(res_buffer: *mut libc::wchar_t, res_size: *mut DWORD) -> HRESULT {
+        if res_size.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(capacity): Result<usize, _> = unsafe { *res_size }.try_into() else {
+            return E_INVALIDARG;
+        };
+        let list = widestring::WideCString::from_str(IMPLEMENTED_EXPORTS.join("\n")).expect("Export names contain nulls");
+        let list = list.as_slice_with_nul();
+        unsafe { *res_size = list.len() as DWORD };
+        if list.len() > capacity {
+            return E_BUFFERTOOSMALL;
+        }
+        if res_buffer.is_null() {
+            return E_INVALIDARG;
         }
+        unsafe { slice::from_raw_parts_mut(res_buffer.cast(), list.len()) }.copy_from_slice(list);
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Not a part of the SDK; may be called before the initialization too.
+    // Sends the log messages up to `max_level` (1 for errors to 5 for traces, 0 for none)
+    // to the callback instead of the standard error, until the library is deinitialized
+    // or the callback is set again; null callback brings the standard error back.
+    // The callback may be called from any thread, with library locks held,
+    // so it must not call library functions.
+    fn DirectOutput_SetLogCallback(callback: Option<Pfn_DirectOutput_Log>, prg_ctx: PrgCtx, max_level: DWORD) -> HRESULT {
+        let Some(callback) = callback else {
+            logging::set_default_sink();
+            return S_OK;
+        };
+        let max_level = match max_level {
+            0 => log::LevelFilter::Off,
+            1 => log::LevelFilter::Error,
+            2 => log::LevelFilter::Warn,
+            3 => log::LevelFilter::Info,
+            4 => log::LevelFilter::Debug,
+            5 => log::LevelFilter::Trace,
+            _ => return E_INVALIDARG,
+        };
+        logging::set_sink(Box::new(LogCallback { callback, prg_ctx }), max_level);
 
         S_OK
     }
@@ -106,16 +294,29 @@ directoutputlib_export! {
     fn DirectOutput_Deinitialize() -> HRESULT {
         log::trace!("DirectOutput_Deinitialize");
 
-        let mut state = STATE.lock().expect("State is poisoned");
-        if state.is_some() {
-            drop(state.take());
-            log::trace!("App deinitialized, state dropped");
+        // taken out, so the library is not locked while the displays are written to
+        let state = STATE.lock().expect("State is poisoned").take();
+        if let Some(state) = state {
+            // the button backlights are not to stay lit once the host is gone
+            state
+                .displays()
+                .iter()
+                .filter(|(_, display)| display.ready())
+                .for_each(|(_, display)| _ = display.release_leds());
+            let app_name = state.app_name().map(str::to_owned);
+            drop(state);
+            log::trace!("App deinitialized ({:?}), state dropped", app_name);
         }
+        // the host may unload its log callback once the library is deinitialized
+        logging::set_default_sink();
 
         S_OK
     }
 }
 
+// Callbacks are never called with the library state locked,
+// so they may safely call any library function.
+
 struct HotplugHandler {
     callback: Pfn_DirectOutput_DeviceChange,
     prg_ctx: PrgCtx,
@@ -123,7 +324,7 @@ struct HotplugHandler {
 
 impl devices::Hotplug for HotplugHandler {
     fn display_arrived(&mut self, addr: devices::UsbDeviceAddress) {
-        let device_ptr = embed_addr(addr);
+        let device_ptr = DevicePtr::from_addr(addr);
         log::trace!(
             "Calling device change callback: {:p}({:#}, {:?})",
             self.callback,
@@ -143,7 +344,7 @@ impl devices::Hotplug for HotplugHandler {
     }
 
     fn display_left(&mut self, addr: devices::UsbDeviceAddress) {
-        let device_ptr = embed_addr(addr);
+        let device_ptr = DevicePtr::from_addr(addr);
         log::trace!(
             "Calling device change callback: {:p}({:#}, {:?})",
             self.callback,
@@ -163,7 +364,127 @@ impl devices::Hotplug for HotplugHandler {
     }
 }
 
+struct ReconnectHandler {
+    callback: Pfn_DirectOutput_DeviceReconnect,
+    prg_ctx: PrgCtx,
+}
+
+impl devices::Hotplug for ReconnectHandler {
+    fn display_arrived(&mut self, _addr: devices::UsbDeviceAddress) {}
+
+    fn display_left(&mut self, _addr: devices::UsbDeviceAddress) {}
+
+    fn display_reconnected(&mut self, addr: devices::UsbDeviceAddress) {
+        let device_ptr = DevicePtr::from_addr(addr);
+        log::trace!(
+            "Calling device reconnect callback: {:p}({:#}, {:?})",
+            self.callback,
+            device_ptr,
+            self.prg_ctx
+        );
+        let callback = self.callback;
+        unsafe {
+            callback(device_ptr, self.prg_ctx);
+        }
+        log::trace!(
+            "Called device reconnect callback: {:p}({:#}, {:?})",
+            self.callback,
+            device_ptr,
+            self.prg_ctx
+        );
+    }
+}
+
+struct DeviceCountHandler {
+    callback: Pfn_DirectOutput_DeviceCountChange,
+    prg_ctx: PrgCtx,
+}
+
+impl devices::Hotplug for DeviceCountHandler {
+    fn display_arrived(&mut self, _addr: devices::UsbDeviceAddress) {}
+
+    fn display_left(&mut self, _addr: devices::UsbDeviceAddress) {}
+
+    fn display_count_changed(&mut self, count: usize) {
+        let count = count as DWORD;
+        log::trace!(
+            "Calling device count change callback: {:p}({}, {:?})",
+            self.callback,
+            count,
+            self.prg_ctx
+        );
+        let callback = self.callback;
+        unsafe {
+            callback(count, self.prg_ctx);
+        }
+        log::trace!(
+            "Called device count change callback: {:p}({}, {:?})",
+            self.callback,
+            count,
+            self.prg_ctx
+        );
+    }
+}
+
+struct LogCallback {
+    callback: Pfn_DirectOutput_Log,
+    prg_ctx: PrgCtx,
+}
+
+impl log::Log for LogCallback {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true // filtered by the max level
+    }
+
+    fn log(&self, record: &log::Record) {
+        let message = format!("{}: {}", record.target(), record.args());
+        let message = widestring::WideCString::from_str_truncate(message);
+        let callback = self.callback;
+        unsafe {
+            callback(
+                record.level() as DWORD,
+                message.as_ptr().cast(),
+                self.prg_ctx,
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+struct SoftButtonHandler {
+    device_ptr: DevicePtr,
+    callback: Pfn_DirectOutput_SoftButtonChange,
+    prg_ctx: PrgCtx,
+}
+
+impl devices::SoftButtonsHandler for SoftButtonHandler {
+    fn soft_buttons_changed(&mut self, buttons: devices::SoftButtons) {
+        let buttons_state = buttons.bits() as DWORD;
+        log::trace!(
+            "Calling soft button change callback: {:p}({:#}, {:#x}, {:?})",
+            self.callback,
+            self.device_ptr,
+            buttons_state,
+            self.prg_ctx
+        );
+        let callback = self.callback;
+        unsafe {
+            callback(self.device_ptr, buttons_state, self.prg_ctx);
+        }
+        log::trace!(
+            "Called soft button change callback: {:p}({:#}, {:#x}, {:?})",
+            self.callback,
+            self.device_ptr,
+            buttons_state,
+            self.prg_ctx
+        );
+    }
+}
+
 directoutputlib_export! {
+    // A device that leaves and one arriving at the same USB port soon after (e.g. on a hub reset)
+    // are told about one right after the other, see LIBFIP_HOTPLUG_DEBOUNCE_MS
     fn DirectOutput_RegisterDeviceCallback(callback: Pfn_DirectOutput_DeviceChange, prg_ctx: PrgCtx) -> HRESULT {
         // TODO
         log::trace!("DirectOutput_RegisterDeviceCallback {:p}(..., {:?})", callback, prg_ctx);
@@ -177,91 +498,182 @@ directoutputlib_export! {
 }
 
 directoutputlib_export! {
-    fn DirectOutput_Enumerate(callback: Pfn_DirectOutput_EnumerateCallback, prg_ctx: PrgCtx) -> HRESULT {
-        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+    // Called (after the device change one) when a device is connected to the port
+    // where one has been connected before, e.g. after a dropout; device handle is a new one
+    fn DirectOutput_RegisterReconnectCallback(callback: Pfn_DirectOutput_DeviceReconnect, prg_ctx: PrgCtx) -> HRESULT {
+        log::trace!("DirectOutput_RegisterReconnectCallback {:p}(..., {:?})", callback, prg_ctx);
+        let Some(ref mut state) = *STATE.lock().expect("State is poisoned") else {
             log::error!("Library function has been called, but the library is not initialized");
             return E_HANDLE;
         };
-
-        state.display_addrs().iter().for_each(move |addr| {
-            let device_ptr = embed_addr(*addr);
-            log::trace!("Calling enumerate callback: {:p}({:#}, {:?})", callback, device_ptr, prg_ctx);
-            unsafe { callback(device_ptr, prg_ctx); }
-            log::trace!("Called enumerate callback {:p}({:#}, {:?})", callback, device_ptr, prg_ctx);
-        });
-
+        state.add_hotplug_handler(Box::new(ReconnectHandler{callback, prg_ctx}));
         S_OK
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_RegisterPageCallback(device_ptr: DevicePtr, callback: Pfn_DirectOutput_PageChange, prg_ctx: PrgCtx) -> HRESULT {
-        // TODO
+    // Not a part of the SDK; called with the number of connected devices once it has
+    // stopped changing (see LIBFIP_HOTPLUG_DEBOUNCE_MS), after the device change callbacks
+    fn DirectOutput_RegisterDeviceCountCallback(callback: Pfn_DirectOutput_DeviceCountChange, prg_ctx: PrgCtx) -> HRESULT {
+        log::trace!("DirectOutput_RegisterDeviceCountCallback {:p}(..., {:?})", callback, prg_ctx);
+        let Some(ref mut state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+        state.add_hotplug_handler(Box::new(DeviceCountHandler{callback, prg_ctx}));
         S_OK
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_RegisterSoftButtonCallback(device_ptr: DevicePtr, callback: Pfn_DirectOutput_SoftButtonChange, prg_ctx: PrgCtx) -> HRESULT {
-        // TODO
+    // Not a part of the SDK; looks for devices connected or disconnected without a hotplug event
+    // (e.g. where libusb has no hotplug support), calling the device change callbacks for them
+    fn DirectOutput_Rescan() -> HRESULT {
+        log::trace!("DirectOutput_Rescan");
+        let usb_scanner = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+            state.usb_scanner()
+        };
+
+        match usb_scanner.rescan() {
+            Ok(()) => S_OK,
+            Err(()) => E_FAIL,
+        }
+    }
+}
+
+directoutputlib_export! {
+    // Devices are enumerated in the same order as by DirectOutput_GetDeviceByIndex
+    fn DirectOutput_Enumerate(callback: Pfn_DirectOutput_EnumerateCallback, prg_ctx: PrgCtx) -> HRESULT {
+        let display_addrs = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+            state.display_addrs()
+        };
+
+        display_addrs.iter().for_each(move |addr| {
+            let device_ptr = DevicePtr::from_addr(*addr);
+            log::trace!("Calling enumerate callback: {:p}({:#}, {:?})", callback, device_ptr, prg_ctx);
+            unsafe { callback(device_ptr, prg_ctx); }
+            log::trace!("Called enumerate callback {:p}({:#}, {:?})", callback, device_ptr, prg_ctx);
+        });
+
         S_OK
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_GetDeviceType(device_ptr: DevicePtr, guid: *mut GUID) -> HRESULT {
+    fn DirectOutput_GetDeviceCount(res_count: *mut DWORD) -> HRESULT {
         let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
             log::error!("Library function has been called, but the library is not initialized");
             return E_HANDLE;
         };
 
-        let display = match get_display(state, device_ptr) {
-            Ok(display) => display,
-            Err(err) => return err,
-        };
-
-        let uuid = display.device_type_uuid();
-        let mut guid = unsafe {&mut *guid };
-
-        let fields = uuid.as_fields();
-        (guid.data1, guid.data2, guid.data3, _) = fields;
-        guid.data4.copy_from_slice(fields.3);
-        log::error!("{:?}", guid);
+        if res_count.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(count) = state.display_addrs().len().try_into() else { return E_OUTOFMEMORY };
+        unsafe { *res_count = count };
 
         S_OK
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_GetDeviceInstance(device_ptr: DevicePtr, guid: *mut GUID) -> HRESULT {
-        // TODO?? (DirectInput)
-        E_NOTIMPL
-    }
-}
+    // Indices are ordered by device serial numbers and stay valid only until the next hotplug event
+    fn DirectOutput_GetDeviceByIndex(index: DWORD, res_device_ptr: *mut DevicePtr) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
 
-directoutputlib_export! {
-    fn DirectOutput_SetProfile(device_ptr: DevicePtr, debug_profile_name_size: usize, debug_profile_name: *mut libc::wchar_t) -> HRESULT {
-        // TODO?? (talks to the driver)
-        E_NOTIMPL
+        if res_device_ptr.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(index): Result<usize, _> = index.try_into() else { return E_INVALIDARG };
+        let Some(addr) = state.display_addrs().get(index).copied() else {
+            return E_INVALIDARG;
+        };
+        unsafe { *res_device_ptr = DevicePtr::from_addr(addr) };
+
+        S_OK
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_AddPage(device_ptr: DevicePtr, page_number: DWORD, debug_name: *const libc::wchar_t, page_flags: DWORD) -> HRESULT {
-        // TODO
+    // Same as DirectOutput_Enumerate, but fills a caller's array of `*res_count` elements
+    // (for bindings where callbacks are awkward); `*res_count` is set to the number of devices
+    // even if they don't fit, so the caller can grow the array
+    fn DirectOutput_EnumerateToBuffer(res_device_ptrs: *mut DevicePtr, res_count: *mut DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        if res_count.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(capacity): Result<usize, _> = unsafe { *res_count }.try_into() else { return E_INVALIDARG };
+        let display_addrs = state.display_addrs();
+        let Ok(count) = display_addrs.len().try_into() else { return E_OUTOFMEMORY };
+        unsafe { *res_count = count };
+        if display_addrs.len() > capacity {
+            return E_BUFFERTOOSMALL;
+        }
+        if !display_addrs.is_empty() {
+            if res_device_ptrs.is_null() {
+                return E_INVALIDARG;
+            }
+            let res_device_ptrs = unsafe { slice::from_raw_parts_mut(res_device_ptrs, display_addrs.len()) };
+            res_device_ptrs
+                .iter_mut()
+                .zip(display_addrs)
+                .for_each(|(res_device_ptr, addr)| *res_device_ptr = DevicePtr::from_addr(addr));
+        }
+
         S_OK
     }
 }
 
-directoutputlib_export! {
-    fn DirectOutput_RemovePage(device_ptr: DevicePtr, page_number: DWORD) -> HRESULT {
-        // TODO
-        S_OK
+struct PageChangeHandler {
+    device_ptr: DevicePtr,
+    callback: Pfn_DirectOutput_PageChange,
+    prg_ctx: PrgCtx,
+}
+
+impl devices::PageChangeHandler for PageChangeHandler {
+    fn page_changed(&mut self, page: u8, is_active: bool) {
+        log::trace!(
+            "Calling page change callback: {:p}({:#}, {}, {}, {:?})",
+            self.callback,
+            self.device_ptr,
+            page,
+            is_active,
+            self.prg_ctx
+        );
+        let callback = self.callback;
+        unsafe {
+            callback(self.device_ptr, page.into(), is_active, self.prg_ctx);
+        }
+        log::trace!(
+            "Called page change callback: {:p}({:#}, {}, {}, {:?})",
+            self.callback,
+            self.device_ptr,
+            page,
+            is_active,
+            self.prg_ctx
+        );
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_SetLed(device_ptr: DevicePtr, page_number: DWORD, led_index: DWORD, led_value: DWORD) -> HRESULT {
+    fn DirectOutput_RegisterPageCallback(device_ptr: DevicePtr, callback: Pfn_DirectOutput_PageChange, prg_ctx: PrgCtx) -> HRESULT {
+        log::trace!("DirectOutput_RegisterPageCallback {:p}(..., {:?})", callback, prg_ctx);
         let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
             log::error!("Library function has been called, but the library is not initialized");
             return E_HANDLE;
@@ -272,88 +684,833 @@ directoutputlib_export! {
             Err(err) => return err,
         };
 
-        let Ok(page) = page_number.try_into() else { return E_INVALIDARG; };
-        let Ok(led_index) = led_index.try_into() else { return E_INVALIDARG; };
-        let led_value = match led_value {
-            0 => false,
-            1 => true,
-            _ => return E_INVALIDARG,
-        };
-        _ = display.set_led(page, led_index, led_value);
-        // TODO: error handling
-
+        display.set_page_change_handler(Box::new(PageChangeHandler{device_ptr, callback, prg_ctx}));
         S_OK
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_SetString(device_ptr: DevicePtr, page_number: DWORD, string_index: DWORD, string_size: DWORD, string: *const libc::wchar_t) -> HRESULT {
-        // TODO? (seemingly not implemented in FIP)
-        E_NOTIMPL
+    fn DirectOutput_RegisterSoftButtonCallback(device_ptr: DevicePtr, callback: Pfn_DirectOutput_SoftButtonChange, prg_ctx: PrgCtx) -> HRESULT {
+        log::trace!("DirectOutput_RegisterSoftButtonCallback {:p}(..., {:?})", callback, prg_ctx);
+        let display = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+            match get_display(state, device_ptr) {
+                Ok(display) => display,
+                Err(err) => return err,
+            }
+        };
+
+        // the callback is called right away with the current buttons state, if it is known
+        display.set_soft_buttons_handler(Box::new(SoftButtonHandler{device_ptr, callback, prg_ctx}));
+        S_OK
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_SetImage(device_ptr: DevicePtr, page_number: DWORD, image_index: DWORD, image_size: DWORD, image: *const u8) -> HRESULT {
+    // Devices in factory mode never become ready, so this works for devices that are not ready too
+    fn DirectOutput_IsFactoryMode(device_ptr: DevicePtr, res_is_factory_mode: *mut DWORD) -> HRESULT {
         let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
             log::error!("Library function has been called, but the library is not initialized");
             return E_HANDLE;
         };
 
-        let display = match get_display(state, device_ptr) {
+        let display = match get_any_display(state, device_ptr) {
             Ok(display) => display,
             Err(err) => return err,
         };
 
-        if image.is_null() {
+        if res_is_factory_mode.is_null() {
             return E_INVALIDARG;
         }
-        if image_size != 0x38400 {  // TODO
-            return E_BUFFERTOOSMALL;
-        }
-        {
-            let image_data = unsafe { slice::from_raw_parts(image, 0x38400) };
-            let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
-            _ = display.set_image_data(page, arrayref::array_ref![image_data, 0, 0x38400]);
-            // TODO: error handling
-        }
+        unsafe { *res_is_factory_mode = display.is_factory_mode().into() };
 
         S_OK
     }
 }
 
 directoutputlib_export! {
-    fn DirectOutput_SetImageFromFile(device_ptr: DevicePtr, page_number: DWORD, image_index: DWORD, filename_size: DWORD, filename: *const libc::wchar_t) -> HRESULT {
-        // TODO
-        todo!()
+    fn DirectOutput_GetDeviceType(device_ptr: DevicePtr, guid: *mut GUID) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let uuid = display.device_type_uuid();
+        let mut guid = unsafe {&mut *guid };
+
+        let fields = uuid.as_fields();
+        (guid.data1, guid.data2, guid.data3, _) = fields;
+        guid.data4.copy_from_slice(fields.3);
+        log::error!("{:?}", guid);
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_GetCapabilities(device_ptr: DevicePtr, res_capabilities: *mut DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        if res_capabilities.is_null() {
+            return E_INVALIDARG;
+        }
+        unsafe { *res_capabilities = display.capabilities().bits() as DWORD };
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Unlike the soft button callback, reads the state from the device; may block for up to 5 seconds
+    fn DirectOutput_ReadButtonsNow(device_ptr: DevicePtr, res_buttons: *mut DWORD) -> HRESULT {
+        // the soft button callback may be called if the state has changed
+        let display = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+            match get_display(state, device_ptr) {
+                Ok(display) => display,
+                Err(err) => return err,
+            }
+        };
+
+        if res_buttons.is_null() {
+            return E_INVALIDARG;
+        }
+        if !display.capabilities().contains(devices::Capabilities::BUTTONS) {
+            return E_NOTIMPL;
+        }
+        let Ok(buttons) = display.read_soft_buttons() else { return E_FAIL };
+        unsafe { *res_buttons = buttons.bits() as DWORD };
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Polling counterpart of the soft button callback: waits for up to `timeout_ms`
+    // for the buttons state to change. Changes are queued from the first call on.
+    fn DirectOutput_WaitForButtons(device_ptr: DevicePtr, timeout_ms: DWORD, res_buttons: *mut DWORD) -> HRESULT {
+        // the library must not be locked while waiting
+        let display = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+            match get_display(state, device_ptr) {
+                Ok(display) => display,
+                Err(err) => return err,
+            }
+        };
+
+        if res_buttons.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(timeout_ms) = timeout_ms.try_into() else { return E_INVALIDARG };
+        if !display.capabilities().contains(devices::Capabilities::BUTTONS) {
+            return E_NOTIMPL;
+        }
+        let Some(buttons) = display.next_button_event(Duration::from_millis(timeout_ms)) else {
+            return E_TIMEOUT;
+        };
+        unsafe { *res_buttons = buttons.bits() as DWORD };
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_GetDeviceInstance(device_ptr: DevicePtr, guid: *mut GUID) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+        if let Err(err) = get_display(state, device_ptr) {
+            return err;
+        }
+
+        // TODO?? (DirectInput)
+        E_NOTIMPL
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_SetProfile(device_ptr: DevicePtr, debug_profile_name_size: usize, debug_profile_name: *mut libc::wchar_t) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+        if let Err(err) = get_display(state, device_ptr) {
+            return err;
+        }
+
+        // TODO?? (talks to the driver)
+        E_NOTIMPL
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_AddPage(device_ptr: DevicePtr, page_number: DWORD, debug_name: *const libc::wchar_t, page_flags: DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+        let debug_name = match debug_name.is_null() {
+            true => None,
+            false => unsafe { widestring::WideCStr::from_ptr_str(debug_name.cast()) }.to_string().ok(),
+        };
+        let page_flags = devices::PageFlags::from_bits_lossy(page_flags as u32);
+        if display.add_page(page, debug_name, page_flags).is_err() {
+            return E_INVALIDARG;
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_AddPageWithImage(device_ptr: DevicePtr, page_number: DWORD, debug_name: *const libc::wchar_t, page_flags: DWORD, image_size: DWORD, image: *const u8) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+        let debug_name = match debug_name.is_null() {
+            true => None,
+            false => unsafe { widestring::WideCStr::from_ptr_str(debug_name.cast()) }.to_string().ok(),
+        };
+        let page_flags = devices::PageFlags::from_bits_lossy(page_flags as u32);
+        let result = if image.is_null() {
+            display.add_page(page, debug_name, page_flags)
+        } else {
+            let image_data_size = display.image_data_size();
+            let Ok(image_size): Result<usize, _> = image_size.try_into() else { return E_INVALIDARG };
+            if image_size != image_data_size {
+                return E_BUFFERTOOSMALL;
+            }
+            let image_data = unsafe { slice::from_raw_parts(image, image_data_size) };
+            display.add_page_with_image(page, debug_name, page_flags, image_data)
+        };
+        if result.is_err() {
+            return E_INVALIDARG;
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_RemovePage(device_ptr: DevicePtr, page_number: DWORD) -> HRESULT {
+        let display = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+            match get_display(state, device_ptr) {
+                Ok(display) => display,
+                Err(err) => return err,
+            }
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+        if display.remove_page(page).is_err() {
+            return E_INVALIDARG;
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_GetPageList(device_ptr: DevicePtr, res_pages: *mut DWORD, res_pages_count: *mut DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        unsafe { write_dword_list(&display.page_numbers(), res_pages, res_pages_count) }
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_GetLedIndices(device_ptr: DevicePtr, page_number: DWORD, res_indices: *mut DWORD, res_indices_count: *mut DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+        unsafe { write_dword_list(&display.led_indices(page), res_indices, res_indices_count) }
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_SetLed(device_ptr: DevicePtr, page_number: DWORD, led_index: DWORD, led_value: DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG; };
+        let Ok(led_index) = led_index.try_into() else { return E_INVALIDARG; };
+        if !display.led_indices(page).contains(&led_index) {
+            return E_INVALIDARG;
+        }
+        let led_value = match led_value {
+            0 => false,
+            1 => true,
+            _ => return E_INVALIDARG,
+        };
+        _ = display.set_led(page, led_index, led_value);
+        // TODO: error handling
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_AllLedsOff(device_ptr: DevicePtr) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        match display.all_leds_off() {
+            Ok(()) => S_OK,
+            Err(()) => E_FAIL,
+        }
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_SetLedLevel(device_ptr: DevicePtr, page_number: DWORD, led_index: DWORD, led_level: DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG; };
+        let Ok(led_index) = led_index.try_into() else { return E_INVALIDARG; };
+        if !display.led_indices(page).contains(&led_index) {
+            return E_INVALIDARG;
+        }
+        let Ok(led_level) = led_level.try_into() else { return E_INVALIDARG; };
+        match display.set_led_level(page, led_index, led_level) {
+            Ok(()) => S_OK,
+            Err(()) => E_FAIL,
+        }
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_ClearImageSlot(device_ptr: DevicePtr, page_number: DWORD, image_index: DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+        let Ok(image_index) = image_index.try_into() else { return E_INVALIDARG };
+        if !display.image_indices(page).contains(&image_index) {
+            return E_INVALIDARG;
+        }
+        // an invalid slot is rejected above, so a failure is the device's
+        match display.clear_image_slot(page, image_index) {
+            Ok(()) => S_OK,
+            Err(()) => E_FAIL,
+        }
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_ResetDevice(device_ptr: DevicePtr) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        match display.reset() {
+            Ok(()) => S_OK,
+            Err(()) => E_FAIL,
+        }
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_SetString(device_ptr: DevicePtr, page_number: DWORD, string_index: DWORD, string_size: DWORD, string: *const libc::wchar_t) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+
+        // FIP has no text lines, only images
+        if !display.capabilities().contains(devices::Capabilities::TEXT) {
+            return E_NOTIMPL;
+        }
+        let Ok(string_size) = string_size.try_into() else { return E_INVALIDARG };
+        let Ok(text) = (unsafe { line_text(string, string_size, display.string_width()) }) else {
+            return E_INVALIDARG;
+        };
+        let Ok(string_index) = string_index.try_into() else { return E_INVALIDARG };
+        if !display.string_indices(page).contains(&string_index) {
+            return E_INVALIDARG;
+        }
+        if display.set_string(page, string_index, &text).is_err() {
+            return E_FAIL;
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Black is transparent in the slots above 0 (the slots are drawn over each other),
+    // so a black image there shows the slots below; DirectOutput_ClearImageSlot removes it
+    fn DirectOutput_SetImage(device_ptr: DevicePtr, page_number: DWORD, image_index: DWORD, image_size: DWORD, image: *const u8) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+
+        if image.is_null() {
+            return E_INVALIDARG;
+        }
+        if !display.capabilities().contains(devices::Capabilities::IMAGE) {
+            return E_NOTIMPL;
+        }
+        let image_data_size = display.image_data_size();
+        let Ok(image_size): Result<usize, _> = image_size.try_into() else { return E_INVALIDARG };
+        if image_size != image_data_size {
+            return E_BUFFERTOOSMALL;
+        }
+        {
+            let image_data = unsafe { slice::from_raw_parts(image, image_data_size) };
+            let Ok(image_index) = image_index.try_into() else { return E_INVALIDARG };
+            if !display.image_indices(page).contains(&image_index) {
+                return E_INVALIDARG;
+            }
+            _ = display.set_image_data(page, image_index, image_data);
+            // TODO: error handling
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Region data is in the same format as the SetImage one, with the region size instead of 320x240
+    fn DirectOutput_SetImageRegion(device_ptr: DevicePtr, page_number: DWORD, image_index: DWORD, x: DWORD, y: DWORD, width: DWORD, height: DWORD, region_size: DWORD, region: *const u8) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+
+        if region.is_null() {
+            return E_INVALIDARG;
+        }
+        if !display.capabilities().contains(devices::Capabilities::IMAGE) {
+            return E_NOTIMPL;
+        }
+        let Ok(image_index) = image_index.try_into() else { return E_INVALIDARG };
+        if !display.image_indices(page).contains(&image_index) {
+            return E_INVALIDARG;
+        }
+        let (Ok(x), Ok(y), Ok(width), Ok(height)) = (x.try_into(), y.try_into(), width.try_into(), height.try_into()) else {
+            return E_INVALIDARG;
+        };
+        let Ok(region_size) = region_size.try_into() else { return E_INVALIDARG };
+        let region_data = unsafe { slice::from_raw_parts(region, region_size) };
+        if display.set_image_region(page, image_index, images::Region { x, y, width, height }, region_data).is_err() {
+            return E_INVALIDARG;
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_BeginFrame(device_ptr: DevicePtr, page_number: DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+        display.begin_frame(page);
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_CommitFrame(device_ptr: DevicePtr, page_number: DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+        if display.commit_frame(page).is_err() {
+            return E_FAIL;
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Images and LEDs set until DirectOutput_EndBatch are only sent by it (the last ones),
+    // e.g. to set up a profile at once. A batch left open for long is ended by the library.
+    fn DirectOutput_BeginBatch(device_ptr: DevicePtr) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        display.begin_batch();
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_EndBatch(device_ptr: DevicePtr) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        if display.end_batch().is_err() {
+            return E_FAIL;
+        }
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_FillColor(device_ptr: DevicePtr, page_number: DWORD, red: DWORD, green: DWORD, blue: DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+        let (Ok(red), Ok(green), Ok(blue)) = (red.try_into(), green.try_into(), blue.try_into()) else {
+            return E_INVALIDARG;
+        };
+        match display.fill_color(page, 0, red, green, blue) {
+            Ok(()) => S_OK,
+            Err(()) => E_FAIL,
+        }
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_GetImage(device_ptr: DevicePtr, page_number: DWORD, image_index: DWORD, res_image_size: DWORD, res_image: *mut u8) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+
+        if res_image.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(image_index) = image_index.try_into() else { return E_INVALIDARG };
+        let Ok(image_data) = display.get_image_data(page, image_index) else { return E_INVALIDARG };
+        let Ok(res_image_size): Result<usize, _> = res_image_size.try_into() else { return E_INVALIDARG };
+        if image_data.len() > res_image_size {
+            return E_BUFFERTOOSMALL;
+        }
+        unsafe { slice::from_raw_parts_mut(res_image, image_data.len()) }.copy_from_slice(&image_data);
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Returns the image shown on the page: its image slots drawn over each other in index order,
+    // black pixels of a slot letting the slots below show through (black if no image is set)
+    fn DirectOutput_GetPageImage(device_ptr: DevicePtr, page_number: DWORD, res_image_size: DWORD, res_image: *mut u8) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+
+        if res_image.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(image_data) = display.get_page_image(page) else { return E_INVALIDARG };
+        let Ok(res_image_size): Result<usize, _> = res_image_size.try_into() else { return E_INVALIDARG };
+        if image_data.len() > res_image_size {
+            return E_BUFFERTOOSMALL;
+        }
+        unsafe { slice::from_raw_parts_mut(res_image, image_data.len()) }.copy_from_slice(&image_data);
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    // Files of exactly the image data size are taken as raw image data (in the SetImage format)
+    // rather than decoded, unless disabled with LIBFIP_RAW_IMAGE_FILES=0
+    fn DirectOutput_SetImageFromFile(device_ptr: DevicePtr, page_number: DWORD, image_index: DWORD, filename_size: DWORD, filename: *const libc::wchar_t) -> HRESULT {
+        // the display is taken out, so the library is not locked while the file is decoded
+        let display = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+
+            match get_display(state, device_ptr) {
+                Ok(display) => display,
+                Err(err) => return err,
+            }
+        };
+
+        let Ok(page) = page_number.try_into() else { return E_INVALIDARG };
+
+        if filename.is_null() {
+            return E_INVALIDARG;
+        }
+        if !display.capabilities().contains(devices::Capabilities::IMAGE) {
+            return E_NOTIMPL;
+        }
+        let Ok(filename_size) = filename_size.try_into() else { return E_INVALIDARG };
+        let Ok(filename_wide) = widestring::WideCStr::from_ptr(filename.cast(), filename_size) else {
+            return E_INVALIDARG;
+        };
+        let Ok(filename) = filename_wide.to_string() else { return E_INVALIDARG };
+        let Ok(image_index) = image_index.try_into() else { return E_INVALIDARG };
+        if !display.image_indices(page).contains(&image_index) {
+            return E_INVALIDARG;
+        }
+
+        let Ok(image_file) = images::ImageCache::load(&IMAGE_CACHE, Path::new(&filename)) else {
+            return E_INVALIDARG;
+        };
+        let image_adjustment = display.image_adjustment();
+        // raw files are sent exactly as they are
+        if image_file.raw || image_adjustment.is_identity() {
+            _ = display.set_image_data(page, image_index, &image_file.data);
+        } else {
+            let mut image_data = image_file.data.to_vec();
+            image_adjustment.apply(&mut image_data);
+            _ = display.set_image_data(page, image_index, &image_data);
+        }
+        // TODO: error handling
+
+        S_OK
     }
 }
 
 directoutputlib_export! {
     fn DirectOutput_StartServer(device_ptr: DevicePtr, filename_size: DWORD, filename: *const libc::wchar_t, server_id: *mut DWORD, status: *mut SRequestStatus) -> HRESULT {
-        // TODO
-        todo!()
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        if filename.is_null() || server_id.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(filename_size) = filename_size.try_into() else { return E_INVALIDARG };
+        let Ok(filename_wide) = widestring::WideCStr::from_ptr(filename.cast(), filename_size) else {
+            return E_INVALIDARG;
+        };
+        let Ok(filename) = filename_wide.to_string() else { return E_INVALIDARG };
+        let Ok(file) = fs::File::open(filename) else { return E_INVALIDARG };
+
+        let mut request_status = devices::RequestStatus::default();
+        let result = display.start_server(&mut BufReader::new(file), &mut request_status);
+        if !status.is_null() {
+            unsafe { fill_status(&mut *status, &request_status) };
+        }
+        match result {
+            Ok(id) => {
+                unsafe { *server_id = id as DWORD };
+                S_OK
+            }
+            Err(()) => E_FAIL,
+        }
     }
 }
 
 directoutputlib_export! {
     fn DirectOutput_CloseServer(device_ptr: DevicePtr, server_id: DWORD, status: *mut SRequestStatus) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+        if let Err(err) = get_display(state, device_ptr) {
+            return err;
+        }
+
         // TODO
-        todo!()
+        E_NOTIMPL
     }
 }
 
 directoutputlib_export! {
     fn DirectOutput_SendServerMsg(device_ptr: DevicePtr, server_id: DWORD, request: DWORD, page_number: DWORD, data_size: DWORD, data: *const u8, output_size: DWORD, output: *mut u8, status: *mut SRequestStatus) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+        if let Err(err) = get_display(state, device_ptr) {
+            return err;
+        }
+
+        let Ok(_page): Result<u8, _> = page_number.try_into() else { return E_INVALIDARG };
+
         // TODO
-        todo!()
+        E_NOTIMPL
     }
 }
 
 directoutputlib_export! {
     fn DirectOutput_SendServerFile(device_ptr: DevicePtr, server_id: DWORD, request: DWORD, page_number: DWORD, header_size: DWORD, header: *const u8, filename_size: DWORD, filename: *const libc::wchar_t, output_size: DWORD, output: *mut u8, status: *mut SRequestStatus) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+        if let Err(err) = get_display(state, device_ptr) {
+            return err;
+        }
+
+        let Ok(_page): Result<u8, _> = page_number.try_into() else { return E_INVALIDARG };
+
         // TODO
-        todo!()
+        E_NOTIMPL
     }
 }
 
@@ -369,20 +1526,72 @@ directoutputlib_export! {
             Err(err) => return err,
         };
 
+        let Ok(page_number) = page_number.try_into() else { return E_INVALIDARG };
+        let Ok(file_index) = file_index.try_into() else { return E_INVALIDARG };
+        if filename.is_null() {
+            return E_INVALIDARG;
+        }
         let Ok(filename_wide) = widestring::WideCStr::from_ptr(filename.cast(), filename_size) else {
             return E_INVALIDARG;
         };
 
-        let Ok(file) = fs::File::open(filename_wide.to_string().expect("Invalid filename")) else {
-            return E_INVALIDARG;
+        let Ok(filename) = filename_wide.to_string() else { return E_INVALIDARG };
+        let Ok(file_data) = fs::read(filename) else { return E_INVALIDARG };
+        let mut request_status = devices::RequestStatus::default();
+        let result = display.save_file_bytes(page_number, file_index, &file_data, &mut request_status);
+        if !status.is_null() {
+            unsafe { fill_status(&mut *status, &request_status) };
+        }
+        match result {
+            Ok(()) => S_OK,
+            Err(devices::RequestError::Timeout) => E_TIMEOUT,
+            Err(devices::RequestError::Failed) => E_FAIL,
+        }
+    }
+}
+
+directoutputlib_export! {
+    // Same as SaveFile, but the transfers of the file time out after `timeout_ms` instead
+    // of the default, for files known to take longer (returning E_TIMEOUT if they do)
+    fn DirectOutput_SaveFileEx(device_ptr: DevicePtr, page_number: DWORD, file_index: DWORD, filename_size: usize, filename: *const libc::wchar_t, status: *mut SRequestStatus, timeout_ms: DWORD) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
         };
+
         let Ok(page_number) = page_number.try_into() else { return E_INVALIDARG };
-        let Ok(file_index) = file_index.try_into() else { return E_INVALIDARG };
-        _ = display.save_file(page_number, file_index, &mut BufReader::new(file));
-        // TODO: error handling
-        // TODO: fill in `status`
 
-        S_OK
+        // 0 would mean no timeout at all to libusb
+        let timeout = match timeout_ms.try_into() {
+            Ok(0) | Err(_) => return E_INVALIDARG,
+            Ok(timeout_ms) => Duration::from_millis(timeout_ms),
+        };
+        let Ok(file_index) = file_index.try_into() else { return E_INVALIDARG };
+        if filename.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(filename_wide) = widestring::WideCStr::from_ptr(filename.cast(), filename_size) else {
+            return E_INVALIDARG;
+        };
+        let Ok(filename) = filename_wide.to_string() else { return E_INVALIDARG };
+        let Ok(file_data) = fs::read(filename) else {
+            return E_INVALIDARG;
+        };
+        let mut request_status = devices::RequestStatus::default();
+        let result = display.save_file_bytes_with_timeout(page_number, file_index, &file_data, timeout, &mut request_status);
+        if !status.is_null() {
+            unsafe { fill_status(&mut *status, &request_status) };
+        }
+        match result {
+            Ok(()) => S_OK,
+            Err(devices::RequestError::Timeout) => E_TIMEOUT,
+            Err(devices::RequestError::Failed) => E_FAIL,
+        }
     }
 }
 
@@ -431,6 +1640,59 @@ directoutputlib_export! {
     }
 }
 
+directoutputlib_export! {
+    fn DirectOutput_GetLastRequestStatus(device_ptr: DevicePtr, status: *mut SRequestStatus) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        if status.is_null() {
+            return E_INVALIDARG;
+        }
+        let request_status = display.last_request_status().unwrap_or_default();
+        unsafe { fill_status(&mut *status, &request_status) };
+
+        S_OK
+    }
+}
+
+directoutputlib_export! {
+    fn DirectOutput_GetTransferStats(device_ptr: DevicePtr, res_stats: *mut STransferStats) -> HRESULT {
+        let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+            log::error!("Library function has been called, but the library is not initialized");
+            return E_HANDLE;
+        };
+
+        let display = match get_display(state, device_ptr) {
+            Ok(display) => display,
+            Err(err) => return err,
+        };
+
+        if res_stats.is_null() {
+            return E_INVALIDARG;
+        }
+        let stats = display.transfer_stats();
+        unsafe {
+            *res_stats = STransferStats {
+                bytes_written: stats.bytes_written,
+                bytes_read: stats.bytes_read,
+                transfers: stats.transfers,
+                errors: stats.errors,
+                timeouts: stats.timeouts,
+                average_latency_us: stats.average_latency.as_micros() as u64,
+            }
+        };
+
+        S_OK
+    }
+}
+
 directoutputlib_export! {
     fn DirectOutput_GetSerialNumber(device_ptr: DevicePtr, res_serial_number: *mut libc::wchar_t, res_serial_number_size: usize) -> HRESULT {
         let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
@@ -455,23 +1717,359 @@ directoutputlib_export! {
     }
 }
 
-fn extract_addr(device_ptr: DevicePtr) -> Result<devices::UsbDeviceAddress, HRESULT> {
-    if device_ptr as u16 == 0 || device_ptr >= u16::MAX.into() {
-        return Err(E_HANDLE);
+directoutputlib_export! {
+    // Writes JSON text (UTF-8, null-terminated) describing the library and devices state,
+    // for bug reports. `*res_size` is the buffer size in bytes, and is set to the needed one.
+    fn DirectOutput_DumpState(res_buffer: *mut u8, res_size: *mut DWORD) -> HRESULT {
+        let dump = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+            diagnostics::dump_state(state)
+        };
+
+        if res_size.is_null() {
+            return E_INVALIDARG;
+        }
+        let Ok(capacity): Result<usize, _> = unsafe { *res_size }.try_into() else {
+            return E_INVALIDARG;
+        };
+        let mut dump = dump.into_bytes();
+        dump.push(0);
+        unsafe { *res_size = dump.len() as DWORD };
+        if dump.len() > capacity {
+            return E_BUFFERTOOSMALL;
+        }
+        if res_buffer.is_null() {
+            return E_INVALIDARG;
+        }
+        unsafe { slice::from_raw_parts_mut(res_buffer, dump.len()) }.copy_from_slice(&dump);
+
+        S_OK
     }
-    let casted: u16 = device_ptr as u16;
-    Ok(((casted >> 8) as u8, (casted & 0xff) as u8))
 }
 
-fn embed_addr(device_addr: devices::UsbDeviceAddress) -> DevicePtr {
-    ((device_addr.0 as u16) << 8 | (device_addr.1 as u16)) as DevicePtr
+directoutputlib_export! {
+    // Blocks until every device has sent all the requests made so far
+    fn DirectOutput_Flush() -> HRESULT {
+        let displays: Vec<_> = {
+            let Some(ref state) = *STATE.lock().expect("State is poisoned") else {
+                log::error!("Library function has been called, but the library is not initialized");
+                return E_HANDLE;
+            };
+            state
+                .display_addrs()
+                .iter()
+                .filter_map(|addr| state.display_by_addr(addr))
+                .collect()
+        };
+        // the state is not locked while waiting, so the requests being waited for can go on
+
+        let started = Instant::now();
+        for display in displays {
+            let timeout = FLUSH_TIMEOUT.saturating_sub(started.elapsed());
+            if display.flush(timeout).is_err() {
+                log::error!("Device {:?} has not become idle in {:?}", display.serial_number(), FLUSH_TIMEOUT);
+                return E_TIMEOUT;
+            }
+        }
+
+        S_OK
+    }
 }
 
-fn get_display(
+// not a device option, as image files are loaded (and cached) for all devices at once
+fn raw_image_files_from_env() -> bool {
+    match std::env::var("LIBFIP_RAW_IMAGE_FILES").as_deref() {
+        Ok("1") | Err(_) => true,
+        Ok("0") => false,
+        Ok(value) => {
+            log::warn!(
+                "Ignoring invalid LIBFIP_RAW_IMAGE_FILES value ({:?})",
+                value
+            );
+            true
+        }
+    }
+}
+
+fn options_from_env() -> devices::Options {
+    let mut options = devices::Options::default();
+    if let Ok(max_fps) = std::env::var("LIBFIP_MAX_FPS") {
+        match max_fps.parse::<f64>() {
+            Ok(max_fps) if max_fps > 0.0 => {
+                options = options.min_frame_interval(Duration::from_secs_f64(1.0 / max_fps));
+            }
+            _ => log::warn!("Ignoring invalid LIBFIP_MAX_FPS value ({:?})", max_fps),
+        }
+    }
+    if let Ok(depth) = std::env::var("LIBFIP_FRAME_QUEUE_DEPTH") {
+        let overflow_policy = match std::env::var("LIBFIP_FRAME_OVERFLOW").as_deref() {
+            Ok("drop-oldest") | Err(_) => Some(devices::FrameOverflowPolicy::DropOldest),
+            Ok("drop-newest") => Some(devices::FrameOverflowPolicy::DropNewest),
+            Ok("block") => Some(devices::FrameOverflowPolicy::Block),
+            Ok(value) => {
+                log::warn!("Ignoring invalid LIBFIP_FRAME_OVERFLOW value ({:?})", value);
+                None
+            }
+        };
+        match (depth.parse::<usize>(), overflow_policy) {
+            (Ok(depth), Some(overflow_policy)) if depth > 0 => {
+                options = options.frame_queue(depth, overflow_policy);
+            }
+            (_, Some(_)) => log::warn!(
+                "Ignoring invalid LIBFIP_FRAME_QUEUE_DEPTH value ({:?})",
+                depth
+            ),
+            (_, None) => (),
+        }
+    }
+    if let Ok(max) = std::env::var("LIBFIP_MAX_IMAGE_UPLOADS") {
+        match max.parse::<usize>() {
+            Ok(max) if max > 0 => options = options.max_image_uploads(max),
+            _ => log::warn!(
+                "Ignoring invalid LIBFIP_MAX_IMAGE_UPLOADS value ({:?})",
+                max
+            ),
+        }
+    }
+    if let Ok(size) = std::env::var("LIBFIP_IMAGE_BUFFERS") {
+        match size.parse::<usize>() {
+            Ok(size) => options = options.image_buffer_pool(size),
+            Err(_) => log::warn!("Ignoring invalid LIBFIP_IMAGE_BUFFERS value ({:?})", size),
+        }
+    }
+    // gamma and contrast are factors, so only positive ones make sense
+    let image_factor = |name: &str| {
+        let value = std::env::var(name).ok()?;
+        match value.parse::<f32>() {
+            Ok(factor) if factor.is_finite() && factor > 0.0 => Some(factor),
+            _ => {
+                log::warn!("Ignoring invalid {} value ({:?})", name, value);
+                None
+            }
+        }
+    };
+    let gamma = image_factor("LIBFIP_IMAGE_GAMMA");
+    let contrast = image_factor("LIBFIP_IMAGE_CONTRAST");
+    if gamma.is_some() || contrast.is_some() {
+        options = options.image_adjustment(images::ImageAdjustment::new(
+            gamma.unwrap_or(1.0),
+            contrast.unwrap_or(1.0),
+        ));
+    }
+    if let Ok(bits) = std::env::var("LIBFIP_IMAGE_DITHER_BITS") {
+        match bits.parse::<u8>() {
+            Ok(bits @ 1..=8) => options = options.image_dithering(bits),
+            _ => log::warn!(
+                "Ignoring invalid LIBFIP_IMAGE_DITHER_BITS value ({:?})",
+                bits
+            ),
+        }
+    }
+    if let Ok(path) = std::env::var("LIBFIP_DEVICE_IDS_FILE") {
+        match fs::read_to_string(&path)
+            .map_err(|err| log::error!("Cannot read {:?}: {}", path, err))
+            .and_then(|text| devices::parse_device_ids(&text))
+        {
+            Ok(device_ids) => options = options.device_ids(device_ids),
+            Err(_) => {
+                log::warn!("Ignoring LIBFIP_DEVICE_IDS_FILE, only built-in device IDs are used")
+            }
+        }
+    }
+    if let Some(path) = std::env::var_os("LIBFIP_STATE_FILE") {
+        options = options.state_file(path.into());
+    }
+    match std::env::var("LIBFIP_RESET_ON_INIT").as_deref() {
+        Ok("1") => options = options.reset_on_init(),
+        Ok("0") | Err(_) => (),
+        Ok(value) => log::warn!("Ignoring invalid LIBFIP_RESET_ON_INIT value ({:?})", value),
+    }
+    match std::env::var("LIBFIP_SKIP_FACTORY_MODE_CHECK").as_deref() {
+        Ok("1") => options = options.skip_factory_mode_check(),
+        Ok("0") | Err(_) => (),
+        Ok(value) => log::warn!(
+            "Ignoring invalid LIBFIP_SKIP_FACTORY_MODE_CHECK value ({:?})",
+            value
+        ),
+    }
+    match std::env::var("LIBFIP_IMAGE_SEQUENCE_NUMBERS").as_deref() {
+        Ok("1") => options = options.image_sequence_numbers(),
+        Ok("0") | Err(_) => (),
+        Ok(value) => log::warn!(
+            "Ignoring invalid LIBFIP_IMAGE_SEQUENCE_NUMBERS value ({:?})",
+            value
+        ),
+    }
+    match std::env::var("LIBFIP_FILE_TRANSFER_ENDPOINT").as_deref() {
+        Ok("1") => options = options.file_transfer_endpoint(),
+        Ok("0") | Err(_) => (),
+        Ok(value) => log::warn!(
+            "Ignoring invalid LIBFIP_FILE_TRANSFER_ENDPOINT value ({:?})",
+            value
+        ),
+    }
+    match std::env::var("LIBFIP_KEEP_HID_DRIVER").as_deref() {
+        Ok("1") => options = options.keep_hid_driver(),
+        Ok("0") | Err(_) => (),
+        Ok(value) => log::warn!(
+            "Ignoring invalid LIBFIP_KEEP_HID_DRIVER value ({:?})",
+            value
+        ),
+    }
+    // `*` mirrors every device, otherwise it's a comma-separated list of serial numbers
+    match std::env::var("LIBFIP_UINPUT").as_deref().map(str::trim) {
+        Ok("*") => options = options.uinput(Vec::new()),
+        Ok("") | Err(_) => (),
+        Ok(serial_numbers) => {
+            options = options.uinput(
+                serial_numbers
+                    .split(',')
+                    .map(|serial_number| serial_number.trim().to_owned())
+                    .collect(),
+            )
+        }
+    }
+    if let Ok(nice) = std::env::var("LIBFIP_THREAD_NICE") {
+        match nice.parse::<i32>() {
+            Ok(nice) => options = options.device_thread_priority(nice),
+            Err(_) => log::warn!("Ignoring invalid LIBFIP_THREAD_NICE value ({:?})", nice),
+        }
+    }
+    if let Ok(cpu) = std::env::var("LIBFIP_THREAD_CPU") {
+        match cpu.parse::<usize>() {
+            Ok(cpu) => options = options.device_thread_cpu(cpu),
+            Err(_) => log::warn!("Ignoring invalid LIBFIP_THREAD_CPU value ({:?})", cpu),
+        }
+    }
+    if let Ok(timeout_ms) = std::env::var("LIBFIP_INIT_TIMEOUT_MS") {
+        match timeout_ms.parse::<u64>() {
+            // no device could be initialized in no time
+            Ok(timeout_ms) if timeout_ms > 0 => {
+                options = options.init_timeout(Duration::from_millis(timeout_ms))
+            }
+            _ => log::warn!(
+                "Ignoring invalid LIBFIP_INIT_TIMEOUT_MS value ({:?})",
+                timeout_ms
+            ),
+        }
+    }
+    if let Ok(delay_ms) = std::env::var("LIBFIP_HOTPLUG_DEBOUNCE_MS") {
+        match delay_ms.parse::<u64>() {
+            Ok(delay_ms) => options = options.hotplug_debounce(Duration::from_millis(delay_ms)),
+            Err(_) => log::warn!(
+                "Ignoring invalid LIBFIP_HOTPLUG_DEBOUNCE_MS value ({:?})",
+                delay_ms
+            ),
+        }
+    }
+    if let Ok(timeout_ms) = std::env::var("LIBFIP_WAIT_FOR_READY_MS") {
+        match timeout_ms.parse::<u64>() {
+            Ok(timeout_ms) => options = options.wait_for_ready(Duration::from_millis(timeout_ms)),
+            Err(_) => log::warn!(
+                "Ignoring invalid LIBFIP_WAIT_FOR_READY_MS value ({:?})",
+                timeout_ms
+            ),
+        }
+    }
+    options
+}
+
+/// Writes the values into a caller's buffer of `*count` elements,
+/// setting `*count` to the number of values (even if they don't fit)
+unsafe fn write_dword_list(values: &[u8], res: *mut DWORD, res_count: *mut DWORD) -> HRESULT {
+    if res_count.is_null() {
+        return E_INVALIDARG;
+    }
+    let Ok(capacity): Result<usize, _> = unsafe { *res_count }.try_into() else {
+        return E_INVALIDARG;
+    };
+
+    // always report the required count, so the caller can grow its buffer
+    unsafe { *res_count = values.len() as DWORD };
+    if values.len() > capacity {
+        return E_BUFFERTOOSMALL;
+    }
+    if !values.is_empty() {
+        if res.is_null() {
+            return E_INVALIDARG;
+        }
+        let res = unsafe { slice::from_raw_parts_mut(res, values.len()) };
+        res.iter_mut()
+            .zip(values)
+            .for_each(|(res_value, value)| *res_value = (*value).into());
+    }
+
+    S_OK
+}
+
+fn fill_status(status: &mut SRequestStatus, request_status: &devices::RequestStatus) {
+    status.dwHeaderError = request_status.header_error as DWORD;
+    status.dwHeaderInfo = request_status.header_info as DWORD;
+    status.dwRequestError = request_status.request_error as DWORD;
+    status.dwRequestInfo = request_status.request_info as DWORD;
+}
+
+/// Decodes the `string_size` characters of a text line given by the host, truncating
+/// the text longer than the line's `width`. An empty text clears the line.
+unsafe fn line_text(
+    string: *const libc::wchar_t,
+    string_size: usize,
+    width: usize,
+) -> Result<String, ()> {
+    if string_size == 0 {
+        return Ok(String::new());
+    }
+    if string.is_null() {
+        return Err(());
+    }
+    let text = unsafe { widestring::WideStr::from_ptr(string.cast(), string_size) }
+        .to_string()
+        .map_err(drop)?;
+    Ok(text.chars().take(width).collect())
+}
+
+/// Device handle passed to the host, with the USB bus number and address of the device embedded.
+/// All the encoding and decoding of handles is done here.
+///
+/// Handles carry no generation: a reconnected device gets the next free address on its bus,
+/// so a stale handle only refers to another device once the addresses of the bus wrap around,
+/// and a device probed again after being invalidated (see `devices::reprobe`) is the same
+/// device at the same address. A generation would also have to go through every hotplug
+/// change, as the host matches the handle of a left device against the one it arrived with.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DevicePtr(u64);
+
+impl DevicePtr {
+    fn from_addr(device_addr: devices::UsbDeviceAddress) -> DevicePtr {
+        DevicePtr(((device_addr.0 as u64) << 8) | (device_addr.1 as u64))
+    }
+
+    /// Fails for handles that no device can have
+    fn to_addr(self) -> Result<devices::UsbDeviceAddress, HRESULT> {
+        // 0 is the null handle, and nothing above the lower 16 bits is ever set
+        if self.0 == 0 || self.0 > u16::MAX.into() {
+            return Err(E_HANDLE);
+        }
+        Ok(((self.0 >> 8) as u8, self.0 as u8))
+    }
+}
+
+impl std::fmt::Display for DevicePtr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Same as `get_display`, but returns devices that are not ready too
+fn get_any_display(
     state: &devices::State,
     device_ptr: DevicePtr,
 ) -> Result<Arc<dyn devices::ManagedDisplay>, HRESULT> {
-    let Ok(addr) = extract_addr(device_ptr) else {
+    let Ok(addr) = device_ptr.to_addr() else {
         log::error!("Library function has been called with an invalid device pointer");
         return Err(E_HANDLE);
     };
@@ -479,9 +2077,192 @@ fn get_display(
         log::error!("Library function has been called with a device pointer that doesn't exists");
         return Err(E_HANDLE);
     };
+    Ok(display)
+}
+
+fn get_display(
+    state: &devices::State,
+    device_ptr: DevicePtr,
+) -> Result<Arc<dyn devices::ManagedDisplay>, HRESULT> {
+    let display = get_any_display(state, device_ptr)?;
+    state.wait_for_ready(display.as_ref());
+    if display.in_use_elsewhere() {
+        log::error!(
+            "Library function has been called with a device that is in use by another application"
+        );
+        return Err(E_BUSY);
+    }
+    if display.init_timed_out() {
+        log::error!(
+            "Library function has been called with a device that has not been initialized in time"
+        );
+        return Err(E_TIMEOUT);
+    }
     if !display.ready() {
-        log::error!("Library function has been called with a device that has been not yet initialized or has been errored");
+        if display.initializing() {
+            log::warn!(
+                "Library function has been called with a device that has been not yet initialized"
+            );
+            return Err(E_DEVICENOTREADY);
+        }
+        log::error!("Library function has been called with a device that has been errored");
         return Err(E_HANDLE);
     }
     Ok(display)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_text_is_truncated_to_the_line_width() {
+        let string = widestring::WideString::from_str("Heading 270");
+        let text = unsafe { line_text(string.as_ptr().cast(), string.len(), 7) };
+        assert_eq!(text, Ok("Heading".to_owned()));
+        let text = unsafe { line_text(string.as_ptr().cast(), string.len(), 16) };
+        assert_eq!(text, Ok("Heading 270".to_owned()));
+        // only the given characters are taken, the string needs no terminator
+        let text = unsafe { line_text(string.as_ptr().cast(), 3, 16) };
+        assert_eq!(text, Ok("Hea".to_owned()));
+    }
+
+    #[test]
+    fn empty_line_text_clears_the_line() {
+        let text = unsafe { line_text(std::ptr::null(), 0, 16) };
+        assert_eq!(text, Ok(String::new()));
+        let string = widestring::WideString::from_str("Heading");
+        let text = unsafe { line_text(string.as_ptr().cast(), 0, 16) };
+        assert_eq!(text, Ok(String::new()));
+    }
+
+    #[test]
+    fn line_text_must_be_given_if_not_empty() {
+        let text = unsafe { line_text(std::ptr::null(), 4, 16) };
+        assert_eq!(text, Err(()));
+    }
+
+    #[test]
+    fn device_ptr_round_trips_every_address() {
+        for bus in u8::MIN..=u8::MAX {
+            for address in u8::MIN..=u8::MAX {
+                let device_ptr = DevicePtr::from_addr((bus, address));
+                match (bus, address) {
+                    // no device has it, so it's taken for the null handle
+                    (0, 0) => assert_eq!(device_ptr.to_addr(), Err(E_HANDLE)),
+                    addr => assert_eq!(device_ptr.to_addr(), Ok(addr)),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn device_ptr_rejects_handles_of_no_device() {
+        assert_eq!(DevicePtr(0).to_addr(), Err(E_HANDLE));
+        assert_eq!(DevicePtr(0x1_0000).to_addr(), Err(E_HANDLE));
+        assert_eq!(DevicePtr(u64::MAX).to_addr(), Err(E_HANDLE));
+    }
+
+    #[test]
+    fn export_list_names_the_implemented_exports() {
+        let mut size: DWORD = 0;
+        let result = unsafe { DirectOutput_GetExportList(std::ptr::null_mut(), &mut size) };
+        assert_eq!(result, E_BUFFERTOOSMALL);
+
+        let mut buffer: Vec<libc::wchar_t> = vec![0; size as usize];
+        let result = unsafe { DirectOutput_GetExportList(buffer.as_mut_ptr(), &mut size) };
+        assert_eq!(result, S_OK);
+        assert_eq!(size as usize, buffer.len());
+        let list = unsafe { widestring::WideCStr::from_ptr_str(buffer.as_ptr().cast()) }
+            .to_string()
+            .expect("Export list is not valid UTF");
+        assert_eq!(list.lines().collect::<Vec<_>>(), IMPLEMENTED_EXPORTS);
+        assert!(!list.contains("DirectOutput_GetDeviceInstance"));
+        assert!(!list.contains("DirectOutput_SetProfile"));
+    }
+
+    /// Calls exports taking no device, and ones taking the device handle, which all fail alike
+    /// for an uninitialized library and for handles of no device
+    unsafe fn assert_exports_fail_with(device_ptr: DevicePtr, expected: HRESULT) {
+        let mut dword: DWORD = 0;
+        let mut guid = GUID {
+            data1: 0,
+            data2: 0,
+            data3: 0,
+            data4: [0; 8],
+        };
+        let image = images::solid_color(0, 0, 0);
+        let mut serial_number: [libc::wchar_t; 16] = [0; 16];
+        let results = [
+            (
+                "IsFactoryMode",
+                DirectOutput_IsFactoryMode(device_ptr, &mut dword),
+            ),
+            (
+                "GetDeviceType",
+                DirectOutput_GetDeviceType(device_ptr, &mut guid),
+            ),
+            ("SetLed", DirectOutput_SetLed(device_ptr, 0, 0, 1)),
+            ("AllLedsOff", DirectOutput_AllLedsOff(device_ptr)),
+            (
+                "SetImage",
+                DirectOutput_SetImage(device_ptr, 0, 0, image.len() as DWORD, image.as_ptr()),
+            ),
+            ("FillColor", DirectOutput_FillColor(device_ptr, 0, 0, 0, 0)),
+            ("ResetDevice", DirectOutput_ResetDevice(device_ptr)),
+            (
+                "GetSerialNumber",
+                DirectOutput_GetSerialNumber(
+                    device_ptr,
+                    serial_number.as_mut_ptr(),
+                    serial_number.len(),
+                ),
+            ),
+        ];
+        results.iter().for_each(|(name, result)| {
+            assert_eq!(*result, expected, "DirectOutput_{}", name);
+        });
+    }
+
+    #[test]
+    fn exports_without_devices() {
+        // the library state is shared, so it is only initialized by this test
+        let mut dword: DWORD = 0;
+        unsafe {
+            assert_eq!(DirectOutput_GetDeviceCount(&mut dword), E_HANDLE);
+            assert_eq!(DirectOutput_Rescan(), E_HANDLE);
+            assert_eq!(DirectOutput_Flush(), E_HANDLE);
+            assert_exports_fail_with(DevicePtr::from_addr((1, 1)), E_HANDLE);
+            // the version is available before the initialization too
+            let (mut major, mut minor, mut patch): (DWORD, DWORD, DWORD) = (0, 0, 0);
+            assert_eq!(
+                DirectOutput_GetLibraryVersion(&mut major, &mut minor, &mut patch),
+                S_OK
+            );
+        }
+
+        // devices are looked for with libusb, which needs USB support of the system
+        if let Err(err) = rusb::Context::new() {
+            eprintln!(
+                "Skipping the exports with invalid handles, no libusb: {}",
+                err
+            );
+            return;
+        }
+        let state = devices::init(devices::Options::default()).expect("Cannot initialize");
+        *STATE.lock().expect("State is poisoned") = Some(state);
+        unsafe {
+            assert_eq!(DirectOutput_GetDeviceCount(&mut dword), S_OK);
+            // no device has a zero address, nor a bus and address past 255
+            for device_ptr in [DevicePtr(0), DevicePtr(0x1_0000), DevicePtr(u64::MAX)] {
+                assert_exports_fail_with(device_ptr, E_HANDLE);
+            }
+            assert_eq!(
+                DirectOutput_GetDeviceByIndex(DWORD::MAX, &mut DevicePtr(0)),
+                E_INVALIDARG
+            );
+            assert_eq!(DirectOutput_Deinitialize(), S_OK);
+            assert_eq!(DirectOutput_GetDeviceCount(&mut dword), E_HANDLE);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Logger of the library, which forwards to a sink that can be swapped.
+//!
+//! `log` only lets the logger be set once per process, while the library may be
+//! initialized and deinitialized many times, with the host logging its own way in between.
+
+use std::sync::{Once, RwLock};
+
+static LOGGER: SwappableLogger = SwappableLogger {
+    sink: RwLock::new(None),
+};
+
+struct SwappableLogger {
+    sink: RwLock<Option<Box<dyn log::Log>>>,
+}
+
+impl log::Log for SwappableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        match self.sink.read() {
+            Ok(sink) => sink.as_ref().is_some_and(|sink| sink.enabled(metadata)),
+            Err(_) => false,
+        }
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Ok(sink) = self.sink.read() {
+            if let Some(ref sink) = *sink {
+                sink.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(sink) = self.sink.read() {
+            if let Some(ref sink) = *sink {
+                sink.flush();
+            }
+        }
+    }
+}
+
+fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        // fails only if the process has set another logger, which then gets the messages
+        _ = log::set_logger(&LOGGER);
+    });
+}
+
+/// Installs the logger, with the default sink unless another one is set already
+pub fn init() {
+    install();
+    let has_sink = LOGGER.sink.read().expect("Logger is poisoned").is_some();
+    if !has_sink {
+        set_default_sink();
+    }
+}
+
+/// Sends the messages up to the level to the sink from now on
+pub fn set_sink(sink: Box<dyn log::Log>, max_level: log::LevelFilter) {
+    install();
+    let previous = LOGGER
+        .sink
+        .write()
+        .expect("Logger is poisoned")
+        .replace(sink);
+    if let Some(previous) = previous {
+        previous.flush();
+    }
+    log::set_max_level(max_level);
+}
+
+/// Sends the messages to the standard error, filtered by `RUST_LOG` (the sink used by default)
+pub fn set_default_sink() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let sink = builder.build();
+    let max_level = sink.filter();
+    set_sink(Box::new(sink), max_level);
+}
@@ -0,0 +1,409 @@
+import os
+import sys
+import time
+from pathlib import Path
+
+from cffi import FFI
+
+
+S_OK = 0x00000000
+E_HANDLE = 0x80070006
+E_INVALIDARG = 0x80070057
+E_NOTIMPL = 0x80004001
+E_BUFFERTOOSMALL = 0xff04006f
+
+# device type GUID of the FIP, as published in `devices::DEVICE_TYPE_SAITEK_FIP`
+DEVICE_TYPE_SAITEK_FIP = '3E083CD8-6A37-4A58-80A8-3D6A2C07513E'
+
+
+f = FFI()
+f.cdef(
+'''
+typedef struct { uint32_t Data1; uint16_t Data2; uint16_t Data3; uint8_t Data4[8]; } GUID;
+typedef void (__stdcall *Pfn_DirectOutput_EnumerateCallback)(void* hDevice, void* pCtxt);
+typedef void (__stdcall *Pfn_DirectOutput_DeviceChange)(void* hDevice, bool bAdded, void* pCtxt);
+typedef void (__stdcall *Pfn_DirectOutput_DeviceReconnect)(void* hDevice, void* pCtxt);
+typedef void (__stdcall *Pfn_DirectOutput_DeviceCountChange)(DWORD dwCount, void* pCtxt);
+typedef void (__stdcall *Pfn_DirectOutput_Log)(DWORD dwLevel, const wchar_t* wszMessage, void* pCtxt);
+typedef void (__stdcall *Pfn_DirectOutput_PageChange)(void* hDevice, DWORD dwPage, bool bSetActive, void* pCtxt);
+typedef void (__stdcall *Pfn_DirectOutput_SoftButtonChange)(void* hDevice, DWORD dwButtons, void* pCtxt);
+
+HRESULT __stdcall DirectOutput_Initialize(const wchar_t* wszPluginName);
+HRESULT __stdcall DirectOutput_Deinitialize();
+HRESULT __stdcall DirectOutput_GetLibraryVersion(DWORD* pdwMajor, DWORD* pdwMinor, DWORD* pdwPatch);
+HRESULT __stdcall DirectOutput_GetExportList(wchar_t* wszBuffer, DWORD* pdwSize);
+HRESULT __stdcall DirectOutput_SetLogCallback(Pfn_DirectOutput_Log pfnCb, void* pCtxt, DWORD dwMaxLevel);
+HRESULT __stdcall DirectOutput_InitializeEx(const wchar_t* wszPluginName, DWORD* pdwCount);
+HRESULT __stdcall DirectOutput_RegisterDeviceCallback(Pfn_DirectOutput_DeviceChange pfnCb, void* pCtxt);
+HRESULT __stdcall DirectOutput_RegisterReconnectCallback(Pfn_DirectOutput_DeviceReconnect pfnCb, void* pCtxt);
+HRESULT __stdcall DirectOutput_RegisterDeviceCountCallback(Pfn_DirectOutput_DeviceCountChange pfnCb, void* pCtxt);
+HRESULT __stdcall DirectOutput_Rescan();
+HRESULT __stdcall DirectOutput_Enumerate(Pfn_DirectOutput_EnumerateCallback pfnCb, void* pCtxt);
+HRESULT __stdcall DirectOutput_GetDeviceCount(DWORD* pdwCount);
+HRESULT __stdcall DirectOutput_GetDeviceByIndex(DWORD dwIndex, void** phDevice);
+HRESULT __stdcall DirectOutput_EnumerateToBuffer(void** phDevices, DWORD* pdwCount);
+HRESULT __stdcall DirectOutput_IsFactoryMode(void* hDevice, DWORD* pdwIsFactoryMode);
+HRESULT __stdcall DirectOutput_GetDeviceType(void* hDevice, GUID* pGdDevice);
+HRESULT __stdcall DirectOutput_GetCapabilities(void* hDevice, DWORD* pdwCapabilities);
+HRESULT __stdcall DirectOutput_RegisterPageCallback(void* hDevice, Pfn_DirectOutput_PageChange pfnCb, void* pCtxt);
+HRESULT __stdcall DirectOutput_RegisterSoftButtonCallback(void* hDevice, Pfn_DirectOutput_SoftButtonChange pfnCb, void* pCtxt);
+HRESULT __stdcall DirectOutput_ReadButtonsNow(void* hDevice, DWORD* pdwButtons);
+HRESULT __stdcall DirectOutput_GetDeviceInstance(void* hDevice, GUID* pGdInstance);
+HRESULT __stdcall DirectOutput_GetPageList(void* hDevice, DWORD* pdwPages, DWORD* pdwCount);
+HRESULT __stdcall DirectOutput_ResetDevice(void* hDevice);
+HRESULT __stdcall DirectOutput_AddPage(void* hDevice, DWORD dwPage, const wchar_t* wszDebugName, DWORD dwFlags);
+HRESULT __stdcall DirectOutput_RemovePage(void* hDevice, DWORD dwPage);
+HRESULT __stdcall DirectOutput_AddPageWithImage(void* hDevice, DWORD dwPage, const wchar_t* wszDebugName, DWORD dwFlags, DWORD cbValue, const void* pvValue);
+HRESULT __stdcall DirectOutput_SetLed(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD dwValue);
+HRESULT __stdcall DirectOutput_AllLedsOff(void* hDevice);
+HRESULT __stdcall DirectOutput_SetLedLevel(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD dwLevel);
+HRESULT __stdcall DirectOutput_GetLedIndices(void* hDevice, DWORD dwPage, DWORD* pdwIndices, DWORD* pdwCount);
+HRESULT __stdcall DirectOutput_FillColor(void* hDevice, DWORD dwPage, DWORD dwRed, DWORD dwGreen, DWORD dwBlue);
+HRESULT __stdcall DirectOutput_SaveFile(void* hDevice, DWORD dwPage, DWORD dwFile, size_t cchFilename, const wchar_t* filename, void* pStatus);
+HRESULT __stdcall DirectOutput_SaveFileEx(void* hDevice, DWORD dwPage, DWORD dwFile, size_t cchFilename, const wchar_t* filename, void* pStatus, DWORD dwTimeoutMs);
+HRESULT __stdcall DirectOutput_StartServer(void* hDevice, DWORD cchFilename, const wchar_t* filename, DWORD* pdwServerId, void* pStatus);
+HRESULT __stdcall DirectOutput_DisplayFile(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD dwFile, void* pStatus);
+HRESULT __stdcall DirectOutput_DeleteFile(void* hDevice, DWORD dwPage, DWORD dwFile, void* pStatus);
+HRESULT __stdcall DirectOutput_SetImage(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD cbValue, const void* pvValue);
+HRESULT __stdcall DirectOutput_GetSerialNumber(void* hDevice, wchar_t* pszSerialNumber, DWORD dwSize);
+HRESULT __stdcall DirectOutput_Flush();
+HRESULT __stdcall DirectOutput_DumpState(char* pszBuffer, DWORD* pdwSize);
+HRESULT __stdcall DirectOutput_SetImageRegion(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD x, DWORD y, DWORD width, DWORD height, DWORD cbValue, const void* pvValue);
+HRESULT __stdcall DirectOutput_BeginFrame(void* hDevice, DWORD dwPage);
+HRESULT __stdcall DirectOutput_CommitFrame(void* hDevice, DWORD dwPage);
+HRESULT __stdcall DirectOutput_ClearImageSlot(void* hDevice, DWORD dwPage, DWORD dwIndex);
+HRESULT __stdcall DirectOutput_WaitForButtons(void* hDevice, DWORD dwTimeoutMs, DWORD* pdwButtons);
+HRESULT __stdcall DirectOutput_SetImageFromFile(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD cchFilename, const wchar_t* filename);
+HRESULT __stdcall DirectOutput_GetImage(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD cbValue, void* pvValue);
+HRESULT __stdcall DirectOutput_GetPageImage(void* hDevice, DWORD dwPage, DWORD cbValue, void* pvValue);
+HRESULT __stdcall DirectOutput_BeginBatch(void* hDevice);
+HRESULT __stdcall DirectOutput_EndBatch(void* hDevice);
+HRESULT __stdcall DirectOutput_SetProfile(void* hDevice, DWORD cchProfile, const wchar_t* wszProfile);
+HRESULT __stdcall DirectOutput_SetString(void* hDevice, DWORD dwPage, DWORD dwIndex, DWORD cchValue, const wchar_t* wszValue);
+HRESULT __stdcall DirectOutput_CloseServer(void* hDevice, DWORD dwServerId, void* pStatus);
+HRESULT __stdcall DirectOutput_SendServerMsg(void* hDevice, DWORD dwServerId, DWORD dwRequest, DWORD dwPage, DWORD cbIn, const void* pvIn, DWORD cbOut, void* pvOut, void* pStatus);
+HRESULT __stdcall DirectOutput_SendServerFile(void* hDevice, DWORD dwServerId, DWORD dwRequest, DWORD dwPage, DWORD cbInHeader, const void* pvInHeader, DWORD cchFile, const wchar_t* wszFile, DWORD cbOut, void* pvOut, void* pStatus);
+HRESULT __stdcall DirectOutput_GetLastRequestStatus(void* hDevice, void* pStatus);
+HRESULT __stdcall DirectOutput_GetTransferStats(void* hDevice, void* pStats);
+'''.replace('HRESULT', 'uint64_t').replace('DWORD', 'int32_t')
+)
+m = f.dlopen(sys.argv[1] if len(sys.argv) > 1 else './target/debug/liblibfip.so')
+
+
+def fmt(value):
+    return f'{value:#x}' if isinstance(value, int) else repr(value)
+
+
+def check(name, result, expected):
+    if result != expected:
+        print(f'FAIL {name}: got {fmt(result)}, expected {fmt(expected)}')
+        exit(1)
+    print(f'ok   {name}')
+
+
+device_ptrs = []
+page_changes = []
+
+
+@f.callback("void(void*, void *)")
+def enumerate_callback(device_ptr, ctx):
+    device_ptrs.append(device_ptr)
+
+
+@f.callback("void(void*, void *)")
+def reconnect_callback(device_ptr, ctx):
+    pass
+
+
+log_messages = []
+
+
+@f.callback("void(int32_t, wchar_t *, void *)")
+def log_callback(level, message, ctx):
+    log_messages.append((level, f.string(message)))
+
+
+hotplug_events = []
+
+
+@f.callback("void(void*, bool, void *)")
+def device_callback(device_ptr, is_added, ctx):
+    hotplug_events.append(('device', is_added))
+
+
+@f.callback("void(int32_t, void *)")
+def device_count_callback(count, ctx):
+    hotplug_events.append(('count', count))
+
+
+@f.callback("void(void*, int32_t, bool, void *)")
+def page_callback(device_ptr, page, is_active, ctx):
+    page_changes.append((page, is_active))
+
+
+BAYER_MATRIX = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]]
+
+
+def dither(data, bits):
+    # same integer arithmetic as `images::dither`, so the result has to match byte for byte
+    levels = (1 << bits) - 1
+    result = bytearray(data)
+    for i, value in enumerate(data):
+        pixel = i // 3
+        threshold = BAYER_MATRIX[pixel // 320 % 4][pixel % 320 % 4]
+        level = min((value * levels * 32 + 255 * (2 * threshold + 1)) // (255 * 32), levels)
+        result[i] = (level * 255 + levels // 2) // levels
+    return bytes(result)
+
+
+def bmp(pixels):
+    # 24 bpp with bottom-up rows, so the pixel data is in the device format
+    header_size = 14 + 40
+    return (
+        b'BM' + (header_size + len(pixels)).to_bytes(4, 'little') + bytes(4) + header_size.to_bytes(4, 'little')
+        + (40).to_bytes(4, 'little') + (320).to_bytes(4, 'little') + (240).to_bytes(4, 'little')
+        + (1).to_bytes(2, 'little') + (24).to_bytes(2, 'little') + bytes(24)
+        + pixels
+    )
+
+
+def null_arg(arg):
+    if arg.kind == 'pointer':
+        return f.NULL
+    if arg.kind == 'function':
+        # function pointers must not be null on the Rust side; this one is never called
+        return f.cast(arg, 1)
+    return 0
+
+
+def null_args(function):
+    return [null_arg(arg) for arg in f.typeof(function).args]
+
+
+export_list_size = f.new('int32_t *', 0)
+check('GetExportList (size query)', m.DirectOutput_GetExportList(f.NULL, export_list_size), E_BUFFERTOOSMALL)
+export_list = f.new('wchar_t[]', export_list_size[0])
+check('GetExportList', m.DirectOutput_GetExportList(export_list, export_list_size), S_OK)
+check('GetExportList (null)', m.DirectOutput_GetExportList(export_list, f.NULL), E_INVALIDARG)
+exports = set(f.string(export_list).split('\n'))
+# every declared export is listed, except the ones not implemented yet
+not_implemented = {'DirectOutput_GetDeviceInstance', 'DirectOutput_SetProfile', 'DirectOutput_CloseServer', 'DirectOutput_SendServerMsg', 'DirectOutput_SendServerFile'}
+check('GetExportList lists the exports', set(dir(m)) - not_implemented <= exports, True)
+# and every listed export is declared, so none is left out of the checks below
+check('GetExportList lists declared exports only', exports <= set(dir(m)), True)
+
+# every export has to check the library is initialized before doing anything else
+for name in sorted(exports | not_implemented):
+    if name in ('DirectOutput_Initialize', 'DirectOutput_InitializeEx', 'DirectOutput_Deinitialize', 'DirectOutput_GetLibraryVersion', 'DirectOutput_GetExportList', 'DirectOutput_SetLogCallback'):
+        continue
+    function = getattr(m, name)
+    check(f'{name[len("DirectOutput_"):]} (not initialized)', function(*null_args(function)), E_HANDLE)
+
+version = [f.new('int32_t *') for _ in range(3)]
+check('GetLibraryVersion', m.DirectOutput_GetLibraryVersion(*version), S_OK)
+check('GetLibraryVersion (null)', m.DirectOutput_GetLibraryVersion(version[0], f.NULL, version[2]), E_INVALIDARG)
+print(f'     library version {".".join(str(part[0]) for part in version)}')
+
+check('SetLogCallback (invalid level)', m.DirectOutput_SetLogCallback(log_callback, f.NULL, 6), E_INVALIDARG)
+check('SetLogCallback', m.DirectOutput_SetLogCallback(log_callback, f.NULL, 3), S_OK)
+# the count callback is called once the count has stopped changing for this long
+hotplug_debounce = int(os.environ.setdefault('LIBFIP_HOTPLUG_DEBOUNCE_MS', '3000')) / 1000
+check('Initialize', m.DirectOutput_Initialize('test-exports'), S_OK)
+initialized_at = time.monotonic()
+check('SetLogCallback gets messages', any('App initialized' in message for _, message in log_messages), True)
+check('SetLogCallback gets messages up to the level', all(1 <= level <= 3 for level, _ in log_messages), True)
+check('SetLogCallback (null)', m.DirectOutput_SetLogCallback(f.NULL, f.NULL, 0), S_OK)
+try:
+    # registered right away, so the count of the devices found by Initialize is not told yet
+    check('RegisterDeviceCallback', m.DirectOutput_RegisterDeviceCallback(device_callback, f.NULL), S_OK)
+    check('RegisterDeviceCountCallback', m.DirectOutput_RegisterDeviceCountCallback(device_count_callback, f.NULL), S_OK)
+    time.sleep(0.5)
+
+    check('RegisterReconnectCallback', m.DirectOutput_RegisterReconnectCallback(reconnect_callback, f.NULL), S_OK)
+    check('Rescan', m.DirectOutput_Rescan(), S_OK)
+    check('Enumerate', m.DirectOutput_Enumerate(enumerate_callback, f.NULL), S_OK)
+    count = f.new('int32_t *')
+    check('GetDeviceCount', m.DirectOutput_GetDeviceCount(count), S_OK)
+    check('GetDeviceCount matches Enumerate', count[0], len(device_ptrs))
+    check('GetDeviceCount (null)', m.DirectOutput_GetDeviceCount(f.NULL), E_INVALIDARG)
+    time.sleep(max(hotplug_debounce + 0.5 - (time.monotonic() - initialized_at), 0))
+    count_events = [value for event, value in hotplug_events if event == 'count']
+    # no devices is the count before any has arrived, so it is not told
+    check('RegisterDeviceCountCallback gets the count', count_events[-1:], [count[0]] if count[0] else [])
+    check('RegisterDeviceCountCallback is called after the device ones', [event for event, _ in hotplug_events[-1:]], ['count'] if count[0] else [])
+    buffer_count = f.new('int32_t *', 0)
+    check('EnumerateToBuffer (count query)', m.DirectOutput_EnumerateToBuffer(f.NULL, buffer_count), E_BUFFERTOOSMALL if device_ptrs else S_OK)
+    check('EnumerateToBuffer count matches Enumerate', buffer_count[0], len(device_ptrs))
+    buffer_ptrs = f.new('void *[]', buffer_count[0] + 1)
+    buffer_count[0] = len(buffer_ptrs)
+    check('EnumerateToBuffer', m.DirectOutput_EnumerateToBuffer(buffer_ptrs, buffer_count), S_OK)
+    check('EnumerateToBuffer matches Enumerate', list(buffer_ptrs)[:buffer_count[0]], device_ptrs)
+    check('EnumerateToBuffer (null)', m.DirectOutput_EnumerateToBuffer(buffer_ptrs, f.NULL), E_INVALIDARG)
+    count_ex = f.new('int32_t *')
+    check('InitializeEx (already initialized)', m.DirectOutput_InitializeEx('test-exports', count_ex), S_OK)
+    check('InitializeEx matches GetDeviceCount', count_ex[0], count[0])
+    check('InitializeEx (null)', m.DirectOutput_InitializeEx('test-exports', f.NULL), E_INVALIDARG)
+
+    dump_size = f.new('int32_t *', 0)
+    check('DumpState (size query)', m.DirectOutput_DumpState(f.NULL, dump_size), E_BUFFERTOOSMALL)
+    dump = f.new('char[]', dump_size[0])
+    check('DumpState', m.DirectOutput_DumpState(dump, dump_size), S_OK)
+    import json
+    check('DumpState devices', len(json.loads(f.string(dump))['devices']) >= len(device_ptrs), True)
+
+    invalid_ptr = f.cast('void *', 0)
+    check('SetLed (invalid device)', m.DirectOutput_SetLed(invalid_ptr, 0, 1, 1), E_HANDLE)
+    check('SetImage (invalid device)', m.DirectOutput_SetImage(invalid_ptr, 0, 0, 0, f.NULL), E_HANDLE)
+
+    if not device_ptrs:
+        print('No devices found, skipping device tests')
+        exit(0)
+
+    device_ptr = f.new('void **')
+    check('GetDeviceByIndex', m.DirectOutput_GetDeviceByIndex(0, device_ptr), S_OK)
+    check('GetDeviceByIndex (out of range)', m.DirectOutput_GetDeviceByIndex(count[0], device_ptr), E_INVALIDARG)
+    device_ptr = device_ptr[0]
+
+    is_factory_mode = f.new('int32_t *')
+    check('IsFactoryMode', m.DirectOutput_IsFactoryMode(device_ptr, is_factory_mode), S_OK)
+    check('IsFactoryMode value', is_factory_mode[0], 0)
+
+    guid = f.new('GUID *')
+    check('GetDeviceType', m.DirectOutput_GetDeviceType(device_ptr, guid), S_OK)
+    data4 = bytes(guid.Data4).hex().upper()
+    check(
+        'GetDeviceType GUID',
+        f'{guid.Data1:08X}-{guid.Data2:04X}-{guid.Data3:04X}-{data4[:4]}-{data4[4:]}',
+        DEVICE_TYPE_SAITEK_FIP,
+    )
+
+    capabilities = f.new('int32_t *')
+    check('GetCapabilities', m.DirectOutput_GetCapabilities(device_ptr, capabilities), S_OK)
+
+    check('WaitForButtons (null)', m.DirectOutput_WaitForButtons(device_ptr, 0, f.NULL), E_INVALIDARG)
+
+    serial_number = f.new('wchar_t[16]')
+    check('GetSerialNumber', m.DirectOutput_GetSerialNumber(device_ptr, serial_number, len(serial_number)), S_OK)
+
+    # FIP has no text lines
+    check('SetString', m.DirectOutput_SetString(device_ptr, 0, 0, 4, 'text'), E_NOTIMPL)
+    check('SetString (empty)', m.DirectOutput_SetString(device_ptr, 0, 0, 0, f.NULL), E_NOTIMPL)
+
+    image = bytes(320 * 240 * 3)
+    check('SetImage', m.DirectOutput_SetImage(device_ptr, 0, 0, len(image), image), S_OK)
+    check('SetImage (wrong size)', m.DirectOutput_SetImage(device_ptr, 0, 0, len(image) - 1, image), E_BUFFERTOOSMALL)
+    check('SetImage (negative size)', m.DirectOutput_SetImage(device_ptr, 0, 0, -1, image), E_INVALIDARG)
+    check('SetImage (invalid index)', m.DirectOutput_SetImage(device_ptr, 0, 4, len(image), image), E_INVALIDARG)
+    # a file has to end up on the device the same as its pixels set directly
+    import tempfile
+    pixels = bytes(range(256)) * (len(image) // 256)
+    with tempfile.NamedTemporaryFile(suffix='.bmp') as image_file:
+        image_file.write(bmp(pixels))
+        image_file.flush()
+        check('SetImageFromFile', m.DirectOutput_SetImageFromFile(device_ptr, 0, 0, len(image_file.name), image_file.name), S_OK)
+        check('SetImageFromFile (invalid index)', m.DirectOutput_SetImageFromFile(device_ptr, 0, 4, len(image_file.name), image_file.name), E_INVALIDARG)
+    image_from_file = f.new('char[]', len(image))
+    check('GetImage', m.DirectOutput_GetImage(device_ptr, 0, 0, len(image), image_from_file), S_OK)
+    check('SetImage', m.DirectOutput_SetImage(device_ptr, 0, 0, len(pixels), pixels), S_OK)
+    image_from_data = f.new('char[]', len(image))
+    check('GetImage', m.DirectOutput_GetImage(device_ptr, 0, 0, len(image), image_from_data), S_OK)
+    # files are dithered with LIBFIP_IMAGE_DITHER_BITS, images set directly are not
+    dither_bits = int(os.environ.get('LIBFIP_IMAGE_DITHER_BITS', '8'))
+    if dither_bits < 8:
+        check('SetImageFromFile is dithered', f.buffer(image_from_file)[:] == dither(pixels, dither_bits), True)
+    else:
+        check('SetImageFromFile matches SetImage', f.buffer(image_from_file)[:] == f.buffer(image_from_data)[:], True)
+    # a file of the image data size is taken as raw data
+    with tempfile.NamedTemporaryFile(suffix='.bin') as raw_file:
+        raw_file.write(pixels)
+        raw_file.flush()
+        check('SetImageFromFile (raw)', m.DirectOutput_SetImageFromFile(device_ptr, 0, 0, len(raw_file.name), raw_file.name), S_OK)
+    image_from_raw_file = f.new('char[]', len(image))
+    check('GetImage', m.DirectOutput_GetImage(device_ptr, 0, 0, len(image), image_from_raw_file), S_OK)
+    # raw files are neither adjusted nor dithered
+    check('SetImageFromFile (raw) matches SetImage', f.buffer(image_from_raw_file)[:] == f.buffer(image_from_data)[:], True)
+    check('ClearImageSlot', m.DirectOutput_ClearImageSlot(device_ptr, 0, 0), S_OK)
+    check('ClearImageSlot (invalid index)', m.DirectOutput_ClearImageSlot(device_ptr, 0, 4), E_INVALIDARG)
+    # the page shows its slots drawn over each other, black being transparent
+    half = len(image) // 2
+    lower_half = b'\x10' * half + bytes(half)
+    upper_half = bytes(half) + b'\x20' * half
+    check('SetImage (slot 0)', m.DirectOutput_SetImage(device_ptr, 0, 0, len(image), lower_half), S_OK)
+    check('SetImage (slot 1)', m.DirectOutput_SetImage(device_ptr, 0, 1, len(image), upper_half), S_OK)
+    page_image = f.new('char[]', len(image))
+    check('GetPageImage', m.DirectOutput_GetPageImage(device_ptr, 0, len(image), page_image), S_OK)
+    check('GetPageImage composites the slots', f.buffer(page_image)[:] == lower_half[:half] + upper_half[half:], True)
+    check('GetPageImage (too small)', m.DirectOutput_GetPageImage(device_ptr, 0, len(image) - 1, page_image), E_BUFFERTOOSMALL)
+    check('ClearImageSlot (slot 1)', m.DirectOutput_ClearImageSlot(device_ptr, 0, 1), S_OK)
+    check('GetPageImage', m.DirectOutput_GetPageImage(device_ptr, 0, len(image), page_image), S_OK)
+    check('GetPageImage without the cleared slot', f.buffer(page_image)[:] == lower_half, True)
+    check('ClearImageSlot', m.DirectOutput_ClearImageSlot(device_ptr, 0, 0), S_OK)
+    check('GetPageImage (no slots)', m.DirectOutput_GetPageImage(device_ptr, 0, len(image), page_image), S_OK)
+    check('GetPageImage (no slots) is blank', f.buffer(page_image)[:] == bytes(len(image)), True)
+    check('SetLed', m.DirectOutput_SetLed(device_ptr, 0, 1, 0), S_OK)
+    check('SetLed (on)', m.DirectOutput_SetLed(device_ptr, 0, 2, 1), S_OK)
+    check('AllLedsOff', m.DirectOutput_AllLedsOff(device_ptr), S_OK)
+    check('Flush', m.DirectOutput_Flush(), S_OK)
+
+    region = bytes(16 * 16 * 3)
+    check('BeginFrame', m.DirectOutput_BeginFrame(device_ptr, 0), S_OK)
+    check('SetImageRegion', m.DirectOutput_SetImageRegion(device_ptr, 0, 0, 0, 0, 16, 16, len(region), region), S_OK)
+    check('SetImageRegion', m.DirectOutput_SetImageRegion(device_ptr, 0, 0, 304, 224, 16, 16, len(region), region), S_OK)
+    check('SetImageRegion (out of bounds)', m.DirectOutput_SetImageRegion(device_ptr, 0, 0, 305, 0, 16, 16, len(region), region), E_INVALIDARG)
+    check('SetImageRegion (wrong size)', m.DirectOutput_SetImageRegion(device_ptr, 0, 0, 0, 0, 16, 15, len(region), region), E_INVALIDARG)
+    check('SetImageRegion (overflowing offset)', m.DirectOutput_SetImageRegion(device_ptr, 0, 0, 0x7fffffff, 0x7fffffff, 16, 16, len(region), region), E_INVALIDARG)
+    check('SetImageRegion (overflowing size)', m.DirectOutput_SetImageRegion(device_ptr, 0, 0, 0, 0, 0x7fffffff, 0x7fffffff, len(region), region), E_INVALIDARG)
+    check('SetImageRegion (negative size)', m.DirectOutput_SetImageRegion(device_ptr, 0, 0, 0, 0, 16, 16, -1, region), E_INVALIDARG)
+    check('CommitFrame', m.DirectOutput_CommitFrame(device_ptr, 0), S_OK)
+
+    # pages are a byte on the device, the ones not fitting it are rejected by every export
+    with tempfile.NamedTemporaryFile() as empty_file:
+        led_count = f.new('int32_t *', 0)
+        page_exports = {
+            'AddPage': lambda page: m.DirectOutput_AddPage(device_ptr, page, f.NULL, 0),
+            'AddPageWithImage': lambda page: m.DirectOutput_AddPageWithImage(device_ptr, page, f.NULL, 0, len(image), image),
+            'RemovePage': lambda page: m.DirectOutput_RemovePage(device_ptr, page),
+            'GetLedIndices': lambda page: m.DirectOutput_GetLedIndices(device_ptr, page, f.NULL, led_count),
+            'SetLed': lambda page: m.DirectOutput_SetLed(device_ptr, page, 1, 0),
+            'SetLedLevel': lambda page: m.DirectOutput_SetLedLevel(device_ptr, page, 1, 0),
+            'ClearImageSlot': lambda page: m.DirectOutput_ClearImageSlot(device_ptr, page, 0),
+            'SetImage': lambda page: m.DirectOutput_SetImage(device_ptr, page, 0, len(image), image),
+            'SetImageRegion': lambda page: m.DirectOutput_SetImageRegion(device_ptr, page, 0, 0, 0, 16, 16, len(region), region),
+            'BeginFrame': lambda page: m.DirectOutput_BeginFrame(device_ptr, page),
+            'CommitFrame': lambda page: m.DirectOutput_CommitFrame(device_ptr, page),
+            'FillColor': lambda page: m.DirectOutput_FillColor(device_ptr, page, 0, 0, 0),
+            'GetImage': lambda page: m.DirectOutput_GetImage(device_ptr, page, 0, len(image), image_from_data),
+            'GetPageImage': lambda page: m.DirectOutput_GetPageImage(device_ptr, page, len(image), image_from_data),
+            'SetImageFromFile': lambda page: m.DirectOutput_SetImageFromFile(device_ptr, page, 0, len(empty_file.name), empty_file.name),
+            'SaveFile': lambda page: m.DirectOutput_SaveFile(device_ptr, page, 0, len(empty_file.name), empty_file.name, f.NULL),
+            'DisplayFile': lambda page: m.DirectOutput_DisplayFile(device_ptr, page, 0, 0, f.NULL),
+            'DeleteFile': lambda page: m.DirectOutput_DeleteFile(device_ptr, page, 0, f.NULL),
+            'SendServerMsg': lambda page: m.DirectOutput_SendServerMsg(device_ptr, 0, 0, page, 0, f.NULL, 0, f.NULL, f.NULL),
+        }
+        for page in (256, -1):
+            for name, call in page_exports.items():
+                check(f'{name} (page {page})', call(page), E_INVALIDARG)
+
+    check('BeginBatch', m.DirectOutput_BeginBatch(device_ptr), S_OK)
+    check('SetImage (batched)', m.DirectOutput_SetImage(device_ptr, 0, 0, len(image), image), S_OK)
+    check('SetLed (batched)', m.DirectOutput_SetLed(device_ptr, 0, 1, 1), S_OK)
+    check('SetLed (batched)', m.DirectOutput_SetLed(device_ptr, 0, 1, 0), S_OK)
+    check('EndBatch', m.DirectOutput_EndBatch(device_ptr), S_OK)
+    check('EndBatch (not begun)', m.DirectOutput_EndBatch(device_ptr), S_OK)
+
+    check('RegisterPageCallback', m.DirectOutput_RegisterPageCallback(device_ptr, page_callback, f.NULL), S_OK)
+    check('AddPage', m.DirectOutput_AddPage(device_ptr, 1, f.NULL, 1), S_OK)
+    check('AddPage', m.DirectOutput_AddPage(device_ptr, 2, f.NULL, 0), S_OK)
+    check('AddPage (duplicate)', m.DirectOutput_AddPage(device_ptr, 2, f.NULL, 0), E_INVALIDARG)
+    check('RemovePage (active, with fallback)', m.DirectOutput_RemovePage(device_ptr, 1), S_OK)
+    check('RemovePage page changes', page_changes, [(1, False), (2, True)])
+    page_changes.clear()
+    check('RemovePage (active, without fallback)', m.DirectOutput_RemovePage(device_ptr, 2), S_OK)
+    check('RemovePage page changes', page_changes, [(2, False)])
+    check('RemovePage (not added)', m.DirectOutput_RemovePage(device_ptr, 2), E_INVALIDARG)
+
+    check('AddPageWithImage', m.DirectOutput_AddPageWithImage(device_ptr, 3, f.NULL, 1, len(image), image), S_OK)
+    check('AddPageWithImage (duplicate)', m.DirectOutput_AddPageWithImage(device_ptr, 3, f.NULL, 1, len(image), image), E_INVALIDARG)
+    check('AddPageWithImage (no image)', m.DirectOutput_AddPageWithImage(device_ptr, 4, f.NULL, 0, 0, f.NULL), S_OK)
+finally:
+    m.DirectOutput_Deinitialize()